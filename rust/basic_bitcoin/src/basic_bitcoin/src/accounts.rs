@@ -0,0 +1,198 @@
+//! Per-user wallets: every principal has its own addresses, derived from the
+//! principal and an account index, so users of the canister don't share
+//! funds. Since the derivation path is always built from the caller, a user
+//! can only get the addresses of, and send from, their own accounts.
+//!
+//! The addresses that the canister used before it had per-user wallets were
+//! shared by all callers. The controllers of the canister can still reach
+//! them, to move their funds, as the reserved account `LEGACY_ACCOUNT`.
+//!
+//! The addresses that users requested are remembered so that they can be
+//! listed. They are saved to stable memory when the canister is upgraded.
+
+use crate::{bitcoin_api, DERIVATION_PATH, NETWORK};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::Satoshi;
+use ic_cdk_macros::{query, update};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// The address types of a wallet.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AddressType {
+    #[serde(rename = "p2pkh")]
+    P2pkh,
+    #[serde(rename = "p2wpkh")]
+    P2wpkh,
+    #[serde(rename = "p2tr_key_only")]
+    P2trKeyOnly,
+    #[serde(rename = "p2tr")]
+    P2tr,
+    #[serde(rename = "p2wsh_multisig")]
+    P2wshMultisig,
+}
+
+/// The account of a controller that stands for the shared wallet of the
+/// canister from before it had per-user wallets.
+pub const LEGACY_ACCOUNT: u32 = u32::MAX;
+
+impl AddressType {
+    // Different derivation paths for different addresses to use different keys.
+    fn derivation_path_suffix(self) -> &'static str {
+        match self {
+            AddressType::P2pkh => "p2pkh",
+            AddressType::P2wpkh => "p2wpkh",
+            AddressType::P2trKeyOnly => "p2tr_key_path_only",
+            AddressType::P2tr => "p2tr_key_and_script_path",
+            AddressType::P2wshMultisig => "p2wsh_multisig",
+        }
+    }
+}
+
+/// An address of one of the caller's accounts. Multisig addresses also
+/// carry the public key of their co-signer.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountAddress {
+    pub account: u32,
+    pub address_type: AddressType,
+    pub cosigner_public_key: Option<Vec<u8>>,
+    pub address: String,
+}
+
+/// The balance of an address of one of the caller's accounts.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountBalance {
+    pub account: u32,
+    pub address_type: AddressType,
+    pub cosigner_public_key: Option<Vec<u8>>,
+    pub address: String,
+    pub balance: Satoshi,
+}
+
+// An owner, account, address type and, for multisig addresses, the public
+// key of the co-signer.
+type AddressKey = (Principal, u32, AddressType, Option<Vec<u8>>);
+
+thread_local! {
+    // The addresses that each user requested. Every co-signer gives another
+    // multisig address, so each of them is kept.
+    static ADDRESSES: RefCell<BTreeMap<AddressKey, String>> = RefCell::default();
+}
+
+/// Returns the caller, who owns the wallet that the current call uses.
+///
+/// Traps if the caller is anonymous, since all anonymous callers would share
+/// the same wallet.
+pub fn owner() -> Principal {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        ic_cdk::trap("anonymous callers have no wallet");
+    }
+    caller
+}
+
+/// Returns the derivation path of the given address type of an account of
+/// `owner`: the canister's derivation path, followed by the owner, the
+/// account index and the address type.
+///
+/// `LEGACY_ACCOUNT` of a controller has the derivation path of the shared
+/// wallet instead, the canister's derivation path followed by the address
+/// type. Traps if `owner` is not a controller, or if the shared wallet had no
+/// address of the given type.
+pub fn derivation_path(owner: Principal, account: u32, address_type: AddressType) -> Vec<Vec<u8>> {
+    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
+    if account == LEGACY_ACCOUNT {
+        if !ic_cdk::api::is_controller(&owner) {
+            ic_cdk::trap("only controllers can use the legacy account");
+        }
+        if address_type == AddressType::P2wshMultisig {
+            ic_cdk::trap("the legacy account has no p2wsh multisig address");
+        }
+        derivation_path.push(address_type.derivation_path_suffix().as_bytes().to_vec());
+        return derivation_path;
+    }
+    derivation_path.push(owner.as_slice().to_vec());
+    derivation_path.push(account.to_be_bytes().to_vec());
+    derivation_path.push(address_type.derivation_path_suffix().as_bytes().to_vec());
+    derivation_path
+}
+
+/// Remembers an address of an account of `owner` so that it is listed by
+/// `get_my_addresses` and `get_my_balances`. `cosigner_public_key` is the
+/// co-signer of a multisig address and `None` for the other address types.
+pub fn record_address(
+    owner: Principal,
+    account: u32,
+    address_type: AddressType,
+    cosigner_public_key: Option<&[u8]>,
+    address: &str,
+) {
+    let key = (
+        owner,
+        account,
+        address_type,
+        cosigner_public_key.map(<[u8]>::to_vec),
+    );
+    ADDRESSES.with_borrow_mut(|addresses| addresses.insert(key, address.to_string()));
+}
+
+/// Saves the remembered addresses to stable memory. Called before upgrades.
+pub fn save_addresses() {
+    let addresses = ADDRESSES.take();
+    ic_cdk::storage::stable_save((addresses,)).expect("failed to save the addresses");
+}
+
+/// Restores the addresses saved by `save_addresses`. Called after upgrades.
+///
+/// Versions of the canister from before addresses were saved left stable
+/// memory empty, in which case no addresses are restored.
+pub fn restore_addresses() {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return;
+    }
+    let (addresses,) = ic_cdk::storage::stable_restore().expect("failed to restore the addresses");
+    ADDRESSES.set(addresses);
+}
+
+fn my_addresses() -> Vec<AccountAddress> {
+    let owner = owner();
+    ADDRESSES.with_borrow(|addresses| {
+        addresses
+            .range((owner, 0, AddressType::P2pkh, None)..)
+            .take_while(|((address_owner, _, _, _), _)| *address_owner == owner)
+            .map(
+                |((_, account, address_type, cosigner_public_key), address)| AccountAddress {
+                    account: *account,
+                    address_type: *address_type,
+                    cosigner_public_key: cosigner_public_key.clone(),
+                    address: address.clone(),
+                },
+            )
+            .collect()
+    })
+}
+
+/// Returns the addresses of the caller's accounts that were requested with
+/// the `get_${type}_address` endpoints, ordered by account and address type.
+#[query]
+pub fn get_my_addresses() -> Vec<AccountAddress> {
+    my_addresses()
+}
+
+/// Returns the balances of the addresses returned by `get_my_addresses`.
+#[update]
+pub async fn get_my_balances() -> Vec<AccountBalance> {
+    let network = NETWORK.with(|n| n.get());
+    let mut balances = vec![];
+    for address in my_addresses() {
+        let balance = bitcoin_api::get_balance(network, address.address.clone()).await;
+        balances.push(AccountBalance {
+            account: address.account,
+            address_type: address.address_type,
+            cosigner_public_key: address.cosigner_public_key,
+            address: address.address,
+            balance,
+        });
+    }
+    balances
+}
@@ -2,7 +2,7 @@ use candid::Principal;
 use ic_cdk::api::management_canister::bitcoin::{
     bitcoin_get_balance, bitcoin_get_current_fee_percentiles, bitcoin_get_utxos,
     bitcoin_send_transaction, BitcoinNetwork, GetBalanceRequest, GetCurrentFeePercentilesRequest,
-    GetUtxosRequest, GetUtxosResponse, MillisatoshiPerByte, SendTransactionRequest,
+    GetUtxosRequest, GetUtxosResponse, MillisatoshiPerByte, SendTransactionRequest, UtxoFilter,
 };
 use crate::{GetBlockHeadersRequest, GetBlockHeadersResponse};
 
@@ -22,12 +22,18 @@ pub async fn get_balance(network: BitcoinNetwork, address: String) -> u64 {
     balance_res.unwrap().0
 }
 
-/// Returns the UTXOs of the given bitcoin address.
+/// Returns one page of the UTXOs of the given bitcoin address.
+///
+/// If the address has more UTXOs than fit in one response, `next_page` is set
+/// and can be passed back as `UtxoFilter::Page` to get the next page.
 ///
 /// NOTE: Relies on the `bitcoin_get_utxos` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_get_utxos
-pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResponse {
-    let filter = None;
+pub async fn get_utxos(
+    network: BitcoinNetwork,
+    address: String,
+    filter: Option<UtxoFilter>,
+) -> GetUtxosResponse {
     let utxos_res = bitcoin_get_utxos(GetUtxosRequest {
         address,
         network,
@@ -38,6 +44,27 @@ pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResp
     utxos_res.unwrap().0
 }
 
+/// Returns all UTXOs of the given bitcoin address with at least
+/// `min_confirmations` confirmations, following `next_page` until the last
+/// page. The returned response has no `next_page`.
+///
+/// The page references pin the tip block of the first page, so all UTXOs are
+/// consistent with the returned tip.
+pub async fn get_all_utxos(
+    network: BitcoinNetwork,
+    address: String,
+    min_confirmations: Option<u32>,
+) -> GetUtxosResponse {
+    let filter = min_confirmations.map(UtxoFilter::MinConfirmations);
+    let mut response = get_utxos(network, address.clone(), filter).await;
+    while let Some(page) = response.next_page.take() {
+        let next = get_utxos(network, address.clone(), Some(UtxoFilter::Page(page))).await;
+        response.utxos.extend(next.utxos);
+        response.next_page = next.next_page;
+    }
+    response
+}
+
 /// Returns the block headers in the given height range.
 pub(crate) async fn get_block_headers(network: BitcoinNetwork, start_height: u32, end_height: Option<u32>) -> GetBlockHeadersResponse{
     let cycles = match network {
@@ -79,13 +106,13 @@ pub async fn get_current_fee_percentiles(network: BitcoinNetwork) -> Vec<Millisa
 ///
 /// Relies on the `bitcoin_send_transaction` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_send_transaction
-pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
+pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) -> Result<(), String> {
     let res = bitcoin_send_transaction(SendTransactionRequest {
         network,
         transaction,
     })
     .await;
 
-    res.unwrap();
+    res.map_err(|(code, message)| format!("Failed to send transaction: {:?} {}", code, message))
 }
 
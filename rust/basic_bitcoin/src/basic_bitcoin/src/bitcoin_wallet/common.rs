@@ -1,3 +1,4 @@
+use super::utxos::{self, DUST_THRESHOLD};
 use crate::bitcoin_api;
 use bitcoin::{
     absolute::LockTime, blockdata::witness::Witness, hashes::Hash, Address, Network, OutPoint,
@@ -12,30 +13,21 @@ pub fn build_transaction_with_fee(
     amount: u64,
     fee: u64,
 ) -> Result<(Transaction, Vec<TxOut>), String> {
-    // Assume that any amount below this threshold is dust.
-    const DUST_THRESHOLD: u64 = 1_000;
-
-    // Select which UTXOs to spend. We naively spend the oldest available UTXOs,
-    // even if they were previously spent in a transaction. This isn't a
-    // problem as long as at most one transaction is created per block and
-    // we're using min_confirmations of 1.
-    let mut utxos_to_spend = vec![];
-    let mut total_spent = 0;
-    for utxo in own_utxos.iter().rev() {
-        total_spent += utxo.value;
-        utxos_to_spend.push(utxo);
-        if total_spent >= amount + fee {
-            // We have enough inputs to cover the amount we want to spend.
-            break;
+    // Select which UTXOs to spend. `own_utxos` is expected to contain only
+    // the UTXOs returned by `utxos::available_utxos`, so that UTXOs spent in
+    // pending transactions are not spent again.
+    let utxos_to_spend = match utxos::select_utxos(own_utxos, amount + fee) {
+        Some(utxos_to_spend) => utxos_to_spend,
+        None => {
+            return Err(format!(
+                "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
+                own_utxos.iter().map(|utxo| utxo.value).sum::<u64>(),
+                amount,
+                fee
+            ))
         }
-    }
-
-    if total_spent < amount + fee {
-        return Err(format!(
-            "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
-            total_spent, amount, fee
-        ));
-    }
+    };
+    let total_spent: u64 = utxos_to_spend.iter().map(|utxo| utxo.value).sum();
 
     let inputs: Vec<TxIn> = utxos_to_spend
         .iter()
@@ -83,6 +75,21 @@ pub fn build_transaction_with_fee(
     ))
 }
 
+/// Returns the fee to build the transaction with next, or `None` if
+/// `total_fee` already pays `fee_per_vbyte` for a transaction of `tx_vsize`
+/// virtual bytes.
+///
+/// The selected UTXOs can change with the fee, so a fee that is higher than
+/// needed is accepted to make sure that the fee loops terminate.
+pub fn next_fee(tx_vsize: u64, fee_per_vbyte: u64, total_fee: u64) -> Option<u64> {
+    let fee = (tx_vsize * fee_per_vbyte) / 1000;
+    if fee <= total_fee {
+        None
+    } else {
+        Some(fee)
+    }
+}
+
 pub fn transform_network(network: BitcoinNetwork) -> Network {
     match network {
         BitcoinNetwork::Mainnet => Network::Bitcoin,
@@ -4,12 +4,15 @@
 //! and how bitcoin transactions can be signed. It is missing several
 //! pieces that any production-grade wallet would have, including:
 //!
-//! * Support for address types that aren't P2PKH, P2TR script spend, or P2TR
-//!   key spend with *untweaked* key.
-//! * Caching spent UTXOs so that they are not reused in future transactions.
+//! * Support for address types that aren't P2PKH, P2WPKH, P2TR script spend,
+//!   P2TR key spend with *untweaked* key, or 2-of-2 P2WSH multisig.
+//! * Persisting the UTXOs spent in pending transactions across upgrades.
 //! * Option to set the fee.
 
 mod common;
 pub mod p2pkh;
 pub mod p2tr;
 pub mod p2tr_key_only;
+pub mod p2wpkh;
+pub mod p2wsh_multisig;
+mod utxos;
@@ -32,14 +32,16 @@ pub async fn get_address(
 
 /// Sends a transaction to the network that transfers the given amount to the
 /// given destination, where the source of the funds is the canister itself
-/// at the given derivation path.
+/// at the given derivation path. Returns the transaction ID, or an
+/// error if the network rejects the transaction.
 pub async fn send(
     network: BitcoinNetwork,
+    min_confirmations: u32,
     derivation_path: Vec<Vec<u8>>,
     key_name: String,
     dst_address: String,
     amount: Satoshi,
-) -> Txid {
+) -> Result<Txid, String> {
     let fee_per_byte = super::common::get_fee_per_byte(network).await;
 
     // Fetch our public key, P2PKH address, and UTXOs.
@@ -48,12 +50,8 @@ pub async fn send(
     let own_address = public_key_to_p2pkh_address(network, &own_public_key);
 
     print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(network, own_address.clone())
-        .await
-        .utxos;
+    let own_utxos =
+        super::utxos::get_available_utxos(network, &own_address, min_confirmations).await;
 
     let own_address = Address::from_str(&own_address)
         .unwrap()
@@ -75,6 +73,8 @@ pub async fn send(
     )
     .await;
 
+    let locks = super::utxos::lock_inputs(&own_address.to_string(), &transaction);
+
     let tx_bytes = serialize(&transaction);
     print(format!("Transaction to sign: {}", hex::encode(tx_bytes)));
 
@@ -96,10 +96,11 @@ pub async fn send(
     ));
 
     print("Sending transaction...");
-    bitcoin_api::send_transaction(network, signed_transaction_bytes).await;
+    bitcoin_api::send_transaction(network, signed_transaction_bytes).await?;
+    locks.keep();
     print("Done");
 
-    signed_transaction.txid()
+    Ok(signed_transaction.txid())
 }
 
 // Builds a transaction to send the given `amount` of satoshis to the
@@ -146,11 +147,12 @@ async fn build_p2pkh_spend_tx(
 
         let tx_vsize = signed_transaction.vsize() as u64;
 
-        if (tx_vsize * fee_per_vbyte) / 1000 == total_fee {
-            print(format!("Transaction built with fee {}.", total_fee));
-            return transaction;
-        } else {
-            total_fee = (tx_vsize * fee_per_vbyte) / 1000;
+        match super::common::next_fee(tx_vsize, fee_per_vbyte, total_fee) {
+            Some(fee) => total_fee = fee,
+            None => {
+                print(format!("Transaction built with fee {}.", total_fee));
+                return transaction;
+            }
         }
     }
 }
@@ -226,7 +228,7 @@ fn public_key_to_p2pkh_address(network: BitcoinNetwork, public_key: &[u8]) -> St
 }
 
 // Converts a SEC1 ECDSA signature to the DER format.
-fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
+pub(crate) fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
     let r: Vec<u8> = if sec1_signature[0] & 0x80 != 0 {
         // r is negative. Prepend a zero byte.
         let mut tmp = vec![0x00];
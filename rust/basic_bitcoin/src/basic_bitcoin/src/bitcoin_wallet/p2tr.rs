@@ -55,7 +55,7 @@ fn p2tr_script_spend_info(internal_key_bytes: &[u8], script_key_bytes: &[u8]) ->
     let spend_script = p2tr_script(script_key_bytes);
     let secp256k1_engine = Secp256k1::new();
     // Key used in the key path spending.
-    let internal_key = XOnlyPublicKey::from(PublicKey::from_slice(&internal_key_bytes).unwrap());
+    let internal_key = XOnlyPublicKey::from(PublicKey::from_slice(internal_key_bytes).unwrap());
 
     // Taproot with an internal key and a single script.
     TaprootBuilder::new()
@@ -77,14 +77,16 @@ fn p2tr_script(public_key: &[u8]) -> ScriptBuf {
 
 /// Sends a P2TR script spend transaction to the network that transfers the
 /// given amount to the given destination, where the source of the funds is the
-/// canister itself at the given derivation path.
+/// canister itself at the given derivation path. Returns the transaction
+/// ID, or an error if the network rejects the transaction.
 pub async fn send_script_path(
     network: BitcoinNetwork,
+    min_confirmations: u32,
     derivation_path: Vec<Vec<u8>>,
     key_name: String,
     dst_address: String,
     amount: Satoshi,
-) -> Txid {
+) -> Result<Txid, String> {
     let fee_per_byte = super::common::get_fee_per_byte(network).await;
 
     // Fetch our public keys and UTXOs, and compute the P2TR address.
@@ -98,12 +100,9 @@ pub async fn send_script_path(
     );
 
     print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(network, own_address.to_string())
-        .await
-        .utxos;
+    let own_utxos =
+        super::utxos::get_available_utxos(network, &own_address.to_string(), min_confirmations)
+            .await;
 
     let dst_address = Address::from_str(&dst_address)
         .unwrap()
@@ -118,6 +117,8 @@ pub async fn send_script_path(
     let (transaction, prevouts) =
         build_p2tr_tx(&own_address, &own_utxos, &dst_address, amount, fee_per_byte).await;
 
+    let locks = super::utxos::lock_inputs(&own_address.to_string(), &transaction);
+
     let tx_bytes = serialize(&transaction);
     print(format!("Transaction to sign: {}", hex::encode(tx_bytes)));
 
@@ -141,22 +142,25 @@ pub async fn send_script_path(
     ));
 
     print("Sending transaction...");
-    bitcoin_api::send_transaction(network, signed_transaction_bytes).await;
+    bitcoin_api::send_transaction(network, signed_transaction_bytes).await?;
+    locks.keep();
     print("Done");
 
-    signed_transaction.txid()
+    Ok(signed_transaction.txid())
 }
 
 /// Sends a P2TR key spend transaction to the network that transfers the
 /// given amount to the given destination, where the source of the funds is the
-/// canister itself at the given derivation path.
+/// canister itself at the given derivation path. Returns the transaction
+/// ID, or an error if the network rejects the transaction.
 pub async fn send_key_path(
     network: BitcoinNetwork,
+    min_confirmations: u32,
     derivation_path: Vec<Vec<u8>>,
     key_name: String,
     dst_address: String,
     amount: Satoshi,
-) -> Txid {
+) -> Result<Txid, String> {
     let fee_per_byte = super::common::get_fee_per_byte(network).await;
 
     // Fetch our public key, P2PKH address, and UTXOs.
@@ -170,12 +174,9 @@ pub async fn send_key_path(
     );
 
     print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(network, own_address.to_string())
-        .await
-        .utxos;
+    let own_utxos =
+        super::utxos::get_available_utxos(network, &own_address.to_string(), min_confirmations)
+            .await;
 
     let dst_address = Address::from_str(&dst_address)
         .unwrap()
@@ -186,6 +187,8 @@ pub async fn send_key_path(
     let (transaction, prevouts) =
         build_p2tr_tx(&own_address, &own_utxos, &dst_address, amount, fee_per_byte).await;
 
+    let locks = super::utxos::lock_inputs(&own_address.to_string(), &transaction);
+
     let tx_bytes = serialize(&transaction);
     print(format!("Transaction to sign: {}", hex::encode(tx_bytes)));
 
@@ -212,10 +215,11 @@ pub async fn send_key_path(
     ));
 
     print("Sending transaction...");
-    bitcoin_api::send_transaction(network, signed_transaction_bytes).await;
+    bitcoin_api::send_transaction(network, signed_transaction_bytes).await?;
+    locks.keep();
     print("Done");
 
-    signed_transaction.txid()
+    Ok(signed_transaction.txid())
 }
 
 // Builds a P2TR transaction to send the given `amount` of satoshis to the
@@ -267,11 +271,12 @@ pub(crate) async fn build_p2tr_tx(
 
         let tx_vsize = signed_transaction.vsize() as u64;
 
-        if (tx_vsize * fee_per_byte) / 1000 == total_fee {
-            print(format!("Transaction built with fee {}.", total_fee));
-            return (transaction, prevouts);
-        } else {
-            total_fee = (tx_vsize * fee_per_byte) / 1000;
+        match super::common::next_fee(tx_vsize, fee_per_byte, total_fee) {
+            Some(fee) => total_fee = fee,
+            None => {
+                print(format!("Transaction built with fee {}.", total_fee));
+                return (transaction, prevouts);
+            }
         }
     }
 }
@@ -283,6 +288,7 @@ pub(crate) async fn build_p2tr_tx(
 //
 // 1. All the inputs are referencing outpoints that are owned by `own_address`.
 // 2. `own_address` is a P2TR address that includes a script.
+#[allow(clippy::too_many_arguments)]
 async fn schnorr_sign_script_spend_transaction<SignFun, Fut>(
     own_address: &Address,
     mut transaction: Transaction,
@@ -310,12 +316,12 @@ where
     for i in 0..num_inputs {
         let mut sighasher = SighashCache::new(&mut transaction);
 
-        let leaf_hash = TapLeafHash::from_script(&script, LeafVersion::TapScript);
+        let leaf_hash = TapLeafHash::from_script(script, LeafVersion::TapScript);
 
         let signing_data = sighasher
             .taproot_script_spend_signature_hash(
                 i,
-                &bitcoin::sighash::Prevouts::All(&prevouts),
+                &bitcoin::sighash::Prevouts::All(prevouts),
                 leaf_hash,
                 TapSighashType::Default,
             )
@@ -383,7 +389,7 @@ where
         let signing_data = sighasher
             .taproot_key_spend_signature_hash(
                 i,
-                &bitcoin::sighash::Prevouts::All(&prevouts),
+                &bitcoin::sighash::Prevouts::All(prevouts),
                 TapSighashType::Default,
             )
             .expect("Failed to encode signing data")
@@ -36,14 +36,16 @@ pub async fn get_address(
 
 /// Sends a P2TR key-only transaction to the network that transfers the
 /// given amount to the given destination, where the source of the funds is the
-/// canister itself at the given derivation path.
+/// canister itself at the given derivation path. Returns the transaction
+/// ID, or an error if the network rejects the transaction.
 pub async fn send(
     network: BitcoinNetwork,
+    min_confirmations: u32,
     derivation_path: Vec<Vec<u8>>,
     key_name: String,
     dst_address: String,
     amount: Satoshi,
-) -> Txid {
+) -> Result<Txid, String> {
     let fee_per_byte = super::common::get_fee_per_byte(network).await;
 
     // Fetch our public key, P2TR key-only address, and UTXOs.
@@ -62,12 +64,9 @@ pub async fn send(
     );
 
     print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(network, own_address.to_string())
-        .await
-        .utxos;
+    let own_utxos =
+        super::utxos::get_available_utxos(network, &own_address.to_string(), min_confirmations)
+            .await;
 
     let dst_address = Address::from_str(&dst_address)
         .unwrap()
@@ -78,6 +77,8 @@ pub async fn send(
         super::p2tr::build_p2tr_tx(&own_address, &own_utxos, &dst_address, amount, fee_per_byte)
             .await;
 
+    let locks = super::utxos::lock_inputs(&own_address.to_string(), &transaction);
+
     let tx_bytes = serialize(&transaction);
     print(format!("Transaction to sign: {}", hex::encode(tx_bytes)));
 
@@ -100,8 +101,9 @@ pub async fn send(
     ));
 
     print("Sending transaction...");
-    bitcoin_api::send_transaction(network, signed_transaction_bytes).await;
+    bitcoin_api::send_transaction(network, signed_transaction_bytes).await?;
+    locks.keep();
     print("Done");
 
-    signed_transaction.txid()
+    Ok(signed_transaction.txid())
 }
@@ -0,0 +1,245 @@
+use crate::{bitcoin_api, ecdsa_api};
+use bitcoin::{
+    consensus::serialize,
+    hashes::Hash,
+    sighash::{EcdsaSighashType, SighashCache},
+    Address, AddressType, PublicKey, Transaction, TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::{
+    BitcoinNetwork, MillisatoshiPerByte, Satoshi, Utxo,
+};
+use ic_cdk::print;
+use std::str::FromStr;
+
+use super::common::transform_network;
+
+const ECDSA_SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
+
+/// Returns the P2WPKH address of this canister at the given derivation path.
+pub async fn get_address(
+    network: BitcoinNetwork,
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+) -> String {
+    // Fetch the public key of the given derivation path.
+    let public_key = ecdsa_api::get_ecdsa_public_key(key_name, derivation_path).await;
+
+    // Compute the address.
+    public_key_to_p2wpkh_address(network, &public_key)
+}
+
+/// Sends a transaction to the network that transfers the given amount to the
+/// given destination, where the source of the funds is the canister itself
+/// at the given derivation path. Returns the transaction ID, or an
+/// error if the network rejects the transaction.
+pub async fn send(
+    network: BitcoinNetwork,
+    min_confirmations: u32,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    dst_address: String,
+    amount: Satoshi,
+) -> Result<Txid, String> {
+    let fee_per_byte = super::common::get_fee_per_byte(network).await;
+
+    // Fetch our public key, P2WPKH address, and UTXOs.
+    let own_public_key =
+        ecdsa_api::get_ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
+    let own_address = public_key_to_p2wpkh_address(network, &own_public_key);
+
+    print("Fetching UTXOs...");
+    let own_utxos =
+        super::utxos::get_available_utxos(network, &own_address, min_confirmations).await;
+
+    let own_address = Address::from_str(&own_address)
+        .unwrap()
+        .require_network(transform_network(network))
+        .expect("should be valid address for the network");
+    let dst_address = Address::from_str(&dst_address)
+        .unwrap()
+        .require_network(transform_network(network))
+        .expect("should be valid address for the network");
+
+    // Build the transaction that sends `amount` to the destination address.
+    let (transaction, prevouts) = build_p2wpkh_spend_tx(
+        &own_public_key,
+        &own_address,
+        &own_utxos,
+        &dst_address,
+        amount,
+        fee_per_byte,
+    )
+    .await;
+
+    let locks = super::utxos::lock_inputs(&own_address.to_string(), &transaction);
+
+    let tx_bytes = serialize(&transaction);
+    print(format!("Transaction to sign: {}", hex::encode(tx_bytes)));
+
+    // Sign the transaction.
+    let signed_transaction = ecdsa_sign_transaction(
+        &own_public_key,
+        &own_address,
+        transaction,
+        &prevouts,
+        key_name,
+        derivation_path,
+        ecdsa_api::get_ecdsa_signature,
+    )
+    .await;
+
+    let signed_transaction_bytes = serialize(&signed_transaction);
+    print(format!(
+        "Signed transaction: {}",
+        hex::encode(&signed_transaction_bytes)
+    ));
+
+    print("Sending transaction...");
+    bitcoin_api::send_transaction(network, signed_transaction_bytes).await?;
+    locks.keep();
+    print("Done");
+
+    Ok(signed_transaction.txid())
+}
+
+// Builds a transaction to send the given `amount` of satoshis to the
+// destination address.
+async fn build_p2wpkh_spend_tx(
+    own_public_key: &[u8],
+    own_address: &Address,
+    own_utxos: &[Utxo],
+    dst_address: &Address,
+    amount: Satoshi,
+    fee_per_vbyte: MillisatoshiPerByte,
+) -> (Transaction, Vec<TxOut>) {
+    // We have a chicken-and-egg problem where we need to know the length
+    // of the transaction in order to compute its proper fee, but we need
+    // to know the proper fee in order to figure out the inputs needed for
+    // the transaction.
+    //
+    // We solve this problem iteratively. We start with a fee of zero, build
+    // and sign a transaction, see what its size is, and then update the fee,
+    // rebuild the transaction, until the fee is set to the correct amount.
+    //
+    // The signatures are in the witness, which only counts a quarter of its
+    // size towards the virtual size, so the fee is computed from the vsize.
+    print("Building transaction...");
+    let mut total_fee = 0;
+    loop {
+        let (transaction, prevouts) = super::common::build_transaction_with_fee(
+            own_utxos,
+            own_address,
+            dst_address,
+            amount,
+            total_fee,
+        )
+        .expect("Error building transaction.");
+
+        // Sign the transaction. In this case, we only care about the size
+        // of the signed transaction, so we use a mock signer here for efficiency.
+        let signed_transaction = ecdsa_sign_transaction(
+            own_public_key,
+            own_address,
+            transaction.clone(),
+            &prevouts,
+            String::from(""), // mock key name
+            vec![],           // mock derivation path
+            mock_signer,
+        )
+        .await;
+
+        let tx_vsize = signed_transaction.vsize() as u64;
+
+        match super::common::next_fee(tx_vsize, fee_per_vbyte, total_fee) {
+            Some(fee) => total_fee = fee,
+            None => {
+                print(format!("Transaction built with fee {}.", total_fee));
+                return (transaction, prevouts);
+            }
+        }
+    }
+}
+
+// Sign a bitcoin transaction.
+//
+// IMPORTANT: This method is for demonstration purposes only and it only
+// supports signing transactions if:
+//
+// 1. All the inputs are referencing outpoints that are owned by `own_address`.
+// 2. `own_address` is a P2WPKH address.
+async fn ecdsa_sign_transaction<SignFun, Fut>(
+    own_public_key: &[u8],
+    own_address: &Address,
+    mut transaction: Transaction,
+    prevouts: &[TxOut],
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    signer: SignFun,
+) -> Transaction
+where
+    SignFun: Fn(String, Vec<Vec<u8>>, Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = Vec<u8>>,
+{
+    // Verify that our own address is P2WPKH.
+    assert_eq!(
+        own_address.address_type(),
+        Some(AddressType::P2wpkh),
+        "This example supports signing p2wpkh addresses only."
+    );
+
+    // BIP-143 signs the P2PKH script of the public key hash.
+    let script_code = own_address
+        .script_pubkey()
+        .p2wpkh_script_code()
+        .expect("should be a p2wpkh script pubkey");
+
+    let txclone = transaction.clone();
+    let mut sighasher = SighashCache::new(&txclone);
+    for (index, input) in transaction.input.iter_mut().enumerate() {
+        // Unlike legacy sighashes, BIP-143 sighashes commit to the value of
+        // the spent output.
+        let sighash = sighasher
+            .segwit_signature_hash(
+                index,
+                &script_code,
+                prevouts[index].value,
+                ECDSA_SIG_HASH_TYPE,
+            )
+            .unwrap();
+
+        let signature = signer(
+            key_name.clone(),
+            derivation_path.clone(),
+            sighash.as_byte_array().to_vec(),
+        )
+        .await;
+
+        // Convert signature to DER.
+        let mut sig_with_hashtype = super::p2pkh::sec1_to_der(signature);
+        sig_with_hashtype.push(ECDSA_SIG_HASH_TYPE.to_u32() as u8);
+
+        input.witness = Witness::from_slice(&[sig_with_hashtype, own_public_key.to_vec()]);
+    }
+
+    transaction
+}
+
+// Converts a public key to a P2WPKH address.
+fn public_key_to_p2wpkh_address(network: BitcoinNetwork, public_key: &[u8]) -> String {
+    Address::p2wpkh(
+        &PublicKey::from_slice(public_key).expect("failed to parse public key"),
+        transform_network(network),
+    )
+    .expect("public key should be compressed")
+    .to_string()
+}
+
+// Returns a signature whose DER encoding has the maximum length, so that the
+// estimated fee is never too low.
+async fn mock_signer(
+    _key_name: String,
+    _derivation_path: Vec<Vec<u8>>,
+    _signing_data: Vec<u8>,
+) -> Vec<u8> {
+    vec![255; 64]
+}
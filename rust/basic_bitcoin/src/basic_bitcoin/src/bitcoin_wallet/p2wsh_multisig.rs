@@ -0,0 +1,363 @@
+//! A 2-of-2 P2WSH multisig address of the canister and an external co-signer,
+//! e.g. a hardware wallet.
+//!
+//! Spending from it takes two steps, which exchange the transaction as a PSBT
+//! (BIP-174): the canister builds an unsigned PSBT, which the co-signer
+//! reviews and signs, and then the canister adds its own signature, finalizes
+//! the transaction and sends it.
+use crate::{bitcoin_api, ecdsa_api};
+use bitcoin::{
+    blockdata::{opcodes::all::OP_CHECKMULTISIG, script::Builder},
+    consensus::serialize,
+    ecdsa,
+    hashes::Hash,
+    psbt::{Psbt, PsbtSighashType},
+    secp256k1::{self, Message, Secp256k1},
+    sighash::{EcdsaSighashType, SighashCache},
+    Address, PublicKey, ScriptBuf, Transaction, TxOut, Txid, Witness,
+};
+use ic_cdk::api::management_canister::bitcoin::{
+    BitcoinNetwork, MillisatoshiPerByte, Satoshi, Utxo,
+};
+use ic_cdk::print;
+use std::str::FromStr;
+
+use super::common::transform_network;
+
+const ECDSA_SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
+
+/// Returns the 2-of-2 P2WSH address of the canister's key at the given
+/// derivation path and the given co-signer public key.
+pub async fn get_address(
+    network: BitcoinNetwork,
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    cosigner_public_key: &[u8],
+) -> Result<String, String> {
+    let (_, _, witness_script) = get_keys(key_name, derivation_path, cosigner_public_key).await?;
+    Ok(Address::p2wsh(&witness_script, transform_network(network)).to_string())
+}
+
+/// Builds an unsigned PSBT that transfers the given amount to the given
+/// destination, where the source of the funds is the 2-of-2 address of the
+/// canister at the given derivation path and the given co-signer.
+///
+/// The spent UTXOs are locked right away, since the PSBT is signed elsewhere
+/// and may only come back much later.
+pub async fn build_psbt(
+    network: BitcoinNetwork,
+    min_confirmations: u32,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    cosigner_public_key: &[u8],
+    dst_address: String,
+    amount: Satoshi,
+) -> Result<Psbt, String> {
+    let dst_address = Address::from_str(&dst_address)
+        .and_then(|address| address.require_network(transform_network(network)))
+        .map_err(|e| format!("Invalid destination address: {}", e))?;
+
+    let fee_per_byte = super::common::get_fee_per_byte(network).await;
+
+    // Fetch our public keys, P2WSH address, and UTXOs.
+    let (_, _, witness_script) = get_keys(key_name, derivation_path, cosigner_public_key).await?;
+    let own_address = Address::p2wsh(&witness_script, transform_network(network));
+
+    print("Fetching UTXOs...");
+    let own_utxos =
+        super::utxos::get_available_utxos(network, &own_address.to_string(), min_confirmations)
+            .await;
+
+    // Build the transaction that sends `amount` to the destination address.
+    let (transaction, prevouts) = build_p2wsh_multisig_tx(
+        &own_address,
+        &witness_script,
+        &own_utxos,
+        &dst_address,
+        amount,
+        fee_per_byte,
+    )?;
+
+    super::utxos::lock_inputs(&own_address.to_string(), &transaction).keep();
+
+    // Add what the signers need to know about the spent outputs.
+    let mut psbt = Psbt::from_unsigned_tx(transaction).expect("transaction should be unsigned");
+    for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+        input.witness_utxo = Some(prevout);
+        input.witness_script = Some(witness_script.clone());
+        input.sighash_type = Some(PsbtSighashType::from(ECDSA_SIG_HASH_TYPE));
+    }
+    Ok(psbt)
+}
+
+/// Adds the canister's signature to the inputs of the given PSBT that spend
+/// from the 2-of-2 address of the canister at the given derivation path and
+/// the given co-signer, finalizes the transaction, and sends it to the
+/// network. Returns the transaction ID.
+///
+/// Every input that spends from the 2-of-2 address must already be signed by
+/// the co-signer, and all other inputs must be finalized.
+///
+/// If signing or sending fails, the UTXOs of the 2-of-2 address that the
+/// PSBT spends are unlocked, since the transaction won't be sent.
+pub async fn sign_and_send_psbt(
+    network: BitcoinNetwork,
+    min_confirmations: u32,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    cosigner_public_key: &[u8],
+    psbt: &[u8],
+) -> Result<Txid, String> {
+    let mut psbt = Psbt::deserialize(psbt).map_err(|e| format!("Invalid PSBT: {}", e))?;
+
+    let (own_public_key, cosigner_public_key, witness_script) = get_keys(
+        key_name.clone(),
+        derivation_path.clone(),
+        cosigner_public_key,
+    )
+    .await?;
+    let own_address = Address::p2wsh(&witness_script, transform_network(network));
+
+    // The sighash commits to the values of the spent outputs given in the
+    // PSBT, so they are checked against the UTXOs of our address before
+    // anything is signed.
+    print("Fetching UTXOs...");
+    let own_utxos =
+        bitcoin_api::get_all_utxos(network, own_address.to_string(), Some(min_confirmations))
+            .await
+            .utxos;
+
+    let transaction = psbt.unsigned_tx.clone();
+    let mut sighasher = SighashCache::new(&transaction);
+    let secp = Secp256k1::verification_only();
+    let mut sighashes = vec![];
+    let mut own_outpoints = vec![];
+    for (index, (input, txin)) in psbt.inputs.iter().zip(&transaction.input).enumerate() {
+        if input.witness_script.as_ref() != Some(&witness_script) {
+            if input.final_script_witness.is_none() && input.final_script_sig.is_none() {
+                return Err(format!(
+                    "Input {} does not spend from {} and is not finalized",
+                    index, own_address
+                ));
+            }
+            continue;
+        }
+
+        let prevout = input
+            .witness_utxo
+            .as_ref()
+            .ok_or_else(|| format!("Input {} has no witness UTXO", index))?;
+        let is_own_utxo = own_utxos.iter().any(|utxo| {
+            utxo.outpoint.txid == txin.previous_output.txid.to_byte_array()
+                && utxo.outpoint.vout == txin.previous_output.vout
+                && utxo.value == prevout.value
+        });
+        if prevout.script_pubkey != own_address.script_pubkey() || !is_own_utxo {
+            return Err(format!(
+                "Input {} does not spend a UTXO of {}",
+                index, own_address
+            ));
+        }
+        if input
+            .sighash_type
+            .unwrap_or(PsbtSighashType::from(ECDSA_SIG_HASH_TYPE))
+            != PsbtSighashType::from(ECDSA_SIG_HASH_TYPE)
+        {
+            return Err(format!("Input {} does not use SIGHASH_ALL", index));
+        }
+
+        let sighash = sighasher
+            .segwit_signature_hash(index, &witness_script, prevout.value, ECDSA_SIG_HASH_TYPE)
+            .unwrap();
+
+        // Check the co-signer's signature now, so that the canister doesn't
+        // sign a transaction that can't be sent.
+        let cosigner_signature = input
+            .partial_sigs
+            .get(&cosigner_public_key)
+            .ok_or_else(|| format!("Input {} is not signed by the co-signer", index))?;
+        let message = Message::from_slice(sighash.as_byte_array()).unwrap();
+        if cosigner_signature.hash_ty != ECDSA_SIG_HASH_TYPE
+            || secp
+                .verify_ecdsa(
+                    &message,
+                    &cosigner_signature.sig,
+                    &cosigner_public_key.inner,
+                )
+                .is_err()
+        {
+            return Err(format!(
+                "Input {} has an invalid co-signer signature",
+                index
+            ));
+        }
+
+        sighashes.push((index, sighash));
+        own_outpoints.push(super::utxos::to_outpoint(&txin.previous_output));
+    }
+
+    // Lock the spent UTXOs of our address again, in case the locks taken when
+    // the PSBT was built have timed out. The other inputs are not ours to lock.
+    let locks = super::utxos::lock_outpoints(&own_address.to_string(), own_outpoints);
+
+    for (index, sighash) in sighashes {
+        let signature = ecdsa_api::get_ecdsa_signature(
+            key_name.clone(),
+            derivation_path.clone(),
+            sighash.as_byte_array().to_vec(),
+        )
+        .await;
+        let mut signature = secp256k1::ecdsa::Signature::from_compact(&signature)
+            .expect("should be a compact signature");
+        signature.normalize_s();
+
+        let input = &mut psbt.inputs[index];
+        input
+            .partial_sigs
+            .insert(own_public_key, ecdsa::Signature::sighash_all(signature));
+
+        // Finalize the input. OP_CHECKMULTISIG pops one element more than it
+        // needs, hence the empty element, and expects the signatures in the
+        // order of the public keys in the script.
+        let mut witness = Witness::new();
+        witness.push([]);
+        for public_key in sorted_public_keys(&own_public_key, &cosigner_public_key) {
+            witness.push(input.partial_sigs[public_key].to_vec());
+        }
+        witness.push(witness_script.as_bytes());
+        input.final_script_witness = Some(witness);
+        input.partial_sigs.clear();
+        input.sighash_type = None;
+        input.witness_script = None;
+    }
+
+    let signed_transaction = psbt.extract_tx();
+    let signed_transaction_bytes = serialize(&signed_transaction);
+    print(format!(
+        "Signed transaction: {}",
+        hex::encode(&signed_transaction_bytes)
+    ));
+
+    print("Sending transaction...");
+    bitcoin_api::send_transaction(network, signed_transaction_bytes).await?;
+    locks.keep();
+    print("Done");
+
+    Ok(signed_transaction.txid())
+}
+
+/// Unlocks the UTXOs of the 2-of-2 address of the canister at the given
+/// derivation path and the given co-signer that the given PSBT, built by
+/// `build_psbt`, spends, so that they can be spent by other transactions.
+///
+/// The PSBT must not be sent afterwards, since its UTXOs may then be spent
+/// twice and one of the transactions would be rejected.
+pub async fn cancel_psbt(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    cosigner_public_key: &[u8],
+    psbt: &[u8],
+) -> Result<(), String> {
+    let psbt = Psbt::deserialize(psbt).map_err(|e| format!("Invalid PSBT: {}", e))?;
+
+    let (_, _, witness_script) = get_keys(key_name, derivation_path, cosigner_public_key).await?;
+    let own_address = Address::p2wsh(&witness_script, transform_network(network));
+
+    // Locks are only released for our address, so a PSBT that claims to
+    // spend UTXOs of other addresses can't unlock them.
+    let outpoints: Vec<_> = psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .map(|txin| super::utxos::to_outpoint(&txin.previous_output))
+        .collect();
+    if super::utxos::unlock_outpoints(&own_address.to_string(), &outpoints) == 0 {
+        return Err(format!(
+            "The PSBT spends no locked UTXOs of {}",
+            own_address
+        ));
+    }
+    Ok(())
+}
+
+// Builds a transaction to send the given `amount` of satoshis to the
+// destination address.
+fn build_p2wsh_multisig_tx(
+    own_address: &Address,
+    witness_script: &ScriptBuf,
+    own_utxos: &[Utxo],
+    dst_address: &Address,
+    amount: Satoshi,
+    fee_per_vbyte: MillisatoshiPerByte,
+) -> Result<(Transaction, Vec<TxOut>), String> {
+    // As for the other address types, the fee is found iteratively. Since
+    // the transaction is signed elsewhere, the witnesses are filled with
+    // placeholder signatures of the maximum length instead of mock signing.
+    print("Building transaction...");
+    let mut total_fee = 0;
+    loop {
+        let (transaction, prevouts) = super::common::build_transaction_with_fee(
+            own_utxos,
+            own_address,
+            dst_address,
+            amount,
+            total_fee,
+        )?;
+
+        let mut signed_transaction = transaction.clone();
+        for input in signed_transaction.input.iter_mut() {
+            input.witness =
+                Witness::from_slice(&[vec![], vec![0; 73], vec![0; 73], witness_script.to_bytes()]);
+        }
+
+        let tx_vsize = signed_transaction.vsize() as u64;
+
+        match super::common::next_fee(tx_vsize, fee_per_vbyte, total_fee) {
+            Some(fee) => total_fee = fee,
+            None => {
+                print(format!("Transaction built with fee {}.", total_fee));
+                return Ok((transaction, prevouts));
+            }
+        }
+    }
+}
+
+// Returns the canister's public key, the co-signer's public key, and the
+// witness script that requires signatures of both. Fails if the co-signer's
+// public key is not a valid compressed public key.
+async fn get_keys(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    cosigner_public_key: &[u8],
+) -> Result<(PublicKey, PublicKey, ScriptBuf), String> {
+    let cosigner_public_key = PublicKey::from_slice(cosigner_public_key)
+        .map_err(|e| format!("Invalid co-signer public key: {}", e))?;
+    if !cosigner_public_key.compressed {
+        return Err("The co-signer public key is not compressed".to_string());
+    }
+    let own_public_key = ecdsa_api::get_ecdsa_public_key(key_name, derivation_path).await;
+    let own_public_key =
+        PublicKey::from_slice(&own_public_key).expect("failed to parse public key");
+
+    let [first, second] = sorted_public_keys(&own_public_key, &cosigner_public_key);
+    let witness_script = Builder::new()
+        .push_int(2)
+        .push_key(first)
+        .push_key(second)
+        .push_int(2)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+
+    Ok((own_public_key, cosigner_public_key, witness_script))
+}
+
+// Sorts the public keys lexicographically, as in BIP-67, so that the address
+// doesn't depend on the order in which the keys are given.
+fn sorted_public_keys<'a>(a: &'a PublicKey, b: &'a PublicKey) -> [&'a PublicKey; 2] {
+    if a.to_bytes() <= b.to_bytes() {
+        [a, b]
+    } else {
+        [b, a]
+    }
+}
@@ -0,0 +1,307 @@
+//! UTXO management shared by all address types: tracking the UTXOs that this
+//! canister already spent in transactions that are not confirmed yet, and
+//! selecting the UTXOs to spend in a new transaction.
+//!
+//! Pending spends are kept in memory only, so they are forgotten when the
+//! canister is upgraded.
+
+use crate::bitcoin_api;
+use bitcoin::{hashes::Hash, OutPoint, Transaction};
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Outpoint, Satoshi, Utxo};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Any amount below this threshold is dust. Dust UTXOs cost more in fees
+/// than they are worth, so they are never spent, and change below this
+/// threshold is left to the miners instead of creating a dust output.
+pub const DUST_THRESHOLD: Satoshi = 1_000;
+
+/// How long a UTXO stays locked after it was spent in a transaction. If the
+/// transaction isn't confirmed by then, e.g. because its fee was too low or
+/// it was never sent, the UTXO can be spent again.
+const LOCK_TIMEOUT_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The maximum number of branches that branch and bound explores before
+/// giving up on finding a selection without change.
+const BNB_MAX_TRIES: usize = 100_000;
+
+struct PendingSpend {
+    // The address that owns the spent UTXO.
+    address: String,
+    // The time the UTXO was spent at.
+    locked_at: u64,
+}
+
+thread_local! {
+    // The outpoints spent by transactions of this canister that aren't
+    // confirmed yet.
+    static PENDING_SPENDS: RefCell<BTreeMap<Outpoint, PendingSpend>> = RefCell::default();
+}
+
+/// Fetches all UTXOs of `address` with at least `min_confirmations`
+/// confirmations and returns the ones that can be spent, see
+/// `available_utxos`.
+pub async fn get_available_utxos(
+    network: BitcoinNetwork,
+    address: &str,
+    min_confirmations: u32,
+) -> Vec<Utxo> {
+    let utxos = bitcoin_api::get_all_utxos(network, address.to_string(), Some(min_confirmations))
+        .await
+        .utxos;
+    available_utxos(address, &utxos)
+}
+
+/// Returns the UTXOs that can be spent: the UTXOs of `address` without dust
+/// and without the UTXOs locked by pending spends.
+///
+/// Locks are released once their UTXO no longer appears in `utxos`, which
+/// means that the spending transaction was confirmed, or once they time out.
+/// `utxos` must therefore be all UTXOs of `address`.
+pub fn available_utxos(address: &str, utxos: &[Utxo]) -> Vec<Utxo> {
+    let now = ic_cdk::api::time();
+    PENDING_SPENDS.with_borrow_mut(|pending| {
+        pending.retain(|outpoint, spend| {
+            now - spend.locked_at < LOCK_TIMEOUT_NANOS
+                && (spend.address != address || utxos.iter().any(|utxo| &utxo.outpoint == outpoint))
+        });
+        utxos
+            .iter()
+            .filter(|utxo| utxo.value >= DUST_THRESHOLD && !pending.contains_key(&utxo.outpoint))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Locks the UTXOs spent by `transaction` so that they are not selected
+/// again until the transaction is confirmed.
+///
+/// This must be called before the first `await` after selecting the UTXOs:
+/// signing awaits other canisters, and other sends could select the same
+/// UTXOs meanwhile. The locks are
+/// released again when the returned `InputLocks` is dropped, unless `keep`
+/// is called once the transaction was sent.
+pub fn lock_inputs(address: &str, transaction: &Transaction) -> InputLocks {
+    let outpoints = transaction
+        .input
+        .iter()
+        .map(|input| to_outpoint(&input.previous_output))
+        .collect();
+    lock_outpoints(address, outpoints)
+}
+
+/// Locks the given UTXOs of `address`, like `lock_inputs`.
+pub fn lock_outpoints(address: &str, outpoints: Vec<Outpoint>) -> InputLocks {
+    let now = ic_cdk::api::time();
+    PENDING_SPENDS.with_borrow_mut(|pending| {
+        for outpoint in &outpoints {
+            let spend = PendingSpend {
+                address: address.to_string(),
+                locked_at: now,
+            };
+            pending.insert(outpoint.clone(), spend);
+        }
+    });
+    InputLocks { outpoints }
+}
+
+/// Releases the locks on the given UTXOs that were locked as UTXOs of
+/// `address`, e.g. because the transaction that spends them won't be sent.
+/// Returns the number of released locks.
+pub fn unlock_outpoints(address: &str, outpoints: &[Outpoint]) -> usize {
+    PENDING_SPENDS.with_borrow_mut(|pending| {
+        let locked = pending.len();
+        pending.retain(|outpoint, spend| spend.address != address || !outpoints.contains(outpoint));
+        locked - pending.len()
+    })
+}
+
+/// Converts an outpoint of the `bitcoin` crate to the outpoint of the
+/// bitcoin API.
+pub fn to_outpoint(outpoint: &OutPoint) -> Outpoint {
+    Outpoint {
+        txid: outpoint.txid.to_byte_array().to_vec(),
+        vout: outpoint.vout,
+    }
+}
+
+/// The locks taken by `lock_inputs` or `lock_outpoints`.
+///
+/// Dropping it releases the locks, so they are released on every path where
+/// the transaction is not sent: when sending returns an error, and also when
+/// the call traps after an `await`, since the canister then drops the pending
+/// future in a cleanup callback whose changes are kept.
+#[must_use]
+pub struct InputLocks {
+    outpoints: Vec<Outpoint>,
+}
+
+impl InputLocks {
+    /// Keeps the UTXOs locked until the transaction is confirmed or the locks
+    /// time out.
+    pub fn keep(mut self) {
+        self.outpoints.clear();
+    }
+}
+
+impl Drop for InputLocks {
+    fn drop(&mut self) {
+        PENDING_SPENDS.with_borrow_mut(|pending| {
+            for outpoint in &self.outpoints {
+                pending.remove(outpoint);
+            }
+        });
+    }
+}
+
+/// Selects UTXOs whose total value is at least `target`.
+///
+/// First, branch and bound looks for a selection that exceeds `target` by
+/// less than `DUST_THRESHOLD`, so that the transaction needs no change
+/// output. If there is none, the smallest single UTXO that covers `target`
+/// is used, and otherwise the largest UTXOs, which minimizes the number of
+/// inputs.
+///
+/// Returns `None` if the UTXOs are not enough to cover `target`.
+pub fn select_utxos(utxos: &[Utxo], target: Satoshi) -> Option<Vec<&Utxo>> {
+    let mut sorted: Vec<&Utxo> = utxos.iter().collect();
+    sorted.sort_by(|a, b| b.value.cmp(&a.value));
+
+    if let Some(selection) = branch_and_bound(&sorted, target) {
+        return Some(selection);
+    }
+
+    if let Some(utxo) = sorted.iter().rev().find(|utxo| utxo.value >= target) {
+        return Some(vec![utxo]);
+    }
+
+    let mut selection = vec![];
+    let mut total = 0;
+    for utxo in sorted {
+        if total >= target {
+            break;
+        }
+        total += utxo.value;
+        selection.push(utxo);
+    }
+    if total >= target {
+        Some(selection)
+    } else {
+        None
+    }
+}
+
+/// Depth-first search for the selection with the least excess over `target`
+/// among the selections whose excess is below `DUST_THRESHOLD`. `sorted`
+/// must be sorted by descending value.
+fn branch_and_bound<'a>(sorted: &[&'a Utxo], target: Satoshi) -> Option<Vec<&'a Utxo>> {
+    // remaining[i] is the total value of sorted[i..].
+    let mut remaining = vec![0; sorted.len() + 1];
+    for i in (0..sorted.len()).rev() {
+        remaining[i] = remaining[i + 1] + sorted[i].value;
+    }
+
+    struct Search<'a, 'b> {
+        sorted: &'b [&'a Utxo],
+        remaining: Vec<Satoshi>,
+        target: Satoshi,
+        tries: usize,
+        selected: Vec<usize>,
+        best: Option<(Satoshi, Vec<usize>)>,
+    }
+
+    impl Search<'_, '_> {
+        fn explore(&mut self, index: usize, total: Satoshi) {
+            self.tries += 1;
+            if self.tries > BNB_MAX_TRIES || total >= self.target + DUST_THRESHOLD {
+                return;
+            }
+            if total >= self.target {
+                let excess = total - self.target;
+                if self.best.as_ref().map_or(true, |(best, _)| excess < *best) {
+                    self.best = Some((excess, self.selected.clone()));
+                }
+                return;
+            }
+            if index == self.sorted.len() || total + self.remaining[index] < self.target {
+                return;
+            }
+            self.selected.push(index);
+            self.explore(index + 1, total + self.sorted[index].value);
+            self.selected.pop();
+            self.explore(index + 1, total);
+        }
+    }
+
+    let mut search = Search {
+        sorted,
+        remaining,
+        target,
+        tries: 0,
+        selected: vec![],
+        best: None,
+    };
+    search.explore(0, 0);
+    search
+        .best
+        .map(|(_, selected)| selected.into_iter().map(|i| sorted[i]).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxos(values: &[Satoshi]) -> Vec<Utxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, &value)| Utxo {
+                outpoint: Outpoint {
+                    txid: vec![0; 32],
+                    vout: vout as u32,
+                },
+                value,
+                height: 0,
+            })
+            .collect()
+    }
+
+    fn values(selection: Option<Vec<&Utxo>>) -> Option<Vec<Satoshi>> {
+        selection.map(|utxos| utxos.into_iter().map(|utxo| utxo.value).collect())
+    }
+
+    #[test]
+    fn selects_exact_match_without_change() {
+        let utxos = utxos(&[100_000, 60_000, 30_000, 20_000]);
+        assert_eq!(
+            values(select_utxos(&utxos, 80_000)),
+            Some(vec![60_000, 20_000])
+        );
+        assert_eq!(
+            values(select_utxos(&utxos, 89_500)),
+            Some(vec![60_000, 30_000])
+        );
+    }
+
+    #[test]
+    fn falls_back_to_smallest_covering_utxo() {
+        let utxos = utxos(&[100_000, 60_000, 30_000]);
+        assert_eq!(values(select_utxos(&utxos, 50_000)), Some(vec![60_000]));
+    }
+
+    #[test]
+    fn falls_back_to_largest_first() {
+        let utxos = utxos(&[40_000, 30_000, 20_000, 15_000]);
+        assert_eq!(
+            values(select_utxos(&utxos, 72_000)),
+            Some(vec![40_000, 30_000, 20_000])
+        );
+    }
+
+    #[test]
+    fn fails_on_insufficient_funds() {
+        let utxos = utxos(&[40_000, 30_000]);
+        assert_eq!(values(select_utxos(&utxos, 70_001)), None);
+        assert_eq!(values(select_utxos(&[], 1)), None);
+    }
+}
@@ -6,6 +6,7 @@ use std::collections::HashMap;
 
 // stores the ecdsa to maintain state across different calls to the canister (not across updates)
 thread_local! {
+    #[allow(clippy::type_complexity)]
     /* flexible */ static ECDSA: RefCell<Option<HashMap<Vec<Vec<u8>> /*derivation path*/, Vec<u8> /*public key*/>>> = RefCell::default();
 }
 
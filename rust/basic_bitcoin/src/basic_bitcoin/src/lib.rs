@@ -1,27 +1,26 @@
+mod accounts;
 mod bitcoin_api;
 mod bitcoin_wallet;
 mod ecdsa_api;
 mod schnorr_api;
 
+use accounts::AddressType;
 use candid::{CandidType, Deserialize};
 use ic_cdk::api::management_canister::{
-    bitcoin::{BitcoinNetwork, GetUtxosResponse, MillisatoshiPerByte},
+    bitcoin::{BitcoinNetwork, BlockHash, GetUtxosResponse, MillisatoshiPerByte, Satoshi, Utxo},
     main::CanisterId,
 };
-use ic_cdk_macros::{init, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 use std::cell::{Cell, RefCell};
 
-// Different derivation paths for different addresses to use different keys.
-const P2PKH_DERIVATION_PATH: &str = "p2pkh";
-const P2TR_DERIVATION_PATH_PREFIX: &str = "p2tr_key_and_script_path";
-const P2TR_KEY_ONLY_DERIVATION_PATH_PREFIX: &str = "p2tr_key_path_only";
+const DEFAULT_MIN_CONFIRMATIONS: u32 = 1;
 
 thread_local! {
     // The bitcoin network to connect to.
     //
     // When developing locally this should be `Regtest`.
     // When deploying to the IC this should be `Testnet` or `Mainnet`.
-    static NETWORK: Cell<BitcoinNetwork> = Cell::new(BitcoinNetwork::Testnet);
+    static NETWORK: Cell<BitcoinNetwork> = const { Cell::new(BitcoinNetwork::Testnet) };
 
     // The derivation path to use for the threshold key.
     static DERIVATION_PATH: Vec<Vec<u8>> = vec![];
@@ -29,6 +28,10 @@ thread_local! {
     // The ECDSA key name.
     static KEY_NAME: RefCell<String> = RefCell::new(String::from(""));
 
+    // The minimum number of confirmations of the UTXOs that the canister
+    // spends. Can be changed by the controllers with `set_min_confirmations`.
+    static MIN_CONFIRMATIONS: Cell<u32> = const { Cell::new(DEFAULT_MIN_CONFIRMATIONS) };
+
     // Management canister ID. Can be replaced for testing.
     static MGMT_CANISTER_ID: RefCell<String> = RefCell::new("aaaaa-aa".to_string());
 }
@@ -49,6 +52,17 @@ pub fn init(network: BitcoinNetwork) {
     KEY_NAME.with_borrow(|key_name| assert_ne!(key_name, ""));
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    accounts::save_addresses();
+}
+
+#[post_upgrade]
+fn post_upgrade(network: BitcoinNetwork) {
+    init(network);
+    accounts::restore_addresses();
+}
+
 /// Returns the balance of the given bitcoin address.
 #[update]
 pub async fn get_balance(address: String) -> u64 {
@@ -56,11 +70,37 @@ pub async fn get_balance(address: String) -> u64 {
     bitcoin_api::get_balance(network, address).await
 }
 
-/// Returns the UTXOs of the given bitcoin address.
+/// Returns the first page of the UTXOs of the given bitcoin address.
 #[update]
 pub async fn get_utxos(address: String) -> GetUtxosResponse {
     let network = NETWORK.with(|n| n.get());
-    bitcoin_api::get_utxos(network, address).await
+    bitcoin_api::get_utxos(network, address, None).await
+}
+
+/// All UTXOs of an address at a given tip, together with their total value.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct UtxosSnapshot {
+    pub balance: Satoshi,
+    pub utxos: Vec<Utxo>,
+    pub tip_block_hash: BlockHash,
+    pub tip_height: Height,
+}
+
+/// Returns all UTXOs of the given bitcoin address with at least
+/// `min_confirmations` confirmations, and their total value.
+///
+/// Unlike calling `get_balance` and `get_utxos` separately, the balance
+/// always matches the UTXOs, since both are taken at the same tip.
+#[update]
+pub async fn get_utxos_snapshot(address: String, min_confirmations: Option<u32>) -> UtxosSnapshot {
+    let network = NETWORK.with(|n| n.get());
+    let response = bitcoin_api::get_all_utxos(network, address, min_confirmations).await;
+    UtxosSnapshot {
+        balance: response.utxos.iter().map(|utxo| utxo.value).sum(),
+        utxos: response.utxos,
+        tip_block_hash: response.tip_block_hash,
+        tip_height: response.tip_height,
+    }
 }
 
 pub type Height = u32;
@@ -94,6 +134,24 @@ async fn for_test_only_change_management_canister_id(id: String) -> Result<(), S
     Ok(())
 }
 
+/// Returns the minimum number of confirmations of the UTXOs that the
+/// canister spends.
+#[query]
+pub fn get_min_confirmations() -> u32 {
+    MIN_CONFIRMATIONS.with(|m| m.get())
+}
+
+/// Sets the minimum number of confirmations of the UTXOs that the
+/// `send_from_${type}` endpoints and the p2wsh multisig PSBTs spend. Only the
+/// controllers of the canister can call this.
+#[update]
+pub fn set_min_confirmations(min_confirmations: u32) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("only controllers can set the minimum confirmations");
+    }
+    MIN_CONFIRMATIONS.with(|m| m.set(min_confirmations));
+}
+
 /// Returns the block headers in the given height range.
 #[update]
 pub async fn get_block_headers(
@@ -112,129 +170,328 @@ pub async fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
     bitcoin_api::get_current_fee_percentiles(network).await
 }
 
-/// Returns the P2PKH address of this canister at a specific derivation path.
+/// Returns the P2PKH address of the given account of the caller, or of their
+/// account 0 if none is given.
 #[update]
-pub async fn get_p2pkh_address() -> String {
-    let mut derivation_path: Vec<Vec<u8>> = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2PKH_DERIVATION_PATH.as_bytes().to_vec());
+pub async fn get_p2pkh_address(account: Option<u32>) -> String {
+    let (owner, account) = (accounts::owner(), account.unwrap_or_default());
+    let derivation_path = accounts::derivation_path(owner, account, AddressType::P2pkh);
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let network = NETWORK.with(|n| n.get());
-    bitcoin_wallet::p2pkh::get_address(network, key_name, derivation_path).await
+    let address = bitcoin_wallet::p2pkh::get_address(network, key_name, derivation_path).await;
+    accounts::record_address(owner, account, AddressType::P2pkh, None, &address);
+    address
 }
 
-/// Sends the given amount of bitcoin from this canister's p2pkh address to the given address.
-/// Returns the transaction ID.
+/// Sends the given amount of bitcoin from the p2pkh address of the caller's
+/// account to the given address. Returns the transaction ID.
 #[update]
-pub async fn send_from_p2pkh_address(request: SendRequest) -> String {
-    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2PKH_DERIVATION_PATH.as_bytes().to_vec());
+pub async fn send_from_p2pkh_address(request: SendRequest) -> Result<String, String> {
+    let derivation_path = request.derivation_path(AddressType::P2pkh);
     let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let tx_id = bitcoin_wallet::p2pkh::send(
         network,
+        min_confirmations,
+        derivation_path,
+        key_name,
+        request.destination_address,
+        request.amount_in_satoshi,
+    )
+    .await?;
+
+    Ok(tx_id.to_string())
+}
+
+/// Returns the P2WPKH address of the given account of the caller, or of their
+/// account 0 if none is given.
+#[update]
+pub async fn get_p2wpkh_address(account: Option<u32>) -> String {
+    let (owner, account) = (accounts::owner(), account.unwrap_or_default());
+    let derivation_path = accounts::derivation_path(owner, account, AddressType::P2wpkh);
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let network = NETWORK.with(|n| n.get());
+    let address = bitcoin_wallet::p2wpkh::get_address(network, key_name, derivation_path).await;
+    accounts::record_address(owner, account, AddressType::P2wpkh, None, &address);
+    address
+}
+
+/// Sends the given amount of bitcoin from the p2wpkh address of the caller's
+/// account to the given address. Returns the transaction ID.
+#[update]
+pub async fn send_from_p2wpkh_address(request: SendRequest) -> Result<String, String> {
+    let derivation_path = request.derivation_path(AddressType::P2wpkh);
+    let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let tx_id = bitcoin_wallet::p2wpkh::send(
+        network,
+        min_confirmations,
         derivation_path,
         key_name,
         request.destination_address,
         request.amount_in_satoshi,
     )
-    .await;
+    .await?;
 
-    tx_id.to_string()
+    Ok(tx_id.to_string())
 }
 
-/// Returns the P2TR address of this canister at a specific derivation path.
+/// Returns the P2TR address of the given account of the caller, or of their
+/// account 0 if none is given.
 #[update]
-pub async fn get_p2tr_address() -> String {
-    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2TR_DERIVATION_PATH_PREFIX.as_bytes().to_vec());
+pub async fn get_p2tr_address(account: Option<u32>) -> String {
+    let (owner, account) = (accounts::owner(), account.unwrap_or_default());
+    let derivation_path = accounts::derivation_path(owner, account, AddressType::P2tr);
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let network = NETWORK.with(|n| n.get());
 
-    bitcoin_wallet::p2tr::get_address(network, key_name, derivation_path)
+    let address = bitcoin_wallet::p2tr::get_address(network, key_name, derivation_path)
         .await
-        .to_string()
+        .to_string();
+    accounts::record_address(owner, account, AddressType::P2tr, None, &address);
+    address
 }
 
-/// Sends the given amount of bitcoin from this canister's p2tr address to the given address.
-/// Returns the transaction ID.
+/// Sends the given amount of bitcoin from the p2tr address of the caller's
+/// account to the given address. Returns the transaction ID.
 #[update]
-pub async fn send_from_p2tr_address_key_path(request: SendRequest) -> String {
-    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2TR_DERIVATION_PATH_PREFIX.as_bytes().to_vec());
+pub async fn send_from_p2tr_address_key_path(request: SendRequest) -> Result<String, String> {
+    let derivation_path = request.derivation_path(AddressType::P2tr);
     let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let tx_id = bitcoin_wallet::p2tr::send_key_path(
         network,
+        min_confirmations,
         derivation_path,
         key_name,
         request.destination_address,
         request.amount_in_satoshi,
     )
-    .await;
+    .await?;
 
-    tx_id.to_string()
+    Ok(tx_id.to_string())
 }
 
 #[update]
-pub async fn send_from_p2tr_address_script_path(request: SendRequest) -> String {
-    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2TR_DERIVATION_PATH_PREFIX.as_bytes().to_vec());
+pub async fn send_from_p2tr_address_script_path(request: SendRequest) -> Result<String, String> {
+    let derivation_path = request.derivation_path(AddressType::P2tr);
     let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let tx_id = bitcoin_wallet::p2tr::send_script_path(
         network,
+        min_confirmations,
         derivation_path,
         key_name,
         request.destination_address,
         request.amount_in_satoshi,
     )
-    .await;
+    .await?;
 
-    tx_id.to_string()
+    Ok(tx_id.to_string())
 }
 
-/// Returns the P2TR address of this canister at a specific derivation path.
+/// Returns the P2TR address of the given account of the caller, or of their
+/// account 0 if none is given, that can only be spent with the key path.
 #[update]
-pub async fn get_p2tr_key_only_address() -> String {
-    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2TR_KEY_ONLY_DERIVATION_PATH_PREFIX.as_bytes().to_vec());
+pub async fn get_p2tr_key_only_address(account: Option<u32>) -> String {
+    let (owner, account) = (accounts::owner(), account.unwrap_or_default());
+    let derivation_path = accounts::derivation_path(owner, account, AddressType::P2trKeyOnly);
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let network = NETWORK.with(|n| n.get());
 
-    bitcoin_wallet::p2tr_key_only::get_address(network, key_name, derivation_path)
+    let address = bitcoin_wallet::p2tr_key_only::get_address(network, key_name, derivation_path)
         .await
-        .to_string()
+        .to_string();
+    accounts::record_address(owner, account, AddressType::P2trKeyOnly, None, &address);
+    address
 }
 
-/// Sends the given amount of bitcoin from this canister's p2tr address to the
-/// given address. Returns the transaction ID.
+/// Sends the given amount of bitcoin from the p2tr key only address of the
+/// caller's account to the given address. Returns the transaction ID.
 ///
 /// IMPORTANT: This function uses an untweaked key as the spending key.
 ///
 /// WARNING: This function is not suited for multi-party scenarios where
 /// multiple keys are used for spending.
 #[update]
-pub async fn send_from_p2tr_key_only_address(request: SendRequest) -> String {
-    let mut derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    derivation_path.push(P2TR_KEY_ONLY_DERIVATION_PATH_PREFIX.as_bytes().to_vec());
+pub async fn send_from_p2tr_key_only_address(request: SendRequest) -> Result<String, String> {
+    let derivation_path = request.derivation_path(AddressType::P2trKeyOnly);
     let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let tx_id = bitcoin_wallet::p2tr_key_only::send(
         network,
+        min_confirmations,
         derivation_path,
         key_name,
         request.destination_address,
         request.amount_in_satoshi,
     )
-    .await;
+    .await?;
 
-    tx_id.to_string()
+    Ok(tx_id.to_string())
+}
+
+/// Returns the 2-of-2 P2WSH multisig address of the given account of the
+/// caller, or of their account 0 if none is given, and the given co-signer
+/// public key. Spending from it requires signatures of both the canister and
+/// the co-signer. Fails if the co-signer public key is not a valid compressed
+/// public key.
+#[update]
+pub async fn get_p2wsh_multisig_address(
+    cosigner_public_key: Vec<u8>,
+    account: Option<u32>,
+) -> Result<String, String> {
+    let (owner, account) = (accounts::owner(), account.unwrap_or_default());
+    let derivation_path = accounts::derivation_path(owner, account, AddressType::P2wshMultisig);
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let network = NETWORK.with(|n| n.get());
+    let address = bitcoin_wallet::p2wsh_multisig::get_address(
+        network,
+        key_name,
+        derivation_path,
+        &cosigner_public_key,
+    )
+    .await?;
+    accounts::record_address(
+        owner,
+        account,
+        AddressType::P2wshMultisig,
+        Some(&cosigner_public_key),
+        &address,
+    );
+    Ok(address)
+}
+
+/// Builds an unsigned PSBT (BIP-174) that sends the given amount of bitcoin
+/// from the caller's 2-of-2 p2wsh multisig address to the given address.
+///
+/// The PSBT is to be signed by the co-signer and then passed to
+/// `sign_and_send_p2wsh_multisig_psbt`.
+///
+/// Fails if the co-signer public key or the destination address is invalid,
+/// or if the address doesn't have enough funds.
+#[update]
+pub async fn build_p2wsh_multisig_psbt(request: PsbtRequest) -> Result<Vec<u8>, String> {
+    let derivation_path = accounts::derivation_path(
+        accounts::owner(),
+        request.account.unwrap_or_default(),
+        AddressType::P2wshMultisig,
+    );
+    let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let psbt = bitcoin_wallet::p2wsh_multisig::build_psbt(
+        network,
+        min_confirmations,
+        derivation_path,
+        key_name,
+        &request.cosigner_public_key,
+        request.destination_address,
+        request.amount_in_satoshi,
+    )
+    .await?;
+
+    Ok(psbt.serialize())
+}
+
+/// Adds the canister's signatures to a PSBT that was signed by the co-signer
+/// of the caller's 2-of-2 p2wsh multisig address, finalizes it, and sends
+/// the transaction. Returns the transaction ID.
+#[update]
+pub async fn sign_and_send_p2wsh_multisig_psbt(request: SignPsbtRequest) -> Result<String, String> {
+    let derivation_path = accounts::derivation_path(
+        accounts::owner(),
+        request.account.unwrap_or_default(),
+        AddressType::P2wshMultisig,
+    );
+    let network = NETWORK.with(|n| n.get());
+    let min_confirmations = MIN_CONFIRMATIONS.with(|m| m.get());
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let tx_id = bitcoin_wallet::p2wsh_multisig::sign_and_send_psbt(
+        network,
+        min_confirmations,
+        derivation_path,
+        key_name,
+        &request.cosigner_public_key,
+        &request.psbt,
+    )
+    .await?;
+
+    Ok(tx_id.to_string())
+}
+
+/// Unlocks the UTXOs that a PSBT built by `build_p2wsh_multisig_psbt` for the
+/// caller's 2-of-2 p2wsh multisig address spends, when the PSBT won't be
+/// signed and sent, so that they can be spent by other transactions.
+#[update]
+pub async fn cancel_p2wsh_multisig_psbt(request: CancelPsbtRequest) -> Result<(), String> {
+    let derivation_path = accounts::derivation_path(
+        accounts::owner(),
+        request.account.unwrap_or_default(),
+        AddressType::P2wshMultisig,
+    );
+    let network = NETWORK.with(|n| n.get());
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    bitcoin_wallet::p2wsh_multisig::cancel_psbt(
+        network,
+        derivation_path,
+        key_name,
+        &request.cosigner_public_key,
+        &request.psbt,
+    )
+    .await
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+pub struct PsbtRequest {
+    pub cosigner_public_key: Vec<u8>,
+    pub destination_address: String,
+    pub amount_in_satoshi: u64,
+    /// The caller's account to send from. Defaults to account 0.
+    pub account: Option<u32>,
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+pub struct SignPsbtRequest {
+    pub cosigner_public_key: Vec<u8>,
+    /// The serialized PSBT, signed by the co-signer.
+    pub psbt: Vec<u8>,
+    /// The caller's account to send from. Defaults to account 0.
+    pub account: Option<u32>,
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+pub struct CancelPsbtRequest {
+    pub cosigner_public_key: Vec<u8>,
+    /// The serialized PSBT, as returned by `build_p2wsh_multisig_psbt`.
+    pub psbt: Vec<u8>,
+    /// The caller's account that the PSBT sends from. Defaults to account 0.
+    pub account: Option<u32>,
 }
 
 #[derive(candid::CandidType, candid::Deserialize)]
 pub struct SendRequest {
     pub destination_address: String,
     pub amount_in_satoshi: u64,
+    /// The caller's account to send from. Defaults to account 0.
+    pub account: Option<u32>,
+}
+
+impl SendRequest {
+    // The derivation path of the sending address. It is derived from the
+    // caller, so that only the owner of an account can send from it.
+    fn derivation_path(&self, address_type: AddressType) -> Vec<Vec<u8>> {
+        accounts::derivation_path(
+            accounts::owner(),
+            self.account.unwrap_or_default(),
+            address_type,
+        )
+    }
 }
 
 /// The current management canister Schnorr API in Pocket IC / `dfx` is not yet
@@ -1,5 +1,6 @@
 use crate::env::CanisterEnvironment;
 use crate::SERVICE;
+use crate::timers;
 use crate::service::BasicDaoService;
 use ic_cdk_macros::init;
 use crate::types::BasicDaoStableStorage;
@@ -12,4 +13,5 @@ fn init(init_state: BasicDaoStableStorage) {
     init_service.env = Box::new(CanisterEnvironment {});
 
     SERVICE.with(|service| *service.borrow_mut() = init_service);
+    timers::schedule_all();
 }
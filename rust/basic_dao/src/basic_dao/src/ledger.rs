@@ -0,0 +1,786 @@
+use crate::service::BasicDaoService;
+use crate::types::*;
+use candid::Nat;
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use std::convert::TryFrom;
+
+pub const TOKEN_NAME: &str = "Basic DAO Token";
+pub const TOKEN_SYMBOL: &str = "BDAO";
+pub const TOKEN_DECIMALS: u8 = 8;
+
+/// The maximum length (in bytes) of a transaction memo
+pub const MAX_MEMO_BYTES: usize = 32;
+
+/// The amount of time (in nanoseconds) during which transactions are deduplicated
+pub const TRANSACTION_WINDOW_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The amount of time (in nanoseconds) `created_at_time` may be ahead of the ledger time
+pub const PERMITTED_DRIFT_NS: u64 = 2 * 60 * 1_000_000_000;
+
+/// The errors shared by all ICRC methods that create a transaction
+enum LedgerError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError(String),
+}
+
+/// Implements the ICRC-1 and ICRC-2 interface of the DAO token
+///
+/// Every account of the DAO is an ICRC-1 account. Staking, proposal deposits and
+/// the legacy `transfer` method use the default subaccount of the caller. The fee of
+/// a transaction is `SystemParams::transfer_fee`, and it is burned.
+impl BasicDaoService {
+    pub fn icrc1_fee(&self) -> Nat {
+        Nat::from(self.system_params.transfer_fee.amount_e8s)
+    }
+
+    pub fn icrc1_metadata(&self) -> Vec<(String, MetadataValue)> {
+        vec![
+            (
+                "icrc1:name".to_string(),
+                MetadataValue::Text(TOKEN_NAME.to_string()),
+            ),
+            (
+                "icrc1:symbol".to_string(),
+                MetadataValue::Text(TOKEN_SYMBOL.to_string()),
+            ),
+            (
+                "icrc1:decimals".to_string(),
+                MetadataValue::Nat(Nat::from(TOKEN_DECIMALS)),
+            ),
+            (
+                "icrc1:fee".to_string(),
+                MetadataValue::Nat(self.icrc1_fee()),
+            ),
+        ]
+    }
+
+    /// Return the amount of tokens in all accounts and stakes
+    pub fn icrc1_total_supply(&self) -> Nat {
+        let balances = self.accounts.values().map(|tokens| tokens.amount_e8s);
+        let stakes = self.stakes.values().map(|stake| stake.amount.amount_e8s);
+        Nat::from(balances.chain(stakes).map(u128::from).sum::<u128>())
+    }
+
+    pub fn icrc1_balance_of(&self, account: Account) -> Nat {
+        Nat::from(self.balance_of(&account).amount_e8s)
+    }
+
+    pub fn icrc1_supported_standards(&self) -> Vec<StandardRecord> {
+        vec![
+            StandardRecord {
+                name: "ICRC-1".to_string(),
+                url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+            },
+            StandardRecord {
+                name: "ICRC-2".to_string(),
+                url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+            },
+        ]
+    }
+
+    /// Transfer tokens from an account of the caller, and return the block index of the
+    /// transaction
+    pub fn icrc1_transfer(&mut self, args: TransferArg) -> Result<Nat, TransferError> {
+        let from = Account {
+            owner: self.env.caller(),
+            subaccount: args.from_subaccount,
+        };
+        let fee = self.system_params.transfer_fee;
+
+        if !self.is_expected_fee(&args.fee) {
+            return Err(TransferError::BadFee {
+                expected_fee: self.icrc1_fee(),
+            });
+        }
+        check_memo(&args.memo)?;
+        let amount = tokens_from_nat(&args.amount)?;
+
+        let transaction = Transaction {
+            operation: Operation::Transfer {
+                from,
+                to: args.to,
+                spender: None,
+                amount,
+                fee,
+            },
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            timestamp: self.env.now(),
+        };
+        self.deduplicate(&transaction)?;
+
+        let balance = self.balance_of(&from);
+        if amount.checked_add(fee).is_none_or(|debit| balance < debit) {
+            return Err(TransferError::InsufficientFunds {
+                balance: Nat::from(balance.amount_e8s),
+            });
+        }
+
+        Ok(self.apply(transaction))
+    }
+
+    /// Approve a spender to transfer tokens from an account of the caller, and return the
+    /// block index of the transaction
+    ///
+    /// The allowance is replaced, not increased, by the approved amount.
+    pub fn icrc2_approve(&mut self, args: ApproveArgs) -> Result<Nat, ApproveError> {
+        let from = Account {
+            owner: self.env.caller(),
+            subaccount: args.from_subaccount,
+        };
+        let fee = self.system_params.transfer_fee;
+        let now = self.env.now();
+
+        if from == args.spender {
+            return Err(ApproveError::GenericError {
+                error_code: Nat::from(0u8),
+                message: "An account cannot approve itself as spender".to_string(),
+            });
+        }
+        if !self.is_expected_fee(&args.fee) {
+            return Err(ApproveError::BadFee {
+                expected_fee: self.icrc1_fee(),
+            });
+        }
+        check_memo(&args.memo)?;
+        if matches!(args.expires_at, Some(expires_at) if expires_at <= now) {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+
+        // Allowances beyond the total supply are capped, as they cannot be exhausted anyway
+        let amount = tokens_from_nat(&args.amount).unwrap_or(Tokens {
+            amount_e8s: u64::MAX,
+        });
+        let expected_allowance = match &args.expected_allowance {
+            Some(expected) => Some(tokens_from_nat(expected)?),
+            None => None,
+        };
+
+        let transaction = Transaction {
+            operation: Operation::Approve {
+                from,
+                spender: args.spender,
+                amount,
+                expected_allowance,
+                expires_at: args.expires_at,
+                fee,
+            },
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            timestamp: now,
+        };
+        self.deduplicate(&transaction)?;
+
+        let current_allowance = self.allowance(&from, &args.spender);
+        if matches!(expected_allowance, Some(expected) if expected != current_allowance) {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: Nat::from(current_allowance.amount_e8s),
+            });
+        }
+
+        let balance = self.balance_of(&from);
+        if balance < fee {
+            return Err(ApproveError::InsufficientFunds {
+                balance: Nat::from(balance.amount_e8s),
+            });
+        }
+
+        Ok(self.apply(transaction))
+    }
+
+    pub fn icrc2_allowance(&self, args: AllowanceArgs) -> Allowance {
+        let allowance = self.allowance(&args.account, &args.spender);
+        let expires_at = match self.allowances.get(&(args.account, args.spender)) {
+            Some(approval) if allowance.amount_e8s > 0 => approval.expires_at,
+            _ => None,
+        };
+
+        Allowance {
+            allowance: Nat::from(allowance.amount_e8s),
+            expires_at,
+        }
+    }
+
+    /// Transfer tokens from an account that approved the caller as spender, and return the
+    /// block index of the transaction
+    ///
+    /// Both the amount and the fee are deducted from the caller's allowance.
+    pub fn icrc2_transfer_from(
+        &mut self,
+        args: TransferFromArgs,
+    ) -> Result<Nat, TransferFromError> {
+        let spender = Account {
+            owner: self.env.caller(),
+            subaccount: args.spender_subaccount,
+        };
+        let fee = self.system_params.transfer_fee;
+
+        if !self.is_expected_fee(&args.fee) {
+            return Err(TransferFromError::BadFee {
+                expected_fee: self.icrc1_fee(),
+            });
+        }
+        check_memo(&args.memo)?;
+        let amount = tokens_from_nat(&args.amount)?;
+
+        let transaction = Transaction {
+            operation: Operation::Transfer {
+                from: args.from,
+                to: args.to,
+                spender: Some(spender),
+                amount,
+                fee,
+            },
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            timestamp: self.env.now(),
+        };
+        self.deduplicate(&transaction)?;
+
+        // The owner of an account does not need an allowance to spend from it
+        if spender != args.from {
+            let allowance = self.allowance(&args.from, &spender);
+            if amount
+                .checked_add(fee)
+                .is_none_or(|debit| allowance < debit)
+            {
+                return Err(TransferFromError::InsufficientAllowance {
+                    allowance: Nat::from(allowance.amount_e8s),
+                });
+            }
+        }
+
+        let balance = self.balance_of(&args.from);
+        if amount.checked_add(fee).is_none_or(|debit| balance < debit) {
+            return Err(TransferFromError::InsufficientFunds {
+                balance: Nat::from(balance.amount_e8s),
+            });
+        }
+
+        Ok(self.apply(transaction))
+    }
+
+    /// Return the balance of the given account
+    pub fn balance_of(&self, account: &Account) -> Tokens {
+        self.accounts.get(account).copied().unwrap_or_default()
+    }
+
+    /// Transfer tokens between two accounts without checking the balance of `from`, record
+    /// the transaction, and return its block index
+    pub fn record_transfer(
+        &mut self,
+        from: Account,
+        to: Account,
+        amount: Tokens,
+        fee: Tokens,
+    ) -> Nat {
+        self.apply(Transaction {
+            operation: Operation::Transfer {
+                from,
+                to,
+                spender: None,
+                amount,
+                fee,
+            },
+            memo: None,
+            created_at_time: None,
+            timestamp: self.env.now(),
+        })
+    }
+
+    /// Return the allowance of `spender` on `account`, which is zero once it has expired
+    fn allowance(&self, account: &Account, spender: &Account) -> Tokens {
+        match self.allowances.get(&(*account, *spender)) {
+            Some(approval) if !matches!(approval.expires_at, Some(t) if t <= self.env.now()) => {
+                approval.allowance
+            }
+            _ => Tokens::default(),
+        }
+    }
+
+    /// Return true if the fee given by the caller, if any, is the transfer fee
+    fn is_expected_fee(&self, fee: &Option<Nat>) -> bool {
+        match fee {
+            Some(fee) => *fee == self.icrc1_fee(),
+            None => true,
+        }
+    }
+
+    /// Check the `created_at_time` of a transaction, and whether the same transaction
+    /// has already been recorded within the transaction window
+    fn deduplicate(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        let created_at_time = match transaction.created_at_time {
+            Some(created_at_time) => created_at_time,
+            None => return Ok(()),
+        };
+        let now = transaction.timestamp;
+
+        if created_at_time.saturating_add(TRANSACTION_WINDOW_NS + PERMITTED_DRIFT_NS) < now {
+            return Err(LedgerError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT_NS) {
+            return Err(LedgerError::CreatedInFuture { ledger_time: now });
+        }
+
+        let window_start = now.saturating_sub(TRANSACTION_WINDOW_NS + PERMITTED_DRIFT_NS);
+        match self
+            .transactions
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(_, recorded)| recorded.timestamp >= window_start)
+            .find(|(_, recorded)| transaction.is_duplicate_of(recorded))
+        {
+            Some((index, _)) => Err(LedgerError::Duplicate {
+                duplicate_of: Nat::from(index),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply a validated transaction to the balances and allowances, append it to the
+    /// transaction log, and return its block index
+    ///
+    /// Traps if a balance or allowance would overflow or underflow, which validation
+    /// should have ruled out, so that the state is rolled back instead of corrupted.
+    fn apply(&mut self, transaction: Transaction) -> Nat {
+        match &transaction.operation {
+            Operation::Transfer {
+                from,
+                to,
+                spender,
+                amount,
+                fee,
+            } => {
+                let debit = amount
+                    .checked_add(*fee)
+                    .expect("transfer amount plus fee overflows");
+                if let Some(spender) = spender.filter(|spender| spender != from) {
+                    let key = (*from, spender);
+                    if let Some(approval) = self.allowances.get_mut(&key) {
+                        approval.allowance = approval
+                            .allowance
+                            .checked_sub(debit)
+                            .expect("transfer exceeds the allowance");
+                        if approval.allowance.amount_e8s == 0 {
+                            self.allowances.remove(&key);
+                        }
+                    }
+                }
+                let from_balance = self.accounts.entry(*from).or_default();
+                *from_balance = from_balance
+                    .checked_sub(debit)
+                    .expect("transfer exceeds the balance");
+                let to_balance = self.accounts.entry(*to).or_default();
+                *to_balance = to_balance
+                    .checked_add(*amount)
+                    .expect("transfer overflows the balance of the recipient");
+            }
+            Operation::Approve {
+                from,
+                spender,
+                amount,
+                expires_at,
+                fee,
+                ..
+            } => {
+                let from_balance = self.accounts.entry(*from).or_default();
+                *from_balance = from_balance
+                    .checked_sub(*fee)
+                    .expect("approval fee exceeds the balance");
+                if amount.amount_e8s == 0 {
+                    self.allowances.remove(&(*from, *spender));
+                } else {
+                    let approval = Approval {
+                        account: *from,
+                        spender: *spender,
+                        allowance: *amount,
+                        expires_at: *expires_at,
+                    };
+                    self.allowances.insert((*from, *spender), approval);
+                }
+            }
+        }
+
+        self.transactions.push(transaction);
+        Nat::from(self.transactions.len() - 1)
+    }
+}
+
+/// Check that a memo is not longer than `MAX_MEMO_BYTES`
+fn check_memo(memo: &Option<Memo>) -> Result<(), LedgerError> {
+    match memo {
+        Some(memo) if memo.0.len() > MAX_MEMO_BYTES => Err(LedgerError::GenericError(format!(
+            "The memo must not be longer than {} bytes",
+            MAX_MEMO_BYTES
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Convert an amount of tokens given by the caller, which must fit into 64 bits
+fn tokens_from_nat(amount: &Nat) -> Result<Tokens, LedgerError> {
+    u64::try_from(&amount.0)
+        .map(|amount_e8s| Tokens { amount_e8s })
+        .map_err(|_| {
+            LedgerError::GenericError(format!(
+                "The amount {} exceeds the maximum amount of tokens",
+                amount
+            ))
+        })
+}
+
+impl From<LedgerError> for TransferError {
+    fn from(error: LedgerError) -> Self {
+        match error {
+            LedgerError::TooOld => TransferError::TooOld,
+            LedgerError::CreatedInFuture { ledger_time } => {
+                TransferError::CreatedInFuture { ledger_time }
+            }
+            LedgerError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            LedgerError::GenericError(message) => TransferError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        }
+    }
+}
+
+impl From<LedgerError> for ApproveError {
+    fn from(error: LedgerError) -> Self {
+        match error {
+            LedgerError::TooOld => ApproveError::TooOld,
+            LedgerError::CreatedInFuture { ledger_time } => {
+                ApproveError::CreatedInFuture { ledger_time }
+            }
+            LedgerError::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            LedgerError::GenericError(message) => ApproveError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        }
+    }
+}
+
+impl From<LedgerError> for TransferFromError {
+    fn from(error: LedgerError) -> Self {
+        match error {
+            LedgerError::TooOld => TransferFromError::TooOld,
+            LedgerError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+            LedgerError::Duplicate { duplicate_of } => {
+                TransferFromError::Duplicate { duplicate_of }
+            }
+            LedgerError::GenericError(message) => TransferFromError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::TestEnvironment;
+    use candid::Principal;
+
+    const FEE: u64 = 10;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn account(id: u8, subaccount: u8) -> Account {
+        Account {
+            owner: principal(id),
+            subaccount: Some([subaccount; 32]),
+        }
+    }
+
+    fn set_env(service: &mut BasicDaoService, caller: u8, now: u64) {
+        service.env = Box::new(TestEnvironment {
+            now,
+            caller: principal(caller),
+            canister_id: principal(0),
+        });
+    }
+
+    fn service_with_balance() -> BasicDaoService {
+        let mut service = BasicDaoService::default();
+        service.system_params.transfer_fee = Tokens { amount_e8s: FEE };
+        service
+            .accounts
+            .insert(Account::from(principal(1)), Tokens { amount_e8s: 1_000 });
+        set_env(&mut service, 1, 0);
+        service
+    }
+
+    fn transfer_arg(to: Account, amount: u64) -> TransferArg {
+        TransferArg {
+            from_subaccount: None,
+            to,
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: Nat::from(amount),
+        }
+    }
+
+    fn approve_args(spender: Account, amount: u64) -> ApproveArgs {
+        ApproveArgs {
+            from_subaccount: None,
+            spender,
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn transfer_from_args(to: Account, amount: u64) -> TransferFromArgs {
+        TransferFromArgs {
+            spender_subaccount: None,
+            from: Account::from(principal(1)),
+            to,
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn balance(service: &BasicDaoService, account: Account) -> Nat {
+        service.icrc1_balance_of(account)
+    }
+
+    #[test]
+    fn transfer_to_subaccount_burns_fee() {
+        let mut service = service_with_balance();
+
+        let block_index = service.icrc1_transfer(transfer_arg(account(2, 1), 100));
+
+        assert_eq!(block_index, Ok(Nat::from(0u8)));
+        assert_eq!(balance(&service, Account::from(principal(1))), 890u64);
+        assert_eq!(balance(&service, account(2, 1)), 100u64);
+        assert_eq!(balance(&service, Account::from(principal(2))), 0u64);
+        assert_eq!(service.icrc1_total_supply(), 990u64);
+    }
+
+    #[test]
+    fn default_subaccount_is_the_legacy_account() {
+        let mut service = service_with_balance();
+        let mut args = transfer_arg(Account::from(principal(2)), 100);
+        args.from_subaccount = Some([0; 32]);
+
+        service.icrc1_transfer(args).unwrap();
+
+        set_env(&mut service, 2, 0);
+        assert_eq!(service.account_balance(), Tokens { amount_e8s: 100 });
+    }
+
+    #[test]
+    fn transfer_checks_fee_and_funds() {
+        let mut service = service_with_balance();
+
+        let mut args = transfer_arg(account(2, 1), 100);
+        args.fee = Some(Nat::from(1u8));
+        assert_eq!(
+            service.icrc1_transfer(args),
+            Err(TransferError::BadFee {
+                expected_fee: Nat::from(FEE)
+            })
+        );
+
+        // The fee must be covered as well
+        assert_eq!(
+            service.icrc1_transfer(transfer_arg(account(2, 1), 995)),
+            Err(TransferError::InsufficientFunds {
+                balance: Nat::from(1_000u64)
+            })
+        );
+        assert!(service.transactions.is_empty());
+    }
+
+    #[test]
+    fn transfer_amount_plus_fee_overflow_is_rejected() {
+        let mut service = service_with_balance();
+        let amount = u64::MAX - FEE + 1;
+
+        assert_eq!(
+            service.icrc1_transfer(transfer_arg(account(2, 1), amount)),
+            Err(TransferError::InsufficientFunds {
+                balance: Nat::from(1_000u64)
+            })
+        );
+
+        service
+            .icrc2_approve(approve_args(Account::from(principal(2)), u64::MAX))
+            .unwrap();
+        set_env(&mut service, 2, 0);
+        assert_eq!(
+            service.icrc2_transfer_from(transfer_from_args(account(3, 1), amount)),
+            Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(u64::MAX)
+            })
+        );
+
+        set_env(&mut service, 1, 0);
+        let result = service.transfer(TransferArgs {
+            to: principal(2),
+            amount: Tokens { amount_e8s: amount },
+        });
+        assert!(result.is_err());
+
+        assert_eq!(balance(&service, Account::from(principal(1))), 990u64);
+        assert_eq!(balance(&service, account(2, 1)), 0u64);
+        assert_eq!(balance(&service, account(3, 1)), 0u64);
+        assert_eq!(service.icrc1_total_supply(), 990u64);
+    }
+
+    #[test]
+    fn legacy_transfer_cannot_underflow() {
+        let mut service = service_with_balance();
+
+        let result = service.transfer(TransferArgs {
+            to: principal(2),
+            amount: Tokens { amount_e8s: 995 },
+        });
+
+        assert!(result.is_err());
+        assert_eq!(balance(&service, Account::from(principal(1))), 1_000u64);
+    }
+
+    #[test]
+    fn transfer_with_created_at_time_is_deduplicated() {
+        let mut service = service_with_balance();
+        set_env(&mut service, 1, TRANSACTION_WINDOW_NS);
+        let mut args = transfer_arg(account(2, 1), 100);
+        args.created_at_time = Some(TRANSACTION_WINDOW_NS);
+        args.memo = Some(Memo::from(7));
+
+        service.icrc1_transfer(args.clone()).unwrap();
+        assert_eq!(
+            service.icrc1_transfer(args.clone()),
+            Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(0u8)
+            })
+        );
+
+        // A different memo makes it a different transaction
+        args.memo = Some(Memo::from(8));
+        assert_eq!(service.icrc1_transfer(args), Ok(Nat::from(1u8)));
+    }
+
+    #[test]
+    fn transfer_checks_created_at_time() {
+        let mut service = service_with_balance();
+        let now = 2 * TRANSACTION_WINDOW_NS;
+        set_env(&mut service, 1, now);
+        let mut args = transfer_arg(account(2, 1), 100);
+
+        args.created_at_time = Some(now - TRANSACTION_WINDOW_NS - PERMITTED_DRIFT_NS - 1);
+        assert_eq!(
+            service.icrc1_transfer(args.clone()),
+            Err(TransferError::TooOld)
+        );
+
+        args.created_at_time = Some(now + PERMITTED_DRIFT_NS + 1);
+        assert_eq!(
+            service.icrc1_transfer(args),
+            Err(TransferError::CreatedInFuture { ledger_time: now })
+        );
+    }
+
+    #[test]
+    fn transfer_rejects_long_memo() {
+        let mut service = service_with_balance();
+        let mut args = transfer_arg(account(2, 1), 100);
+        args.memo = Some(Memo::from(vec![0; MAX_MEMO_BYTES + 1]));
+
+        assert!(matches!(
+            service.icrc1_transfer(args),
+            Err(TransferError::GenericError { .. })
+        ));
+    }
+
+    #[test]
+    fn transfer_from_spends_allowance() {
+        let mut service = service_with_balance();
+        let spender = Account::from(principal(2));
+
+        service.icrc2_approve(approve_args(spender, 300)).unwrap();
+        assert_eq!(balance(&service, Account::from(principal(1))), 990u64);
+
+        set_env(&mut service, 2, 0);
+        assert_eq!(
+            service.icrc2_transfer_from(transfer_from_args(account(3, 1), 100)),
+            Ok(Nat::from(1u8))
+        );
+        assert_eq!(balance(&service, Account::from(principal(1))), 880u64);
+        assert_eq!(balance(&service, account(3, 1)), 100u64);
+
+        let allowance = service.icrc2_allowance(AllowanceArgs {
+            account: Account::from(principal(1)),
+            spender,
+        });
+        assert_eq!(allowance.allowance, 190u64);
+
+        assert_eq!(
+            service.icrc2_transfer_from(transfer_from_args(account(3, 1), 181)),
+            Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(190u64)
+            })
+        );
+    }
+
+    #[test]
+    fn expired_allowance_cannot_be_spent() {
+        let mut service = service_with_balance();
+        let mut args = approve_args(Account::from(principal(2)), 300);
+        args.expires_at = Some(100);
+        service.icrc2_approve(args).unwrap();
+
+        set_env(&mut service, 2, 100);
+        assert_eq!(
+            service.icrc2_transfer_from(transfer_from_args(account(3, 1), 100)),
+            Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(0u8)
+            })
+        );
+    }
+
+    #[test]
+    fn approve_checks_expected_allowance() {
+        let mut service = service_with_balance();
+        let spender = Account::from(principal(2));
+        service.icrc2_approve(approve_args(spender, 300)).unwrap();
+
+        let mut args = approve_args(spender, 500);
+        args.expected_allowance = Some(Nat::from(200u64));
+        assert_eq!(
+            service.icrc2_approve(args.clone()),
+            Err(ApproveError::AllowanceChanged {
+                current_allowance: Nat::from(300u64)
+            })
+        );
+
+        args.expected_allowance = Some(Nat::from(300u64));
+        service.icrc2_approve(args).unwrap();
+        let allowance = service.icrc2_allowance(AllowanceArgs {
+            account: Account::from(principal(1)),
+            spender,
+        });
+        assert_eq!(allowance.allowance, 500u64);
+    }
+}
@@ -1,12 +1,21 @@
 mod env;
-mod heartbeat;
 mod init;
+mod ledger;
 mod service;
+mod timers;
 mod types;
+mod upgrade;
 
 use crate::service::BasicDaoService;
 use crate::types::*;
+use candid::Nat;
 use ic_cdk_macros::*;
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 use std::cell::RefCell;
 
 thread_local! {
@@ -33,14 +42,18 @@ fn account_balance() -> Tokens {
 
 #[query]
 #[candid::candid_method(query)]
-fn list_accounts() -> Vec<Account> {
-    SERVICE.with(|service| service.borrow().list_accounts())
+fn list_accounts(args: ListAccountsArgs) -> ListAccountsResponse {
+    SERVICE.with(|service| service.borrow().list_accounts(args))
 }
 
 #[update]
 #[candid::candid_method]
 fn submit_proposal(proposal: ProposalPayload) -> Result<u64, String> {
-    SERVICE.with(|service| service.borrow_mut().submit_proposal(proposal))
+    let proposal_id = SERVICE.with(|service| service.borrow_mut().submit_proposal(proposal))?;
+    let voting_deadline =
+        SERVICE.with(|service| service.borrow().proposals[&proposal_id].voting_deadline);
+    timers::schedule_close(proposal_id, voting_deadline);
+    Ok(proposal_id)
 }
 
 #[query]
@@ -51,22 +64,153 @@ fn get_proposal(proposal_id: u64) -> Option<Proposal> {
 
 #[query]
 #[candid::candid_method(query)]
-fn list_proposals() -> Vec<Proposal> {
-    SERVICE.with(|service| service.borrow().list_proposals())
+fn list_proposals(args: ListProposalsArgs) -> ListProposalsResponse {
+    SERVICE.with(|service| service.borrow().list_proposals(args))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn list_ballots(args: ListBallotsArgs) -> Result<ListBallotsResponse, String> {
+    SERVICE.with(|service| service.borrow().list_ballots(args))
 }
 
 #[update]
 #[candid::candid_method]
 fn vote(args: VoteArgs) -> Result<ProposalState, String> {
-    SERVICE.with(|service| service.borrow_mut().vote(args))
+    let proposal_id = args.proposal_id;
+    let state = SERVICE.with(|service| service.borrow_mut().vote(args))?;
+    if state == ProposalState::Accepted {
+        timers::schedule_execution(proposal_id);
+    }
+    Ok(state)
+}
+
+#[update]
+#[candid::candid_method]
+fn stake(args: StakeArgs) -> Result<Stake, String> {
+    SERVICE.with(|service| service.borrow_mut().stake(args))
+}
+
+#[update]
+#[candid::candid_method]
+fn start_dissolving() -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().start_dissolving())
+}
+
+#[update]
+#[candid::candid_method]
+fn unstake() -> Result<Tokens, String> {
+    SERVICE.with(|service| service.borrow_mut().unstake())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_stake() -> Option<Stake> {
+    SERVICE.with(|service| service.borrow().get_stake())
+}
+
+#[update]
+#[candid::candid_method]
+fn delegate(args: DelegateArgs) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().delegate(args))
+}
+
+#[update]
+#[candid::candid_method]
+fn undelegate(args: UndelegateArgs) -> Result<(), String> {
+    SERVICE.with(|service| service.borrow_mut().undelegate(args))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn list_delegations() -> Vec<Delegation> {
+    SERVICE.with(|service| service.borrow().list_delegations())
 }
 
 #[update]
 #[candid::candid_method]
-fn update_system_params(payload: UpdateSystemParamsPayload) {
+fn update_system_params(payload: UpdateSystemParamsPayload) -> Result<(), String> {
     SERVICE.with(|service| service.borrow_mut().update_system_params(payload))
 }
 
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_name() -> String {
+    ledger::TOKEN_NAME.to_string()
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_symbol() -> String {
+    ledger::TOKEN_SYMBOL.to_string()
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_decimals() -> u8 {
+    ledger::TOKEN_DECIMALS
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_fee() -> Nat {
+    SERVICE.with(|service| service.borrow().icrc1_fee())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    SERVICE.with(|service| service.borrow().icrc1_metadata())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_total_supply() -> Nat {
+    SERVICE.with(|service| service.borrow().icrc1_total_supply())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_balance_of(account: Account) -> Nat {
+    SERVICE.with(|service| service.borrow().icrc1_balance_of(account))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    SERVICE.with(|service| service.borrow().icrc1_supported_standards())
+}
+
+#[update]
+#[candid::candid_method]
+fn icrc1_transfer(args: TransferArg) -> Result<Nat, TransferError> {
+    SERVICE.with(|service| service.borrow_mut().icrc1_transfer(args))
+}
+
+#[update]
+#[candid::candid_method]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    SERVICE.with(|service| service.borrow_mut().icrc2_approve(args))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    SERVICE.with(|service| service.borrow().icrc2_allowance(args))
+}
+
+#[update]
+#[candid::candid_method]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    SERVICE.with(|service| service.borrow_mut().icrc2_transfer_from(args))
+}
+
 candid::export_service!();
 
 #[ic_cdk_macros::query(name = "__get_candid_interface_tmp_hack")]
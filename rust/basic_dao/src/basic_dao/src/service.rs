@@ -1,14 +1,20 @@
 use crate::env::{EmptyEnvironment, Environment};
 use crate::types::*;
 use candid::Principal;
-use std::collections::HashMap;
+use icrc_ledger_types::icrc1::account::Account;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
 
 /// Implements the Basic DAO interface
 pub struct BasicDaoService {
     pub env: Box<dyn Environment>,
-    pub accounts: HashMap<Principal, Tokens>,
-    pub proposals: HashMap<u64, Proposal>,
+    pub accounts: BTreeMap<Account, Tokens>,
+    pub proposals: BTreeMap<u64, Proposal>,
     pub next_proposal_id: u64,
+    pub delegations: HashMap<(Principal, Option<ProposalTopic>), Principal>,
+    pub stakes: HashMap<Principal, Stake>,
+    pub allowances: HashMap<(Account, Account), Approval>,
+    pub transactions: Vec<Transaction>,
     pub system_params: SystemParams,
 }
 
@@ -16,9 +22,13 @@ impl Default for BasicDaoService {
     fn default() -> Self {
         BasicDaoService {
             env: Box::new(EmptyEnvironment {}),
-            accounts: HashMap::new(),
-            proposals: HashMap::new(),
+            accounts: BTreeMap::new(),
+            proposals: BTreeMap::new(),
             next_proposal_id: 0,
+            delegations: HashMap::new(),
+            stakes: HashMap::new(),
+            allowances: HashMap::new(),
+            transactions: vec![],
             system_params: Default::default(),
         }
     }
@@ -30,87 +40,166 @@ impl From<BasicDaoStableStorage> for BasicDaoService {
             .accounts
             .clone()
             .into_iter()
-            .map(|a| (a.owner, a.tokens))
+            .map(|balance| (balance.account(), balance.tokens))
             .collect();
-        let proposals = stable
+        let proposals: BTreeMap<u64, Proposal> = stable
             .proposals
             .clone()
             .into_iter()
             .map(|p| (p.id, p))
             .collect();
+        // Never hand out an ID that is already taken by an existing proposal
+        let next_proposal_id = proposals
+            .keys()
+            .map(|id| id + 1)
+            .fold(stable.next_proposal_id, u64::max);
+        let delegations = stable
+            .delegations
+            .into_iter()
+            .map(|d| ((d.delegator, d.topic), d.delegate))
+            .collect();
+        let stakes = stable
+            .stakes
+            .into_iter()
+            .map(|stake| (stake.owner, stake))
+            .collect();
+        let allowances = stable
+            .approvals
+            .into_iter()
+            .map(|approval| ((approval.account, approval.spender), approval))
+            .collect();
 
         BasicDaoService {
             env: Box::new(EmptyEnvironment {}),
             accounts,
             proposals,
-            next_proposal_id: 0,
+            next_proposal_id,
+            delegations,
+            stakes,
+            allowances,
+            transactions: stable.transactions,
             system_params: stable.system_params,
         }
     }
 }
 
+impl From<&BasicDaoService> for BasicDaoStableStorage {
+    fn from(service: &BasicDaoService) -> BasicDaoStableStorage {
+        BasicDaoStableStorage {
+            accounts: service
+                .accounts
+                .iter()
+                .map(|(account, tokens)| AccountBalance::new(*account, *tokens))
+                .collect(),
+            proposals: service.proposals.values().cloned().collect(),
+            next_proposal_id: service.next_proposal_id,
+            delegations: service.list_delegations(),
+            stakes: service.stakes.values().cloned().collect(),
+            approvals: service.allowances.values().cloned().collect(),
+            transactions: service.transactions.clone(),
+            system_params: service.system_params.clone(),
+        }
+    }
+}
+
 /// Implements the Basic DAO interface
 impl BasicDaoService {
-    /// Transfer tokens from the caller's account to another account
+    /// Transfer tokens from the caller's default account to the default account of another
+    /// principal
     pub fn transfer(&mut self, transfer: TransferArgs) -> Result<(), String> {
-        let caller = self.env.caller();
+        let from = Account::from(self.env.caller());
+        let fee = self.system_params.transfer_fee;
 
-        if let Some(account) = self.accounts.get_mut(&caller) {
-            if account.clone() < transfer.amount {
+        match self.accounts.get(&from) {
+            None => return Err("Caller needs an account to transfer funds".to_string()),
+            Some(balance)
+                if transfer
+                    .amount
+                    .checked_add(fee)
+                    .is_none_or(|debit| *balance < debit) =>
+            {
                 return Err(format!(
                     "Caller's account has insufficient funds to transfer {:?}",
                     transfer.amount
-                ));
-            } else {
-                *account -= transfer.amount + self.system_params.transfer_fee;
-                let to_account = self.accounts.entry(transfer.to).or_default();
-                *to_account += transfer.amount;
+                ))
             }
-        } else {
-            return Err("Caller needs an account to transfer funds".to_string());
+            Some(_) => (),
+        }
+
+        self.record_transfer(from, Account::from(transfer.to), transfer.amount, fee);
+        Ok(())
+    }
+
+    /// Transfer tokens from the DAO treasury, i.e. the default account of the DAO canister
+    pub fn transfer_from_treasury(&mut self, transfer: TransferArgs) -> Result<(), String> {
+        let treasury = Account::from(self.env.canister_id());
+        let fee = self.system_params.transfer_fee;
+
+        let balance = self.balance_of(&treasury);
+        if transfer
+            .amount
+            .checked_add(fee)
+            .is_none_or(|debit| balance < debit)
+        {
+            return Err(format!(
+                "The treasury has insufficient funds to transfer {:?}",
+                transfer.amount
+            ));
         }
 
+        self.record_transfer(treasury, Account::from(transfer.to), transfer.amount, fee);
         Ok(())
     }
 
-    /// Return the account balance of the caller
+    /// Return the balance of the caller's default account
     pub fn account_balance(&self) -> Tokens {
-        let caller = self.env.caller();
-        self.accounts
-            .get(&caller)
-            .cloned()
-            .unwrap_or_else(|| Default::default())
+        self.balance_of(&Account::from(self.env.caller()))
     }
 
-    /// Lists all accounts
-    pub fn list_accounts(&self) -> Vec<Account> {
-        self.accounts
-            .clone()
-            .into_iter()
-            .map(|(owner, tokens)| Account { owner, tokens })
-            .collect()
+    /// Lists a page of accounts, ordered by owner and subaccount
+    pub fn list_accounts(&self, args: ListAccountsArgs) -> ListAccountsResponse {
+        let start = match args.start_after {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let accounts = self
+            .accounts
+            .range((start, Bound::Unbounded))
+            .map(|(account, tokens)| AccountBalance::new(*account, *tokens));
+
+        let (accounts, next_cursor) = paginate(accounts, args.limit, AccountBalance::account);
+        ListAccountsResponse {
+            accounts,
+            next_cursor,
+        }
     }
 
     /// Submit a proposal
     ///
-    /// A proposal contains a canister ID, method name and method args. If enough users
-    /// vote "yes" on the proposal, the given method will be called with the given method
-    /// args on the given canister.
+    /// A proposal contains a payload describing the action to perform. The payload is
+    /// validated before the proposal is created. If enough users vote "yes" on the
+    /// proposal, its payload will be executed.
     pub fn submit_proposal(&mut self, payload: ProposalPayload) -> Result<u64, String> {
+        payload.validate()?;
         self.deduct_proposal_submission_deposit()?;
 
         let proposal_id = self.next_proposal_id;
         self.next_proposal_id += 1;
 
+        let now = self.env.now();
         let proposal = Proposal {
             id: proposal_id,
-            timestamp: self.env.now(),
+            timestamp: now,
             proposer: self.env.caller(),
+            summary: payload.to_string(),
+            topic: payload.topic(),
             payload,
             state: ProposalState::Open,
+            voting_deadline: now.saturating_add(self.system_params.voting_period_ns),
             votes_yes: Default::default(),
             votes_no: Default::default(),
-            voters: vec![],
+            ballots: vec![],
+            execution: None,
         };
 
         self.proposals.insert(proposal_id, proposal);
@@ -122,18 +211,65 @@ impl BasicDaoService {
         self.proposals.get(&proposal_id).cloned()
     }
 
-    /// Return the list of all proposals
-    pub fn list_proposals(&self) -> Vec<Proposal> {
-        self.proposals.values().cloned().collect()
+    /// Lists a page of the proposals matching the given filters, ordered by ID
+    pub fn list_proposals(&self, args: ListProposalsArgs) -> ListProposalsResponse {
+        let start = match args.start_after {
+            Some(cursor) => Bound::Excluded(cursor),
+            None => Bound::Unbounded,
+        };
+        let proposals = self
+            .proposals
+            .range((start, Bound::Unbounded))
+            .map(|(_, proposal)| proposal)
+            .filter(|proposal| args.matches(proposal))
+            .map(ProposalSummary::from);
+
+        let (proposals, next_cursor) = paginate(proposals, args.limit, |proposal| proposal.id);
+        ListProposalsResponse {
+            proposals,
+            next_cursor,
+        }
+    }
+
+    /// Lists a page of the ballots cast on a proposal, in the order they were cast
+    pub fn list_ballots(&self, args: ListBallotsArgs) -> Result<ListBallotsResponse, String> {
+        let proposal = self
+            .proposals
+            .get(&args.proposal_id)
+            .ok_or_else(|| format!("No proposal with ID {} exists", args.proposal_id))?;
+
+        let start = match args.start_after {
+            Some(cursor) => proposal
+                .ballots
+                .iter()
+                .position(|ballot| ballot.voter == cursor)
+                .map(|index| index + 1)
+                .ok_or_else(|| {
+                    format!("{} has not voted on proposal {}", cursor, args.proposal_id)
+                })?,
+            None => 0,
+        };
+        let ballots = proposal.ballots[start..].iter().cloned();
+
+        let (ballots, next_cursor) = paginate(ballots, args.limit, |ballot| ballot.voter);
+        Ok(ListBallotsResponse {
+            ballots,
+            next_cursor,
+        })
     }
 
     // Vote on an open proposal
+    //
+    // The caller votes with the voting power of their stake and the stakes of everyone who delegated
+    // their voting power to the caller (directly or transitively) and has not voted
+    // themselves. Voting directly overrides any delegation of the caller.
     pub fn vote(&mut self, args: VoteArgs) -> Result<ProposalState, String> {
         let caller = self.env.caller();
 
-        let proposal = self
+        let mut proposal = self
             .proposals
-            .get_mut(&args.proposal_id)
+            .get(&args.proposal_id)
+            .cloned()
             .ok_or_else(|| format!("No proposal with ID {} exists", args.proposal_id))?;
 
         if proposal.state != ProposalState::Open {
@@ -143,47 +279,263 @@ impl BasicDaoService {
             ));
         }
 
-        let voting_tokens = self
-            .accounts
-            .get(&caller)
-            .ok_or_else(|| "Caller does not have any tokens to vote with".to_string())?
-            .clone();
+        if self.env.now() >= proposal.voting_deadline {
+            return Err(format!(
+                "Voting period for proposal {} has ended",
+                args.proposal_id
+            ));
+        }
 
-        if proposal.voters.contains(&self.env.caller()) {
+        if proposal.ballots.iter().any(|ballot| ballot.voter == caller) {
             return Err("Already voted".to_string());
         }
 
-        match args.vote {
-            Vote::Yes => proposal.votes_yes += voting_tokens,
-            Vote::No => proposal.votes_no += voting_tokens,
-        }
+        proposal.ballots.push(Ballot {
+            voter: caller,
+            vote: args.vote,
+            voting_power: Default::default(),
+        });
+        self.tally_votes(&mut proposal);
 
-        proposal.voters.push(caller);
+        let voting_power = proposal.ballots.last().map(|ballot| ballot.voting_power);
+        if voting_power == Some(Tokens::default()) {
+            return Err(if self.stakes.contains_key(&caller) {
+                "Caller's stake does not have any voting power".to_string()
+            } else {
+                "Caller does not have any staked tokens to vote with".to_string()
+            });
+        }
 
         if proposal.votes_yes >= self.system_params.proposal_vote_threshold {
-            // Refund the proposal deposit when the proposal is accepted
-            if let Some(account) = self.accounts.get_mut(&proposal.proposer) {
-                *account += self.system_params.proposal_submission_deposit.clone();
+            self.accept_proposal(&mut proposal);
+        } else if proposal.votes_no >= self.system_params.proposal_vote_threshold {
+            proposal.state = ProposalState::Rejected;
+        }
+
+        let state = proposal.state.clone();
+        self.proposals.insert(proposal.id, proposal);
+        Ok(state)
+    }
+
+    /// Close all open proposals whose voting period has ended
+    ///
+    /// The votes are tallied again first, since voting power may have been delegated or
+    /// undelegated since the last vote. A proposal that reached quorum is accepted if it
+    /// has more "yes" than "no" votes, and rejected otherwise. A proposal that did not
+    /// reach quorum expires. Returns the IDs of the accepted proposals.
+    pub fn close_expired_proposals(&mut self) -> Vec<u64> {
+        let now = self.env.now();
+        let expired_ids: Vec<u64> = self
+            .proposals
+            .values()
+            .filter(|proposal| {
+                proposal.state == ProposalState::Open && now >= proposal.voting_deadline
+            })
+            .map(|proposal| proposal.id)
+            .collect();
+
+        let mut accepted_ids = vec![];
+        for proposal_id in expired_ids {
+            let mut proposal = self.proposals[&proposal_id].clone();
+            self.tally_votes(&mut proposal);
+
+            if proposal.votes_yes + proposal.votes_no < self.system_params.quorum {
+                proposal.state = ProposalState::Expired;
+            } else if proposal.votes_yes > proposal.votes_no {
+                self.accept_proposal(&mut proposal);
+                accepted_ids.push(proposal_id);
+            } else {
+                proposal.state = ProposalState::Rejected;
             }
 
-            proposal.state = ProposalState::Accepted;
+            self.proposals.insert(proposal_id, proposal);
         }
 
-        if proposal.votes_no >= self.system_params.proposal_vote_threshold {
-            proposal.state = ProposalState::Rejected;
+        accepted_ids
+    }
+
+    /// Stake tokens from the caller's account
+    ///
+    /// The tokens are added to the caller's stake, which is locked again with the given
+    /// dissolve delay. The dissolve delay of a stake can never be decreased.
+    pub fn stake(&mut self, args: StakeArgs) -> Result<Stake, String> {
+        let caller = self.env.caller();
+
+        let account = self
+            .accounts
+            .get_mut(&Account::from(caller))
+            .ok_or_else(|| "Caller needs an account to stake tokens".to_string())?;
+
+        if *account < args.amount {
+            return Err(format!(
+                "Caller's account has insufficient funds to stake {:?}",
+                args.amount
+            ));
+        }
+
+        let stake = self.stakes.entry(caller).or_insert_with(|| Stake {
+            owner: caller,
+            amount: Tokens::default(),
+            dissolve_delay_ns: 0,
+            dissolving_since: None,
+        });
+
+        if args.dissolve_delay_ns < stake.dissolve_delay_ns {
+            return Err(format!(
+                "Dissolve delay cannot be decreased below {} ns",
+                stake.dissolve_delay_ns
+            ));
+        }
+
+        *account -= args.amount;
+        stake.amount += args.amount;
+        stake.dissolve_delay_ns = args.dissolve_delay_ns;
+        stake.dissolving_since = None;
+
+        Ok(stake.clone())
+    }
+
+    /// Start dissolving the caller's stake
+    ///
+    /// A stake cannot start dissolving while it is counted in a vote on an open proposal.
+    pub fn start_dissolving(&mut self) -> Result<(), String> {
+        let caller = self.env.caller();
+        self.check_stake_unfrozen(caller)?;
+
+        let now = self.env.now();
+        let stake = self
+            .stakes
+            .get_mut(&caller)
+            .ok_or_else(|| "Caller does not have a stake".to_string())?;
+
+        if stake.dissolving_since.is_some() {
+            return Err("Caller's stake is already dissolving".to_string());
+        }
+
+        stake.dissolving_since = Some(now);
+        Ok(())
+    }
+
+    /// Return the caller's dissolved stake to their account
+    ///
+    /// A stake cannot be withdrawn while it is counted in a vote on an open proposal.
+    pub fn unstake(&mut self) -> Result<Tokens, String> {
+        let caller = self.env.caller();
+        self.check_stake_unfrozen(caller)?;
+
+        let stake = self
+            .stakes
+            .get(&caller)
+            .ok_or_else(|| "Caller does not have a stake".to_string())?;
+
+        if stake.dissolving_since.is_none() {
+            return Err("Caller's stake is not dissolving".to_string());
+        }
+
+        let remaining = stake.remaining_dissolve_delay_ns(self.env.now());
+        if remaining > 0 {
+            return Err(format!(
+                "Caller's stake is still dissolving for {} ns",
+                remaining
+            ));
+        }
+
+        let amount = stake.amount;
+        self.stakes.remove(&caller);
+        *self.accounts.entry(Account::from(caller)).or_default() += amount;
+
+        Ok(amount)
+    }
+
+    /// Return the stake of the caller, if they have one
+    pub fn get_stake(&self) -> Option<Stake> {
+        self.stakes.get(&self.env.caller()).cloned()
+    }
+
+    /// Delegate the caller's voting power to another principal
+    ///
+    /// If a topic is given, the delegation only applies to proposals with that topic and
+    /// takes precedence over a delegation without a topic. Delegations that would
+    /// create a cycle are rejected.
+    pub fn delegate(&mut self, args: DelegateArgs) -> Result<(), String> {
+        let caller = self.env.caller();
+
+        if args.to == caller {
+            return Err("Cannot delegate to self".to_string());
+        }
+
+        let previous = self.delegations.insert((caller, args.topic), args.to);
+
+        if ProposalTopic::ALL
+            .iter()
+            .any(|topic| self.delegation_chain_has_cycle(caller, *topic))
+        {
+            match previous {
+                Some(delegate) => self.delegations.insert((caller, args.topic), delegate),
+                None => self.delegations.remove(&(caller, args.topic)),
+            };
+            return Err(format!(
+                "Delegating to {} would create a delegation cycle",
+                args.to
+            ));
         }
 
-        Ok(proposal.state.clone())
+        Ok(())
+    }
+
+    /// Remove the caller's delegation for the given topic
+    pub fn undelegate(&mut self, args: UndelegateArgs) -> Result<(), String> {
+        let caller = self.env.caller();
+
+        self.delegations
+            .remove(&(caller, args.topic))
+            .map(|_| ())
+            .ok_or_else(|| format!("Caller has no delegation for topic {:?}", args.topic))
+    }
+
+    /// Lists all delegations
+    pub fn list_delegations(&self) -> Vec<Delegation> {
+        self.delegations
+            .iter()
+            .map(|(&(delegator, topic), &delegate)| Delegation {
+                delegator,
+                delegate,
+                topic,
+            })
+            .collect()
     }
 
     /// Update system params
     ///
     /// Only callable via proposal execution
-    pub fn update_system_params(&mut self, payload: UpdateSystemParamsPayload) {
+    pub fn update_system_params(
+        &mut self,
+        payload: UpdateSystemParamsPayload,
+    ) -> Result<(), String> {
         if self.env.caller() != self.env.canister_id() {
-            return;
+            return Err("Only the DAO itself can update system params".to_string());
         }
 
+        self.apply_system_params(payload)
+    }
+
+    /// Apply the given changes to the system params
+    ///
+    /// Fails without changing anything if the minimum dissolve delay would end up greater
+    /// than the maximum one.
+    pub fn apply_system_params(
+        &mut self,
+        payload: UpdateSystemParamsPayload,
+    ) -> Result<(), String> {
+        check_dissolve_delays(
+            payload
+                .min_dissolve_delay_ns
+                .unwrap_or(self.system_params.min_dissolve_delay_ns),
+            payload
+                .max_dissolve_delay_ns
+                .unwrap_or(self.system_params.max_dissolve_delay_ns),
+        )?;
+
         if let Some(transfer_fee) = payload.transfer_fee {
             self.system_params.transfer_fee = transfer_fee;
         }
@@ -195,26 +547,223 @@ impl BasicDaoService {
         if let Some(proposal_submission_deposit) = payload.proposal_submission_deposit {
             self.system_params.proposal_submission_deposit = proposal_submission_deposit;
         }
+
+        if let Some(voting_period_ns) = payload.voting_period_ns {
+            self.system_params.voting_period_ns = voting_period_ns;
+        }
+
+        if let Some(quorum) = payload.quorum {
+            self.system_params.quorum = quorum;
+        }
+
+        if let Some(min_dissolve_delay_ns) = payload.min_dissolve_delay_ns {
+            self.system_params.min_dissolve_delay_ns = min_dissolve_delay_ns;
+        }
+
+        if let Some(max_dissolve_delay_ns) = payload.max_dissolve_delay_ns {
+            self.system_params.max_dissolve_delay_ns = max_dissolve_delay_ns;
+        }
+
+        if let Some(execution_delay_ns) = payload.execution_delay_ns {
+            self.system_params.execution_delay_ns = execution_delay_ns;
+        }
+
+        Ok(())
+    }
+
+    /// Mark the given proposal as executing and return it, if it is accepted and its
+    /// execution delay has passed
+    pub fn start_execution(&mut self, proposal_id: u64) -> Option<Proposal> {
+        let now = self.env.now();
+        let proposal = self.proposals.get_mut(&proposal_id)?;
+
+        match &proposal.execution {
+            Some(execution)
+                if proposal.state == ProposalState::Accepted && now >= execution.executable_at =>
+            {
+                proposal.state = ProposalState::Executing;
+                Some(proposal.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Record an attempt to execute the given proposal and update its state accordingly
+    ///
+    /// A proposal whose execution failed transiently stays accepted and is executed again
+    /// after `EXECUTION_RETRY_DELAY_NS`, until `MAX_EXECUTION_ATTEMPTS` were made. Returns
+    /// the time of the next attempt if the proposal should be retried.
+    pub fn record_execution_attempt(
+        &mut self,
+        proposal_id: u64,
+        attempt: ExecutionAttempt,
+    ) -> Option<u64> {
+        let proposal = self.proposals.get_mut(&proposal_id)?;
+        let execution = proposal.execution.as_mut()?;
+
+        let next_attempt_at = attempt.finished_at.saturating_add(EXECUTION_RETRY_DELAY_NS);
+        proposal.state = match &attempt.result {
+            ExecutionResult::Ok(_) => ProposalState::Succeeded,
+            result
+                if result.is_transient()
+                    && execution.attempts.len() + 1 < MAX_EXECUTION_ATTEMPTS =>
+            {
+                execution.executable_at = next_attempt_at;
+                ProposalState::Accepted
+            }
+            ExecutionResult::Rejected { code, message } => ProposalState::Failed(format!(
+                "Proposal execution failed: rejection code: {:?}, message: {}",
+                code, message
+            )),
+            ExecutionResult::Err(message) => ProposalState::Failed(message.clone()),
+        };
+        execution.attempts.push(attempt);
+
+        if proposal.state == ProposalState::Accepted {
+            Some(next_attempt_at)
+        } else {
+            None
+        }
+    }
+
+    /// Accept the given proposal, refund its deposit and schedule its execution after the
+    /// execution delay
+    fn accept_proposal(&mut self, proposal: &mut Proposal) {
+        if let Some(account) = self.accounts.get_mut(&Account::from(proposal.proposer)) {
+            *account += self.system_params.proposal_submission_deposit;
+        }
+
+        let now = self.env.now();
+        proposal.state = ProposalState::Accepted;
+        proposal.execution = Some(ExecutionRecord {
+            accepted_at: now,
+            executable_at: now.saturating_add(self.system_params.execution_delay_ns),
+            attempts: vec![],
+        });
+    }
+
+    /// Return the principal the given principal delegates to for the given topic, if any
+    fn delegate_of(&self, principal: Principal, topic: ProposalTopic) -> Option<Principal> {
+        self.delegations
+            .get(&(principal, Some(topic)))
+            .or_else(|| self.delegations.get(&(principal, None)))
+            .copied()
+    }
+
+    /// Return true if following the delegations for the given topic from the given
+    /// principal leads back to a principal that was already visited
+    fn delegation_chain_has_cycle(&self, principal: Principal, topic: ProposalTopic) -> bool {
+        let mut visited = HashSet::new();
+        let mut current = principal;
+
+        while visited.insert(current) {
+            match self.delegate_of(current, topic) {
+                Some(delegate) => current = delegate,
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Return the index of the ballot that casts the vote of the given principal on the
+    /// given proposal, following the principal's delegations until a voter is found
+    fn find_ballot(&self, principal: Principal, proposal: &Proposal) -> Option<usize> {
+        let mut visited = HashSet::new();
+        let mut current = principal;
+
+        loop {
+            if let Some(index) = proposal
+                .ballots
+                .iter()
+                .position(|ballot| ballot.voter == current)
+            {
+                return Some(index);
+            }
+
+            if !visited.insert(current) {
+                return None;
+            }
+
+            current = self.delegate_of(current, proposal.topic)?;
+        }
+    }
+
+    /// Return the voting power of the given stake
+    ///
+    /// Stakes with less than the minimum dissolve delay left have no voting power. Other
+    /// stakes get a bonus of up to 100% of their amount, growing linearly with their
+    /// remaining dissolve delay up to the maximum dissolve delay.
+    fn voting_power(&self, stake: &Stake) -> Tokens {
+        let remaining = stake.remaining_dissolve_delay_ns(self.env.now());
+        if remaining < self.system_params.min_dissolve_delay_ns {
+            return Tokens::default();
+        }
+
+        let max_delay = self.system_params.max_dissolve_delay_ns;
+        if max_delay == 0 {
+            return stake.amount;
+        }
+
+        let bonus = stake.amount.amount_e8s as u128 * remaining.min(max_delay) as u128
+            / max_delay as u128;
+        Tokens {
+            amount_e8s: stake.amount.amount_e8s + bonus as u64,
+        }
     }
 
-    /// Update the state of a proposal
-    pub fn update_proposal_state(&mut self, proposal_id: u64, new_state: ProposalState) {
-        if let Some(proposal) = self.proposals.get_mut(&proposal_id) {
-            proposal.state = new_state
+    /// Return an error if the stake of the given principal is counted in a vote on an
+    /// open proposal, either directly or through delegation
+    fn check_stake_unfrozen(&self, principal: Principal) -> Result<(), String> {
+        let voted_proposal = self.proposals.values().find(|proposal| {
+            proposal.state == ProposalState::Open && self.find_ballot(principal, proposal).is_some()
+        });
+
+        match voted_proposal {
+            Some(proposal) => Err(format!(
+                "Caller's stake is frozen while proposal {} is open",
+                proposal.id
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Recount the votes on the given proposal
+    ///
+    /// The voting power of every stake is credited to the ballot that casts the stake
+    /// owner's vote, either directly or through delegation.
+    fn tally_votes(&self, proposal: &mut Proposal) {
+        for ballot in proposal.ballots.iter_mut() {
+            ballot.voting_power = Tokens::default();
+        }
+
+        for (owner, stake) in self.stakes.iter() {
+            if let Some(index) = self.find_ballot(*owner, proposal) {
+                proposal.ballots[index].voting_power += self.voting_power(stake);
+            }
+        }
+
+        proposal.votes_yes = Tokens::default();
+        proposal.votes_no = Tokens::default();
+        for ballot in proposal.ballots.iter() {
+            match ballot.vote {
+                Vote::Yes => proposal.votes_yes += ballot.voting_power,
+                Vote::No => proposal.votes_no += ballot.voting_power,
+            }
         }
     }
 
     /// Deduct the proposal submission deposit from the caller's account
     fn deduct_proposal_submission_deposit(&mut self) -> Result<(), String> {
         let caller = self.env.caller();
-        if let Some(account) = self.accounts.get_mut(&caller) {
-            if account.clone() < self.system_params.proposal_submission_deposit {
+        if let Some(account) = self.accounts.get_mut(&Account::from(caller)) {
+            if *account < self.system_params.proposal_submission_deposit {
                 return Err(format!(
                     "Caller's account must have at least {:?} to submit a proposal",
                     self.system_params.proposal_submission_deposit
                 ));
             } else {
-                *account -= self.system_params.proposal_submission_deposit.clone();
+                *account -= self.system_params.proposal_submission_deposit;
             }
         } else {
             return Err("Caller needs an account to submit a proposal".to_string());
@@ -223,3 +772,739 @@ impl BasicDaoService {
         Ok(())
     }
 }
+
+/// Take a page of at most `limit` items, and return the cursor of its last item if there
+/// are more items after it
+fn paginate<T, C>(
+    items: impl Iterator<Item = T>,
+    limit: Option<u32>,
+    cursor: impl Fn(&T) -> C,
+) -> (Vec<T>, Option<C>) {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut items = items.peekable();
+    let page: Vec<T> = items.by_ref().take(limit).collect();
+
+    let next_cursor = match (items.peek(), page.last()) {
+        (Some(_), Some(last)) => Some(cursor(last)),
+        _ => None,
+    };
+    (page, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::TestEnvironment;
+    use ic_cdk::api::call::RejectionCode;
+
+    const VOTING_PERIOD_NS: u64 = 1_000;
+    const EXECUTION_DELAY_NS: u64 = 500;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn tokens(amount_e8s: u64) -> Tokens {
+        Tokens { amount_e8s }
+    }
+
+    fn balance(service: &BasicDaoService, owner: Principal) -> Tokens {
+        service.balance_of(&Account::from(owner))
+    }
+
+    fn set_env(service: &mut BasicDaoService, caller: Principal, now: u64) {
+        service.env = Box::new(TestEnvironment {
+            now,
+            caller,
+            canister_id: principal(0),
+        });
+    }
+
+    fn service_with_proposal() -> (BasicDaoService, u64) {
+        let mut service = BasicDaoService::from(BasicDaoStableStorage {
+            accounts: (1..=3)
+                .map(|id| AccountBalance {
+                    owner: principal(id),
+                    subaccount: None,
+                    tokens: tokens(100),
+                })
+                .collect(),
+            proposals: vec![],
+            next_proposal_id: 0,
+            delegations: vec![],
+            stakes: (1..=3)
+                .map(|id| Stake {
+                    owner: principal(id),
+                    amount: tokens(100),
+                    dissolve_delay_ns: 0,
+                    dissolving_since: None,
+                })
+                .collect(),
+            approvals: vec![],
+            transactions: vec![],
+            system_params: SystemParams {
+                transfer_fee: tokens(0),
+                proposal_vote_threshold: tokens(250),
+                proposal_submission_deposit: tokens(10),
+                voting_period_ns: VOTING_PERIOD_NS,
+                quorum: tokens(150),
+                min_dissolve_delay_ns: 0,
+                max_dissolve_delay_ns: 0,
+                execution_delay_ns: EXECUTION_DELAY_NS,
+            },
+        });
+        set_env(&mut service, principal(1), 0);
+
+        let proposal_id = service
+            .submit_proposal(ProposalPayload::UpdateSystemParams(
+                UpdateSystemParamsPayload {
+                    transfer_fee: Some(tokens(1)),
+                    ..Default::default()
+                },
+            ))
+            .unwrap();
+
+        (service, proposal_id)
+    }
+
+    fn vote(service: &mut BasicDaoService, voter: u8, proposal_id: u64, vote: Vote) {
+        set_env(service, principal(voter), 0);
+        service.vote(VoteArgs { proposal_id, vote }).unwrap();
+    }
+
+    fn state_after_deadline(service: &mut BasicDaoService, proposal_id: u64) -> ProposalState {
+        set_env(service, principal(1), VOTING_PERIOD_NS);
+        service.close_expired_proposals();
+        service.get_proposal(proposal_id).unwrap().state
+    }
+
+    #[test]
+    fn proposal_stays_open_until_deadline() {
+        let (mut service, proposal_id) = service_with_proposal();
+
+        set_env(&mut service, principal(1), VOTING_PERIOD_NS - 1);
+        service.close_expired_proposals();
+
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().state,
+            ProposalState::Open
+        );
+    }
+
+    #[test]
+    fn proposal_without_quorum_expires() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+
+        assert_eq!(
+            state_after_deadline(&mut service, proposal_id),
+            ProposalState::Expired
+        );
+        assert_eq!(balance(&service, principal(1)), tokens(90));
+    }
+
+    #[test]
+    fn proposal_with_quorum_and_majority_is_accepted() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+        vote(&mut service, 3, proposal_id, Vote::Yes);
+
+        assert_eq!(
+            state_after_deadline(&mut service, proposal_id),
+            ProposalState::Accepted
+        );
+        assert_eq!(balance(&service, principal(1)), tokens(100));
+    }
+
+    #[test]
+    fn proposal_with_quorum_and_tie_is_rejected() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+        vote(&mut service, 3, proposal_id, Vote::No);
+
+        assert_eq!(
+            state_after_deadline(&mut service, proposal_id),
+            ProposalState::Rejected
+        );
+    }
+
+    #[test]
+    fn votes_are_tallied_again_when_proposal_closes() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+        delegate(&mut service, 3, 2, None);
+
+        assert_eq!(
+            state_after_deadline(&mut service, proposal_id),
+            ProposalState::Accepted
+        );
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().votes_yes,
+            tokens(200)
+        );
+    }
+
+    #[test]
+    fn accepted_proposal_is_not_rejected_by_the_same_vote() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+        service.system_params.proposal_vote_threshold = tokens(100);
+        vote(&mut service, 3, proposal_id, Vote::No);
+
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.state, ProposalState::Accepted);
+        assert!(proposal.execution.is_some());
+    }
+
+    #[test]
+    fn update_system_params_from_other_callers_fails() {
+        let (mut service, _) = service_with_proposal();
+
+        let result = service.update_system_params(UpdateSystemParamsPayload {
+            transfer_fee: Some(tokens(5)),
+            ..Default::default()
+        });
+
+        assert!(result.is_err());
+        assert_eq!(service.system_params.transfer_fee, tokens(0));
+    }
+
+    #[test]
+    fn voting_after_deadline_fails() {
+        let (mut service, proposal_id) = service_with_proposal();
+
+        set_env(&mut service, principal(2), VOTING_PERIOD_NS);
+        let result = service.vote(VoteArgs {
+            proposal_id,
+            vote: Vote::Yes,
+        });
+
+        assert!(result.unwrap_err().contains("has ended"));
+    }
+
+    #[test]
+    fn stable_storage_round_trip_preserves_state() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+
+        let mut restored = BasicDaoService::from(BasicDaoStableStorage::from(&service));
+        set_env(&mut restored, principal(1), 0);
+
+        assert_eq!(restored.accounts, service.accounts);
+        assert_eq!(
+            restored.get_proposal(proposal_id).unwrap().votes_yes,
+            tokens(100)
+        );
+        assert_eq!(restored.next_proposal_id, proposal_id + 1);
+    }
+
+    #[test]
+    fn next_proposal_id_skips_existing_proposals() {
+        let (service, proposal_id) = service_with_proposal();
+        let mut stable = BasicDaoStableStorage::from(&service);
+        stable.next_proposal_id = 0;
+
+        let restored = BasicDaoService::from(stable);
+
+        assert_eq!(restored.next_proposal_id, proposal_id + 1);
+    }
+
+    fn delegate(service: &mut BasicDaoService, from: u8, to: u8, topic: Option<ProposalTopic>) {
+        set_env(service, principal(from), 0);
+        service
+            .delegate(DelegateArgs {
+                to: principal(to),
+                topic,
+            })
+            .unwrap();
+    }
+
+    fn ballot_power(service: &BasicDaoService, proposal_id: u64, voter: u8) -> Tokens {
+        service
+            .get_proposal(proposal_id)
+            .unwrap()
+            .ballots
+            .iter()
+            .find(|ballot| ballot.voter == principal(voter))
+            .unwrap()
+            .voting_power
+    }
+
+    #[test]
+    fn delegated_voting_power_is_resolved_transitively() {
+        let (mut service, proposal_id) = service_with_proposal();
+        delegate(&mut service, 3, 2, None);
+        delegate(&mut service, 2, 1, None);
+        vote(&mut service, 1, proposal_id, Vote::Yes);
+
+        assert_eq!(ballot_power(&service, proposal_id, 1), tokens(300));
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().state,
+            ProposalState::Accepted
+        );
+    }
+
+    #[test]
+    fn voting_directly_overrides_delegation() {
+        let (mut service, proposal_id) = service_with_proposal();
+        delegate(&mut service, 3, 2, None);
+        delegate(&mut service, 2, 1, None);
+        vote(&mut service, 2, proposal_id, Vote::No);
+        vote(&mut service, 1, proposal_id, Vote::Yes);
+
+        let proposal = service.get_proposal(proposal_id).unwrap();
+        assert_eq!(proposal.votes_yes, tokens(100));
+        assert_eq!(proposal.votes_no, tokens(200));
+    }
+
+    #[test]
+    fn topic_delegation_takes_precedence() {
+        let (mut service, proposal_id) = service_with_proposal();
+        delegate(&mut service, 3, 1, None);
+        delegate(&mut service, 3, 2, Some(ProposalTopic::SystemParams));
+        vote(&mut service, 1, proposal_id, Vote::Yes);
+        vote(&mut service, 2, proposal_id, Vote::No);
+
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().topic,
+            ProposalTopic::SystemParams
+        );
+        assert_eq!(ballot_power(&service, proposal_id, 1), tokens(100));
+        assert_eq!(ballot_power(&service, proposal_id, 2), tokens(200));
+    }
+
+    #[test]
+    fn delegation_cycles_are_rejected() {
+        let (mut service, _) = service_with_proposal();
+        delegate(&mut service, 1, 2, None);
+        delegate(&mut service, 2, 3, Some(ProposalTopic::SystemParams));
+
+        set_env(&mut service, principal(3), 0);
+        let result = service.delegate(DelegateArgs {
+            to: principal(1),
+            topic: None,
+        });
+
+        assert!(result.unwrap_err().contains("cycle"));
+        assert_eq!(service.list_delegations().len(), 2);
+    }
+
+    #[test]
+    fn voting_power_grows_with_dissolve_delay() {
+        let (mut service, _) = service_with_proposal();
+        service.system_params.min_dissolve_delay_ns = 100;
+        service.system_params.max_dissolve_delay_ns = 1_000;
+        let stake = |dissolve_delay_ns, dissolving_since| Stake {
+            owner: principal(1),
+            amount: tokens(100),
+            dissolve_delay_ns,
+            dissolving_since,
+        };
+
+        assert_eq!(service.voting_power(&stake(99, None)), tokens(0));
+        assert_eq!(service.voting_power(&stake(500, None)), tokens(150));
+        assert_eq!(service.voting_power(&stake(5_000, None)), tokens(200));
+
+        set_env(&mut service, principal(1), 400);
+        assert_eq!(service.voting_power(&stake(500, Some(0))), tokens(100 + 10));
+        assert_eq!(service.voting_power(&stake(500, Some(350))), tokens(145));
+    }
+
+    #[test]
+    fn staked_tokens_cannot_vote_twice_after_transfer() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+
+        set_env(&mut service, principal(2), 0);
+        service
+            .transfer(TransferArgs {
+                to: principal(4),
+                amount: tokens(100),
+            })
+            .unwrap();
+        set_env(&mut service, principal(4), 0);
+        let result = service.vote(VoteArgs {
+            proposal_id,
+            vote: Vote::Yes,
+        });
+
+        assert!(result.unwrap_err().contains("staked tokens"));
+    }
+
+    #[test]
+    fn stake_is_frozen_while_voted_proposal_is_open() {
+        let (mut service, proposal_id) = service_with_proposal();
+        delegate(&mut service, 3, 2, None);
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+
+        set_env(&mut service, principal(3), 0);
+        assert!(service.start_dissolving().unwrap_err().contains("frozen"));
+
+        set_env(&mut service, principal(1), VOTING_PERIOD_NS);
+        service.close_expired_proposals();
+
+        set_env(&mut service, principal(3), VOTING_PERIOD_NS);
+        service.start_dissolving().unwrap();
+        assert_eq!(service.unstake().unwrap(), tokens(100));
+        assert_eq!(balance(&service, principal(3)), tokens(200));
+    }
+
+    #[test]
+    fn stake_can_only_be_withdrawn_after_dissolving() {
+        let (mut service, _) = service_with_proposal();
+        set_env(&mut service, principal(2), 0);
+        service
+            .stake(StakeArgs {
+                amount: tokens(50),
+                dissolve_delay_ns: 100,
+            })
+            .unwrap();
+        assert!(service.unstake().unwrap_err().contains("not dissolving"));
+
+        service.start_dissolving().unwrap();
+        set_env(&mut service, principal(2), 99);
+        assert!(service.unstake().unwrap_err().contains("still dissolving"));
+
+        set_env(&mut service, principal(2), 100);
+        assert_eq!(service.unstake().unwrap(), tokens(150));
+        assert_eq!(balance(&service, principal(2)), tokens(200));
+    }
+
+    #[test]
+    fn proposal_summary_describes_payload() {
+        let (mut service, proposal_id) = service_with_proposal();
+        assert_eq!(
+            service.get_proposal(proposal_id).unwrap().summary,
+            "Update system params: transfer_fee = 1 e8s"
+        );
+
+        let proposal_id = service
+            .submit_proposal(ProposalPayload::CallCanister {
+                canister_id: principal(0),
+                method: "transfer".to_string(),
+                candid_args_text: "(record { amount = 10 : nat64 })".to_string(),
+            })
+            .unwrap();
+        let proposal = service.get_proposal(proposal_id).unwrap();
+
+        assert_eq!(proposal.topic, ProposalTopic::CanisterCall);
+        assert!(proposal.summary.starts_with("Call transfer on canister"));
+        assert!(proposal.summary.contains("10 : nat64"));
+    }
+
+    #[test]
+    fn invalid_payloads_are_rejected_without_deposit() {
+        let (mut service, _) = service_with_proposal();
+        let invalid_payloads = vec![
+            ProposalPayload::UpdateSystemParams(Default::default()),
+            ProposalPayload::TransferTreasury(TransferArgs {
+                to: principal(2),
+                amount: tokens(0),
+            }),
+            ProposalPayload::CallCanister {
+                canister_id: principal(0),
+                method: "transfer".to_string(),
+                candid_args_text: "(record { amount = ".to_string(),
+            },
+            ProposalPayload::Motion {
+                text: " ".to_string(),
+            },
+        ];
+
+        for payload in invalid_payloads {
+            assert!(service.submit_proposal(payload).is_err());
+        }
+        assert_eq!(balance(&service, principal(1)), tokens(90));
+    }
+
+    #[test]
+    fn treasury_transfer_debits_dao_account() {
+        let (mut service, _) = service_with_proposal();
+        service
+            .accounts
+            .insert(Account::from(principal(0)), tokens(50));
+
+        let result = service.transfer_from_treasury(TransferArgs {
+            to: principal(2),
+            amount: tokens(60),
+        });
+        assert!(result.unwrap_err().contains("insufficient funds"));
+        assert!(service.transactions.is_empty());
+        assert_eq!(balance(&service, principal(2)), tokens(100));
+
+        service
+            .transfer_from_treasury(TransferArgs {
+                to: principal(2),
+                amount: tokens(50),
+            })
+            .unwrap();
+        assert_eq!(balance(&service, principal(0)), tokens(0));
+        assert_eq!(balance(&service, principal(2)), tokens(150));
+    }
+
+    #[test]
+    fn min_dissolve_delay_cannot_exceed_max() {
+        let (mut service, _) = service_with_proposal();
+        let payload = |min, max| UpdateSystemParamsPayload {
+            min_dissolve_delay_ns: min,
+            max_dissolve_delay_ns: max,
+            ..Default::default()
+        };
+
+        let result = service.submit_proposal(ProposalPayload::UpdateSystemParams(payload(
+            Some(200),
+            Some(100),
+        )));
+        assert!(result.unwrap_err().contains("minimum dissolve delay"));
+
+        service
+            .apply_system_params(payload(None, Some(100)))
+            .unwrap();
+        let result = service.apply_system_params(payload(Some(200), None));
+        assert!(result.unwrap_err().contains("minimum dissolve delay"));
+        assert_eq!(service.system_params.min_dissolve_delay_ns, 0);
+
+        service
+            .apply_system_params(payload(Some(100), None))
+            .unwrap();
+        assert_eq!(service.system_params.min_dissolve_delay_ns, 100);
+    }
+
+    fn accepted_proposal() -> (BasicDaoService, u64) {
+        let (mut service, proposal_id) = service_with_proposal();
+        delegate(&mut service, 3, 2, None);
+        vote(&mut service, 2, proposal_id, Vote::Yes);
+        vote(&mut service, 1, proposal_id, Vote::Yes);
+        (service, proposal_id)
+    }
+
+    fn attempt(finished_at: u64, result: ExecutionResult) -> ExecutionAttempt {
+        ExecutionAttempt {
+            started_at: finished_at,
+            finished_at,
+            result,
+        }
+    }
+
+    fn transient_rejection() -> ExecutionResult {
+        ExecutionResult::Rejected {
+            code: RejectionCode::SysTransient,
+            message: "busy".to_string(),
+        }
+    }
+
+    #[test]
+    fn accepted_proposal_is_executed_after_delay() {
+        let (mut service, proposal_id) = accepted_proposal();
+        let execution = service
+            .get_proposal(proposal_id)
+            .unwrap()
+            .execution
+            .unwrap();
+        assert_eq!(execution.executable_at, EXECUTION_DELAY_NS);
+
+        set_env(&mut service, principal(1), EXECUTION_DELAY_NS - 1);
+        assert!(service.start_execution(proposal_id).is_none());
+
+        set_env(&mut service, principal(1), EXECUTION_DELAY_NS);
+        assert!(service.start_execution(proposal_id).is_some());
+        assert!(service.start_execution(proposal_id).is_none());
+
+        let retry_at = service.record_execution_attempt(
+            proposal_id,
+            attempt(EXECUTION_DELAY_NS, ExecutionResult::Ok(vec![1, 2, 3])),
+        );
+        let proposal = service.get_proposal(proposal_id).unwrap();
+
+        assert_eq!(retry_at, None);
+        assert_eq!(proposal.state, ProposalState::Succeeded);
+        assert_eq!(proposal.execution.unwrap().attempts.len(), 1);
+    }
+
+    #[test]
+    fn transient_execution_failures_are_retried_a_bounded_number_of_times() {
+        let (mut service, proposal_id) = accepted_proposal();
+
+        for attempt_number in 1..MAX_EXECUTION_ATTEMPTS {
+            let now = attempt_number as u64 * EXECUTION_RETRY_DELAY_NS;
+            set_env(&mut service, principal(1), now);
+            service.start_execution(proposal_id).unwrap();
+
+            let retry_at =
+                service.record_execution_attempt(proposal_id, attempt(now, transient_rejection()));
+
+            assert_eq!(retry_at, Some(now + EXECUTION_RETRY_DELAY_NS));
+            assert_eq!(
+                service.get_proposal(proposal_id).unwrap().state,
+                ProposalState::Accepted
+            );
+        }
+
+        let now = MAX_EXECUTION_ATTEMPTS as u64 * EXECUTION_RETRY_DELAY_NS;
+        set_env(&mut service, principal(1), now);
+        service.start_execution(proposal_id).unwrap();
+        let retry_at =
+            service.record_execution_attempt(proposal_id, attempt(now, transient_rejection()));
+        let proposal = service.get_proposal(proposal_id).unwrap();
+
+        assert_eq!(retry_at, None);
+        assert!(matches!(proposal.state, ProposalState::Failed(_)));
+        assert_eq!(
+            proposal.execution.unwrap().attempts.len(),
+            MAX_EXECUTION_ATTEMPTS
+        );
+    }
+
+    #[test]
+    fn permanent_execution_failures_are_not_retried() {
+        let (mut service, proposal_id) = accepted_proposal();
+        set_env(&mut service, principal(1), EXECUTION_DELAY_NS);
+        service.start_execution(proposal_id).unwrap();
+
+        let retry_at = service.record_execution_attempt(
+            proposal_id,
+            attempt(
+                EXECUTION_DELAY_NS,
+                ExecutionResult::Rejected {
+                    code: RejectionCode::CanisterReject,
+                    message: "no".to_string(),
+                },
+            ),
+        );
+
+        assert_eq!(retry_at, None);
+        assert!(matches!(
+            service.get_proposal(proposal_id).unwrap().state,
+            ProposalState::Failed(_)
+        ));
+    }
+
+    fn submit_motion(service: &mut BasicDaoService, proposer: u8, now: u64) -> u64 {
+        set_env(service, principal(proposer), now);
+        service
+            .submit_proposal(ProposalPayload::Motion {
+                text: "motion".to_string(),
+            })
+            .unwrap()
+    }
+
+    fn proposal_ids(response: &ListProposalsResponse) -> Vec<u64> {
+        response
+            .proposals
+            .iter()
+            .map(|proposal| proposal.id)
+            .collect()
+    }
+
+    #[test]
+    fn proposals_are_listed_in_pages_ordered_by_id() {
+        let (mut service, _) = service_with_proposal();
+        for now in 1..5 {
+            submit_motion(&mut service, 2, now);
+        }
+
+        let first_page = service.list_proposals(ListProposalsArgs {
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(proposal_ids(&first_page), vec![0, 1]);
+        assert_eq!(first_page.next_cursor, Some(1));
+
+        let last_page = service.list_proposals(ListProposalsArgs {
+            start_after: Some(3),
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(proposal_ids(&last_page), vec![4]);
+        assert_eq!(last_page.next_cursor, None);
+    }
+
+    #[test]
+    fn proposals_are_filtered_by_state_proposer_and_time() {
+        let (mut service, proposal_id) = service_with_proposal();
+        for voter in 1..=3 {
+            vote(&mut service, voter, proposal_id, Vote::No);
+        }
+        for now in 1..5 {
+            submit_motion(&mut service, 2, now);
+        }
+        submit_motion(&mut service, 3, 3);
+
+        let rejected = service.list_proposals(ListProposalsArgs {
+            state: Some(ProposalState::Rejected),
+            ..Default::default()
+        });
+        assert_eq!(proposal_ids(&rejected), vec![proposal_id]);
+
+        let filtered = service.list_proposals(ListProposalsArgs {
+            state: Some(ProposalState::Open),
+            proposer: Some(principal(2)),
+            from_timestamp: Some(2),
+            to_timestamp: Some(4),
+            ..Default::default()
+        });
+        assert_eq!(proposal_ids(&filtered), vec![2, 3]);
+    }
+
+    #[test]
+    fn ballots_are_listed_in_pages_with_their_voting_power() {
+        let (mut service, proposal_id) = service_with_proposal();
+        vote(&mut service, 1, proposal_id, Vote::Yes);
+        vote(&mut service, 2, proposal_id, Vote::No);
+
+        let first_page = service
+            .list_ballots(ListBallotsArgs {
+                proposal_id,
+                start_after: None,
+                limit: Some(1),
+            })
+            .unwrap();
+        assert_eq!(first_page.ballots.len(), 1);
+        assert_eq!(first_page.ballots[0].voter, principal(1));
+        assert_eq!(first_page.ballots[0].vote, Vote::Yes);
+        assert_eq!(first_page.ballots[0].voting_power, tokens(100));
+        assert_eq!(first_page.next_cursor, Some(principal(1)));
+
+        let last_page = service
+            .list_ballots(ListBallotsArgs {
+                proposal_id,
+                start_after: first_page.next_cursor,
+                limit: Some(1),
+            })
+            .unwrap();
+        assert_eq!(last_page.ballots[0].voter, principal(2));
+        assert_eq!(last_page.ballots[0].vote, Vote::No);
+        assert_eq!(last_page.next_cursor, None);
+
+        let proposals = service.list_proposals(Default::default());
+        assert_eq!(proposals.proposals[0].ballot_count, 2);
+
+        assert!(service
+            .list_ballots(ListBallotsArgs {
+                proposal_id,
+                start_after: Some(principal(3)),
+                limit: None,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn accounts_are_listed_in_pages() {
+        let (service, _) = service_with_proposal();
+
+        let first_page = service.list_accounts(ListAccountsArgs {
+            start_after: None,
+            limit: Some(2),
+        });
+        let owners: Vec<Principal> = first_page.accounts.iter().map(|a| a.owner).collect();
+        assert_eq!(owners, vec![principal(1), principal(2)]);
+
+        let last_page = service.list_accounts(ListAccountsArgs {
+            start_after: first_page.next_cursor,
+            limit: Some(2),
+        });
+        let owners: Vec<Principal> = last_page.accounts.iter().map(|a| a.owner).collect();
+        assert_eq!(owners, vec![principal(3)]);
+        assert_eq!(last_page.next_cursor, None);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::types::{
+    encode_candid_args, ExecutionAttempt, ExecutionResult, Proposal, ProposalPayload, ProposalState,
+};
+use crate::SERVICE;
+use std::time::Duration;
+
+/// Schedule timers for all proposals that are waiting to be closed or executed
+///
+/// Timers do not survive upgrades, so this is called whenever the canister is
+/// (re)installed. Neither do the callbacks of calls made by an execution, so a
+/// proposal that is still executing was interrupted, by a trap or by the upgrade,
+/// and is executed again.
+pub fn schedule_all() {
+    let (open, accepted): (Vec<(u64, u64)>, Vec<u64>) = SERVICE.with(|service| {
+        let mut service = service.borrow_mut();
+        let open = service
+            .proposals
+            .values()
+            .filter(|proposal| proposal.state == ProposalState::Open)
+            .map(|proposal| (proposal.id, proposal.voting_deadline))
+            .collect();
+        let accepted = service
+            .proposals
+            .values_mut()
+            .filter(|proposal| {
+                proposal.state == ProposalState::Accepted
+                    || proposal.state == ProposalState::Executing
+            })
+            .map(|proposal| {
+                proposal.state = ProposalState::Accepted;
+                proposal.id
+            })
+            .collect();
+        (open, accepted)
+    });
+
+    for (proposal_id, voting_deadline) in open {
+        schedule_close(proposal_id, voting_deadline);
+    }
+
+    for proposal_id in accepted {
+        schedule_execution(proposal_id);
+    }
+}
+
+/// Schedule closing the given proposal when its voting period ends
+pub fn schedule_close(proposal_id: u64, voting_deadline: u64) {
+    ic_cdk_timers::set_timer(delay_until(voting_deadline), move || {
+        let accepted_ids = SERVICE.with(|service| service.borrow_mut().close_expired_proposals());
+        if accepted_ids.contains(&proposal_id) {
+            schedule_execution(proposal_id);
+        }
+    });
+}
+
+/// Schedule executing the given accepted proposal once its execution delay has passed
+pub fn schedule_execution(proposal_id: u64) {
+    let executable_at = SERVICE.with(|service| {
+        service
+            .borrow()
+            .proposals
+            .get(&proposal_id)
+            .and_then(|proposal| proposal.execution.as_ref())
+            .map(|execution| execution.executable_at)
+    });
+
+    if let Some(executable_at) = executable_at {
+        ic_cdk_timers::set_timer(delay_until(executable_at), move || {
+            ic_cdk::spawn(execute_accepted_proposal(proposal_id))
+        });
+    }
+}
+
+/// Execute the given proposal and record the attempt, retrying transient failures
+async fn execute_accepted_proposal(proposal_id: u64) {
+    let proposal = match SERVICE.with(|service| service.borrow_mut().start_execution(proposal_id)) {
+        Some(proposal) => proposal,
+        None => return,
+    };
+
+    let started_at = ic_cdk::api::time();
+    let result = execute_proposal(proposal).await;
+    let attempt = ExecutionAttempt {
+        started_at,
+        finished_at: ic_cdk::api::time(),
+        result,
+    };
+
+    let retry_at = SERVICE.with(|service| {
+        service
+            .borrow_mut()
+            .record_execution_attempt(proposal_id, attempt)
+    });
+    if retry_at.is_some() {
+        schedule_execution(proposal_id);
+    }
+}
+
+/// Execute the given proposal
+async fn execute_proposal(proposal: Proposal) -> ExecutionResult {
+    match proposal.payload {
+        ProposalPayload::UpdateSystemParams(payload) => {
+            match SERVICE.with(|service| service.borrow_mut().apply_system_params(payload)) {
+                Ok(()) => ExecutionResult::Ok(vec![]),
+                Err(msg) => ExecutionResult::Err(msg),
+            }
+        }
+        ProposalPayload::TransferTreasury(args) => {
+            match SERVICE.with(|service| service.borrow_mut().transfer_from_treasury(args)) {
+                Ok(()) => ExecutionResult::Ok(vec![]),
+                Err(msg) => ExecutionResult::Err(msg),
+            }
+        }
+        ProposalPayload::CallCanister {
+            canister_id,
+            method,
+            candid_args_text,
+        } => {
+            let args = match encode_candid_args(&candid_args_text) {
+                Ok(args) => args,
+                Err(msg) => return ExecutionResult::Err(msg),
+            };
+
+            match ic_cdk::api::call::call_raw(canister_id, &method, args, 0).await {
+                Ok(reply) => ExecutionResult::Ok(reply),
+                Err((code, message)) => ExecutionResult::Rejected {
+                    code,
+                    message: format!(
+                        "canister: {}, method: {}, message: {}",
+                        canister_id, method, message
+                    ),
+                },
+            }
+        }
+        ProposalPayload::Motion { .. } => ExecutionResult::Ok(vec![]),
+    }
+}
+
+/// Return the duration from now until the given time
+fn delay_until(time_ns: u64) -> Duration {
+    Duration::from_nanos(time_ns.saturating_sub(ic_cdk::api::time()))
+}
@@ -1,10 +1,20 @@
 use candid::{CandidType, Deserialize, Principal};
+use candid_parser::parse_idl_args;
+use ic_cdk::api::call::RejectionCode;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::Memo;
+use std::fmt;
 use std::ops::{Add, AddAssign, Mul, SubAssign};
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize)]
 pub struct BasicDaoStableStorage {
-    pub accounts: Vec<Account>,
+    pub accounts: Vec<AccountBalance>,
     pub proposals: Vec<Proposal>,
+    pub next_proposal_id: u64,
+    pub delegations: Vec<Delegation>,
+    pub stakes: Vec<Stake>,
+    pub approvals: Vec<Approval>,
+    pub transactions: Vec<Transaction>,
     pub system_params: SystemParams,
 }
 
@@ -13,6 +23,22 @@ pub struct Tokens {
     pub amount_e8s: u64,
 }
 
+impl Tokens {
+    /// Add two amounts, returning `None` on overflow
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.amount_e8s
+            .checked_add(other.amount_e8s)
+            .map(|amount_e8s| Tokens { amount_e8s })
+    }
+
+    /// Subtract two amounts, returning `None` on underflow
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        self.amount_e8s
+            .checked_sub(other.amount_e8s)
+            .map(|amount_e8s| Tokens { amount_e8s })
+    }
+}
+
 impl Add for Tokens {
     type Output = Self;
 
@@ -64,45 +90,418 @@ pub enum ProposalState {
 
     // A failure occurred while executing the proposal
     Failed(String),
+
+    // The voting period ended without reaching quorum, and the proposal will not be executed
+    Expired,
+}
+
+// The topic of a Proposal. Voting power can be delegated separately for each topic.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq, Eq, Hash)]
+pub enum ProposalTopic {
+    // The proposal updates the system params of the DAO
+    SystemParams,
+
+    // The proposal transfers tokens from the DAO treasury
+    Treasury,
+
+    // The proposal calls a canister method
+    CanisterCall,
+
+    // The proposal is a motion that is not executed
+    Motion,
 }
 
-/// A proposal is a proposition to execute an arbitrary canister call
+impl ProposalTopic {
+    pub const ALL: [ProposalTopic; 4] = [
+        ProposalTopic::SystemParams,
+        ProposalTopic::Treasury,
+        ProposalTopic::CanisterCall,
+        ProposalTopic::Motion,
+    ];
+}
+
+/// A proposal is a proposition to perform the action described by its payload
 ///
-/// Token holders can vote to either accept the proposal and execute the given
-/// canister call, or vote to reject the proposal and not execute the canister
-/// call.
+/// Token holders can vote to either accept the proposal and execute its payload,
+/// or vote to reject the proposal and not execute its payload.
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Proposal {
     pub id: u64,
     pub timestamp: u64,
     pub proposer: Principal,
     pub payload: ProposalPayload,
+    pub summary: String,
+    pub topic: ProposalTopic,
     pub state: ProposalState,
+    pub voting_deadline: u64,
     pub votes_yes: Tokens,
     pub votes_no: Tokens,
-    pub voters: Vec<Principal>,
+    pub ballots: Vec<Ballot>,
+    pub execution: Option<ExecutionRecord>,
 }
 
-/// The data needed to call a given method on a given canister with given args
+/// A proposal without its ballots, as returned by `list_proposals`
+///
+/// The ballots of a proposal can grow large, so they are listed separately by
+/// `list_ballots`.
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct ProposalPayload {
-    pub canister_id: Principal,
-    pub method: String,
-    pub message: Vec<u8>,
+pub struct ProposalSummary {
+    pub id: u64,
+    pub timestamp: u64,
+    pub proposer: Principal,
+    pub payload: ProposalPayload,
+    pub summary: String,
+    pub topic: ProposalTopic,
+    pub state: ProposalState,
+    pub voting_deadline: u64,
+    pub votes_yes: Tokens,
+    pub votes_no: Tokens,
+    pub ballot_count: u64,
+    pub execution: Option<ExecutionRecord>,
 }
 
+impl From<&Proposal> for ProposalSummary {
+    fn from(proposal: &Proposal) -> Self {
+        ProposalSummary {
+            id: proposal.id,
+            timestamp: proposal.timestamp,
+            proposer: proposal.proposer,
+            payload: proposal.payload.clone(),
+            summary: proposal.summary.clone(),
+            topic: proposal.topic,
+            state: proposal.state.clone(),
+            voting_deadline: proposal.voting_deadline,
+            votes_yes: proposal.votes_yes,
+            votes_no: proposal.votes_no,
+            ballot_count: proposal.ballots.len() as u64,
+            execution: proposal.execution.clone(),
+        }
+    }
+}
+
+/// The execution history of an accepted proposal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ExecutionRecord {
+    pub accepted_at: u64,
+    // The proposal is not executed before this time, see `SystemParams::execution_delay_ns`
+    pub executable_at: u64,
+    pub attempts: Vec<ExecutionAttempt>,
+}
+
+/// A single attempt to execute a proposal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ExecutionAttempt {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub result: ExecutionResult,
+}
+
+/// The outcome of an attempt to execute a proposal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ExecutionResult {
+    // The proposal was executed. For canister calls, this holds the raw reply.
+    Ok(Vec<u8>),
+
+    // The canister call of the proposal was rejected
+    Rejected {
+        code: RejectionCode,
+        message: String,
+    },
+
+    // The proposal could not be executed by the DAO itself
+    Err(String),
+}
+
+impl ExecutionResult {
+    /// Return true if executing the proposal again may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ExecutionResult::Rejected {
+                code: RejectionCode::SysTransient,
+                ..
+            }
+        )
+    }
+}
+
+/// The maximum length (in bytes) of the text of a motion
+pub const MAX_MOTION_TEXT_BYTES: usize = 10_000;
+
+/// The maximum number of attempts to execute a proposal that fails transiently
+pub const MAX_EXECUTION_ATTEMPTS: usize = 3;
+
+/// The amount of time (in nanoseconds) to wait before retrying a transiently failed execution
+pub const EXECUTION_RETRY_DELAY_NS: u64 = 60_000_000_000;
+
+/// The action that is performed when a proposal is executed
 #[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ProposalPayload {
+    // Update the system params of the DAO
+    UpdateSystemParams(UpdateSystemParamsPayload),
+
+    // Transfer tokens from the DAO treasury, i.e. the account of the DAO canister itself
+    TransferTreasury(TransferArgs),
+
+    // Call the given method on the given canister with the given Candid text arguments,
+    // e.g. "(record { amount = 10 : nat64 })"
+    CallCanister {
+        canister_id: Principal,
+        method: String,
+        candid_args_text: String,
+    },
+
+    // A statement the DAO votes on without executing anything
+    Motion { text: String },
+}
+
+impl ProposalPayload {
+    /// Return the topic of a proposal with this payload
+    pub fn topic(&self) -> ProposalTopic {
+        match self {
+            ProposalPayload::UpdateSystemParams(_) => ProposalTopic::SystemParams,
+            ProposalPayload::TransferTreasury(_) => ProposalTopic::Treasury,
+            ProposalPayload::CallCanister { .. } => ProposalTopic::CanisterCall,
+            ProposalPayload::Motion { .. } => ProposalTopic::Motion,
+        }
+    }
+
+    /// Check that the payload can be executed as given
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ProposalPayload::UpdateSystemParams(payload) => {
+                if payload.voting_period_ns == Some(0) {
+                    return Err("The voting period must be greater than 0".to_string());
+                }
+                if payload.is_empty() {
+                    return Err("The proposal does not update any system params".to_string());
+                }
+                if let (Some(min), Some(max)) =
+                    (payload.min_dissolve_delay_ns, payload.max_dissolve_delay_ns)
+                {
+                    check_dissolve_delays(min, max)?;
+                }
+            }
+            ProposalPayload::TransferTreasury(args) => {
+                if args.amount == Tokens::default() {
+                    return Err("The transfer amount must be greater than 0".to_string());
+                }
+            }
+            ProposalPayload::CallCanister {
+                method,
+                candid_args_text,
+                ..
+            } => {
+                if method.is_empty() {
+                    return Err("The method name must not be empty".to_string());
+                }
+                encode_candid_args(candid_args_text)?;
+            }
+            ProposalPayload::Motion { text } => {
+                if text.trim().is_empty() {
+                    return Err("The motion text must not be empty".to_string());
+                }
+                if text.len() > MAX_MOTION_TEXT_BYTES {
+                    return Err(format!(
+                        "The motion text must not be longer than {} bytes",
+                        MAX_MOTION_TEXT_BYTES
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ProposalPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProposalPayload::UpdateSystemParams(payload) => {
+                write!(f, "Update system params: {}", payload)
+            }
+            ProposalPayload::TransferTreasury(args) => write!(
+                f,
+                "Transfer {} e8s from the treasury to {}",
+                args.amount.amount_e8s, args.to
+            ),
+            ProposalPayload::CallCanister {
+                canister_id,
+                method,
+                candid_args_text,
+            } => match parse_idl_args(candid_args_text) {
+                Ok(args) => write!(f, "Call {} on canister {} with {}", method, canister_id, args),
+                Err(_) => write!(
+                    f,
+                    "Call {} on canister {} with {}",
+                    method, canister_id, candid_args_text
+                ),
+            },
+            ProposalPayload::Motion { text } => write!(f, "Motion: {}", text),
+        }
+    }
+}
+
+/// Check that the given minimum dissolve delay is not greater than the maximum one
+pub fn check_dissolve_delays(
+    min_dissolve_delay_ns: u64,
+    max_dissolve_delay_ns: u64,
+) -> Result<(), String> {
+    if min_dissolve_delay_ns > max_dissolve_delay_ns {
+        return Err(format!(
+            "The minimum dissolve delay ({} ns) must not be greater than the maximum dissolve delay ({} ns)",
+            min_dissolve_delay_ns, max_dissolve_delay_ns
+        ));
+    }
+    Ok(())
+}
+
+/// Parse the given Candid text arguments and return their binary encoding
+pub fn encode_candid_args(candid_args_text: &str) -> Result<Vec<u8>, String> {
+    let args = parse_idl_args(candid_args_text)
+        .map_err(|e| format!("Invalid Candid arguments: {}", e))?;
+    args.to_bytes()
+        .map_err(|e| format!("Invalid Candid arguments: {}", e))
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
 pub enum Vote {
     Yes,
     No,
 }
 
+/// A vote cast directly by a voter
+///
+/// The voting power of a ballot is the voting power of the voter's stake, which grows
+/// with its remaining dissolve delay, plus that of the stakes of everyone who
+/// (transitively) delegated to the voter and did not vote themselves.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Ballot {
+    pub voter: Principal,
+    pub vote: Vote,
+    pub voting_power: Tokens,
+}
+
+/// A delegation of the delegator's voting power to the delegate
+///
+/// A delegation without a topic applies to all topics the delegator has not delegated
+/// separately.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Delegation {
+    pub delegator: Principal,
+    pub delegate: Principal,
+    pub topic: Option<ProposalTopic>,
+}
+
+/// Tokens locked by their owner in exchange for voting power
+///
+/// A stake can only be withdrawn after it has been dissolving for its whole dissolve
+/// delay. The longer the dissolve delay, the more voting power the stake has.
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct Account {
+pub struct Stake {
     pub owner: Principal,
+    pub amount: Tokens,
+    pub dissolve_delay_ns: u64,
+    pub dissolving_since: Option<u64>,
+}
+
+impl Stake {
+    /// Return the time (in nanoseconds) left until the stake is dissolved
+    pub fn remaining_dissolve_delay_ns(&self, now: u64) -> u64 {
+        match self.dissolving_since {
+            Some(since) => self
+                .dissolve_delay_ns
+                .saturating_sub(now.saturating_sub(since)),
+            None => self.dissolve_delay_ns,
+        }
+    }
+}
+
+/// The balance of an ICRC-1 account, i.e. a principal and an optional subaccount
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AccountBalance {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
     pub tokens: Tokens,
 }
 
+impl AccountBalance {
+    pub fn new(account: Account, tokens: Tokens) -> Self {
+        AccountBalance {
+            owner: account.owner,
+            subaccount: account.subaccount,
+            tokens,
+        }
+    }
+
+    /// Return the account this balance belongs to
+    pub fn account(&self) -> Account {
+        Account {
+            owner: self.owner,
+            subaccount: self.subaccount,
+        }
+    }
+}
+
+/// An ICRC-2 allowance of `spender` to transfer tokens from `account`
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Approval {
+    pub account: Account,
+    pub spender: Account,
+    pub allowance: Tokens,
+    pub expires_at: Option<u64>,
+}
+
+/// An entry of the DAO token's transaction log. Its index in the log is its block index.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Transaction {
+    pub operation: Operation,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+    pub timestamp: u64,
+}
+
+impl Transaction {
+    /// Return true if both transactions were requested with the same arguments, which
+    /// makes them duplicates if `created_at_time` is set
+    pub fn is_duplicate_of(&self, other: &Transaction) -> bool {
+        self.created_at_time.is_some()
+            && self.created_at_time == other.created_at_time
+            && self.memo == other.memo
+            && self.operation == other.operation
+    }
+}
+
+// The operation recorded by a Transaction. Fees are burned.
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum Operation {
+    // Tokens were transferred, either by the owner of `from` or by an approved `spender`
+    Transfer {
+        from: Account,
+        to: Account,
+        spender: Option<Account>,
+        amount: Tokens,
+        fee: Tokens,
+    },
+
+    // The owner of `from` approved `spender` to transfer up to `amount` tokens
+    Approve {
+        from: Account,
+        spender: Account,
+        amount: Tokens,
+        expected_allowance: Option<Tokens>,
+        expires_at: Option<u64>,
+        fee: Tokens,
+    },
+}
+
+/// A token standard supported by the DAO ledger, see `icrc1_supported_standards`
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct TransferArgs {
     pub to: Principal,
@@ -115,6 +514,106 @@ pub struct VoteArgs {
     pub vote: Vote,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StakeArgs {
+    pub amount: Tokens,
+    pub dissolve_delay_ns: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DelegateArgs {
+    pub to: Principal,
+    pub topic: Option<ProposalTopic>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UndelegateArgs {
+    pub topic: Option<ProposalTopic>,
+}
+
+/// The number of items returned by a paginated query if the caller does not set a limit
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+
+/// The maximum number of items returned by a paginated query
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ListProposalsArgs {
+    // Only return proposals with a greater ID, i.e. the `next_cursor` of the previous page
+    pub start_after: Option<u64>,
+    pub limit: Option<u32>,
+
+    // Only return proposals in this state. The message of a `Failed` state is ignored.
+    pub state: Option<ProposalState>,
+
+    // Only return proposals submitted by this principal
+    pub proposer: Option<Principal>,
+
+    // Only return proposals submitted at or after this time
+    pub from_timestamp: Option<u64>,
+
+    // Only return proposals submitted before this time
+    pub to_timestamp: Option<u64>,
+}
+
+impl ListProposalsArgs {
+    /// Return true if the proposal matches all filters
+    pub fn matches(&self, proposal: &Proposal) -> bool {
+        let state = self.state.as_ref().is_none_or(|state| {
+            std::mem::discriminant(state) == std::mem::discriminant(&proposal.state)
+        });
+        let proposer = self
+            .proposer
+            .is_none_or(|proposer| proposer == proposal.proposer);
+        let from = self
+            .from_timestamp
+            .is_none_or(|from| proposal.timestamp >= from);
+        let to = self.to_timestamp.is_none_or(|to| proposal.timestamp < to);
+
+        state && proposer && from && to
+    }
+}
+
+/// A page of proposals, ordered by ID
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ListProposalsResponse {
+    pub proposals: Vec<ProposalSummary>,
+    // Set if there may be more proposals, pass it as `start_after` to get the next page
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ListAccountsArgs {
+    // Only return accounts ordered after this one, i.e. the `next_cursor` of the previous page
+    pub start_after: Option<Account>,
+    pub limit: Option<u32>,
+}
+
+/// A page of accounts, ordered by owner and subaccount
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ListAccountsResponse {
+    pub accounts: Vec<AccountBalance>,
+    // Set if there may be more accounts, pass it as `start_after` to get the next page
+    pub next_cursor: Option<Account>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ListBallotsArgs {
+    pub proposal_id: u64,
+    // Only return ballots cast after the ballot of this voter, i.e. the `next_cursor` of
+    // the previous page
+    pub start_after: Option<Principal>,
+    pub limit: Option<u32>,
+}
+
+/// A page of the ballots of a proposal, in the order they were cast
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ListBallotsResponse {
+    pub ballots: Vec<Ballot>,
+    // Set if there may be more ballots, pass it as `start_after` to get the next page
+    pub next_cursor: Option<Principal>,
+}
+
 #[derive(Clone, Default, Debug, CandidType, Deserialize)]
 pub struct SystemParams {
     // The fee incurred by transferring tokens
@@ -127,11 +626,73 @@ pub struct SystemParams {
     // a user that submits a proposal. If the proposal is Accepted, this deposit is returned,
     // otherwise it is lost. This prevents users from submitting superfluous proposals.
     pub proposal_submission_deposit: Tokens,
+
+    // The amount of time (in nanoseconds) a proposal is open for voting. A proposal that
+    // has not been accepted or rejected by the end of this period is closed automatically.
+    pub voting_period_ns: u64,
+
+    // The minimum amount of tokens that must have voted on a proposal for it to be
+    // accepted or rejected when its voting period ends. Otherwise the proposal expires.
+    pub quorum: Tokens,
+
+    // The minimum remaining dissolve delay (in nanoseconds) a stake needs to have any voting power
+    pub min_dissolve_delay_ns: u64,
+
+    // The remaining dissolve delay (in nanoseconds) at which a stake has twice the voting
+    // power of its amount. Stakes with a shorter delay get a proportionally smaller bonus.
+    pub max_dissolve_delay_ns: u64,
+
+    // The amount of time (in nanoseconds) between a proposal being accepted and executed
+    pub execution_delay_ns: u64,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Default, Debug, CandidType, Deserialize)]
 pub struct UpdateSystemParamsPayload {
     pub transfer_fee: Option<Tokens>,
     pub proposal_vote_threshold: Option<Tokens>,
     pub proposal_submission_deposit: Option<Tokens>,
+    pub voting_period_ns: Option<u64>,
+    pub quorum: Option<Tokens>,
+    pub min_dissolve_delay_ns: Option<u64>,
+    pub max_dissolve_delay_ns: Option<u64>,
+    pub execution_delay_ns: Option<u64>,
+}
+
+impl UpdateSystemParamsPayload {
+    /// Return true if the payload does not update any system param
+    pub fn is_empty(&self) -> bool {
+        self.transfer_fee.is_none()
+            && self.proposal_vote_threshold.is_none()
+            && self.proposal_submission_deposit.is_none()
+            && self.voting_period_ns.is_none()
+            && self.quorum.is_none()
+            && self.min_dissolve_delay_ns.is_none()
+            && self.max_dissolve_delay_ns.is_none()
+            && self.execution_delay_ns.is_none()
+    }
+}
+
+impl fmt::Display for UpdateSystemParamsPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tokens = |name: &str, value: Option<Tokens>| {
+            value.map(|tokens| format!("{} = {} e8s", name, tokens.amount_e8s))
+        };
+        let nanos = |name: &str, value: Option<u64>| value.map(|ns| format!("{} = {} ns", name, ns));
+
+        let changes: Vec<String> = vec![
+            tokens("transfer_fee", self.transfer_fee),
+            tokens("proposal_vote_threshold", self.proposal_vote_threshold),
+            tokens("proposal_submission_deposit", self.proposal_submission_deposit),
+            nanos("voting_period_ns", self.voting_period_ns),
+            tokens("quorum", self.quorum),
+            nanos("min_dissolve_delay_ns", self.min_dissolve_delay_ns),
+            nanos("max_dissolve_delay_ns", self.max_dissolve_delay_ns),
+            nanos("execution_delay_ns", self.execution_delay_ns),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        write!(f, "{}", changes.join(", "))
+    }
 }
@@ -0,0 +1,28 @@
+use crate::env::CanisterEnvironment;
+use crate::service::BasicDaoService;
+use crate::timers;
+use crate::types::BasicDaoStableStorage;
+use crate::SERVICE;
+use ic_cdk_macros::{post_upgrade, pre_upgrade};
+
+/// Save the DAO state to stable memory before the canister is upgraded
+#[pre_upgrade]
+fn pre_upgrade() {
+    let stable_state = SERVICE.with(|service| BasicDaoStableStorage::from(&*service.borrow()));
+    ic_cdk::storage::stable_save((stable_state,)).expect("failed to save stable state");
+}
+
+/// Restore the DAO state from stable memory after the canister is upgraded
+#[post_upgrade]
+fn post_upgrade() {
+    ic_cdk::setup();
+
+    let (stable_state,): (BasicDaoStableStorage,) =
+        ic_cdk::storage::stable_restore().expect("failed to restore stable state");
+
+    let mut upgraded_service = BasicDaoService::from(stable_state);
+    upgraded_service.env = Box::new(CanisterEnvironment {});
+
+    SERVICE.with(|service| *service.borrow_mut() = upgraded_service);
+    timers::schedule_all();
+}
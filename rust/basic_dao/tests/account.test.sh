@@ -11,10 +11,20 @@ let args = encode fake.__init_args(
   record {
     accounts = vec { record { owner = alice; tokens = record { amount_e8s = 1_000_000_000_000 } } };
     proposals = vec {};
+    next_proposal_id = 0;
+    delegations = vec {};
+    stakes = vec {};
+    approvals = vec {};
+    transactions = vec {};
     system_params = record {
       transfer_fee = record { amount_e8s = 10_000 };
       proposal_vote_threshold = record { amount_e8s = 1_000_000_000 };
       proposal_submission_deposit = record { amount_e8s = 10_000 };
+      voting_period_ns = 86_400_000_000_000;
+      quorum = record { amount_e8s = 0 };
+      min_dissolve_delay_ns = 0;
+      max_dissolve_delay_ns = 0;
+      execution_delay_ns = 0;
     };
   }
 );
@@ -74,3 +84,28 @@ call DAO.transfer(
 );
 call DAO.account_balance();
 assert _.amount_e8s == (499_999_990_000 : nat64);
+
+// ICRC-1 transfer to a subaccount
+let savings = record { owner = bob; subaccount = opt blob "01234567890123456789012345678901" };
+call DAO.icrc1_fee();
+assert _ == (10_000 : nat);
+call DAO.icrc1_transfer(record { to = savings; amount = 100_000 });
+assert _ == variant { Ok = 2 : nat };
+call DAO.icrc1_balance_of(savings);
+assert _ == (100_000 : nat);
+call DAO.icrc1_balance_of(record { owner = bob });
+assert _ == (499_999_880_000 : nat);
+call DAO.icrc1_transfer(record { to = savings; amount = 100_000; fee = opt (1 : nat) });
+assert _ == variant { Err = variant { BadFee = record { expected_fee = 10_000 : nat } } };
+
+// ICRC-2 approval
+call DAO.icrc2_approve(record { spender = record { owner = alice }; amount = 50_000 });
+assert _ == variant { Ok = 3 : nat };
+identity alice;
+// the fee is deducted from the allowance as well
+call DAO.icrc2_transfer_from(record { from = record { owner = bob }; to = record { owner = alice }; amount = 40_001 });
+assert _ == variant { Err = variant { InsufficientAllowance = record { allowance = 50_000 : nat } } };
+call DAO.icrc2_transfer_from(record { from = record { owner = bob }; to = record { owner = alice }; amount = 40_000 });
+assert _ == variant { Ok = 4 : nat };
+call DAO.icrc2_allowance(record { account = record { owner = bob }; spender = record { owner = alice } });
+assert _.allowance == (0 : nat);
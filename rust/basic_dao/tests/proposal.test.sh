@@ -18,10 +18,20 @@ let init = encode fake.__init_args(
   record {
     accounts = vec { record { owner = genesis; tokens = record { amount_e8s = 1_000_000_000_000 } } };
     proposals = vec {};
+    next_proposal_id = 0;
+    delegations = vec {};
+    stakes = vec {};
+    approvals = vec {};
+    transactions = vec {};
     system_params = record {
       transfer_fee = record { amount_e8s = 0 };
       proposal_vote_threshold = record { amount_e8s = 500 };
       proposal_submission_deposit = record { amount_e8s = 100 };
+      voting_period_ns = 86_400_000_000_000;
+      quorum = record { amount_e8s = 0 };
+      min_dissolve_delay_ns = 0;
+      max_dissolve_delay_ns = 0;
+      execution_delay_ns = 0;
     };
   }
 );
@@ -35,34 +45,41 @@ assert _.transfer_fee.amount_e8s == (0 : nat64);
 
 // distribute tokens
 let _ = call DAO.transfer(record { to = alice; amount = record { amount_e8s = 100 } });
-let _ = call DAO.transfer(record { to = bob; amount = record { amount_e8s = 200 } });
+let _ = call DAO.transfer(record { to = bob; amount = record { amount_e8s = 400 } });
 let _ = call DAO.transfer(record { to = cathy; amount = record { amount_e8s = 300 } });
 let _ = call DAO.transfer(record { to = dory; amount = record { amount_e8s = 400 } });
 call DAO.account_balance();
 // verify no transfer fee
-assert _.amount_e8s == (999_999_999_000 : nat64);
+assert _.amount_e8s == (999_999_998_800 : nat64);
+
+// stake tokens to get voting power
+identity bob;
+call DAO.stake(record { amount = record { amount_e8s = 200 }; dissolve_delay_ns = 0 });
+assert _.Ok ~= record { owner = bob; amount = record { amount_e8s = 200 : nat64 } };
+call DAO.stake(record { amount = record { amount_e8s = 1_000 }; dissolve_delay_ns = 0 });
+assert _.Err ~= "insufficient funds to stake";
+identity cathy;
+let _ = call DAO.stake(record { amount = record { amount_e8s = 300 }; dissolve_delay_ns = 0 });
+identity dory;
+let _ = call DAO.stake(record { amount = record { amount_e8s = 400 }; dissolve_delay_ns = 0 });
 
 // alice makes a proposal
 identity alice;
 call DAO.account_balance();
 assert _.amount_e8s == (100 : nat64);
-call DAO.submit_proposal(
-  record {
-    canister_id = DAO;
-    method = "update_system_params";
-    message = encode DAO.update_system_params(update_transfer_fee);
-  },
-);
+call DAO.submit_proposal(variant { Motion = record { "text" = "" } });
+assert _.Err ~= "The motion text must not be empty";
+call DAO.submit_proposal(variant { UpdateSystemParams = update_transfer_fee });
 let alice_id = _.Ok;
 call DAO.account_balance();
 assert _.amount_e8s == (0 : nat64);
 
 // voting
 call DAO.vote(record { proposal_id = alice_id; vote = variant { Yes } });
-assert _.Ok == variant { Open };
+assert _.Err ~= "Caller does not have any staked tokens to vote with";
 identity eve;
 call DAO.vote(record { proposal_id = alice_id; vote = variant { Yes } });
-assert _.Err ~= "Caller does not have any tokens to vote with";
+assert _.Err ~= "Caller does not have any staked tokens to vote with";
 identity bob;
 call DAO.get_proposal(alice_id);
 assert _? ~= record {
@@ -71,10 +88,8 @@ assert _? ~= record {
   votes_yes = record { amount_e8s = 0 : nat64 };
   votes_no = record { amount_e8s = 0 : nat64 };
   state = variant { Open };
-  payload = record {
-    canister_id = DAO;
-    method = "update_system_params";
-  };
+  payload = variant { UpdateSystemParams = update_transfer_fee };
+  summary = "Update system params: transfer_fee = 10000 e8s";
 };
 call DAO.vote(record { proposal_id = alice_id; vote = variant { Yes } });
 assert _.Ok == variant { Open };
@@ -83,6 +98,8 @@ assert _.Err ~= "Already voted";
 identity dory;
 call DAO.vote(record { proposal_id = alice_id; vote = variant { No } });
 assert _.Ok == variant { Open };
+call DAO.start_dissolving();
+assert _.Err ~= "frozen";
 identity cathy;
 call DAO.vote(record { proposal_id = alice_id; vote = variant { Yes } });
 assert _.Ok == variant { Accepted };
@@ -101,6 +118,15 @@ assert _? ~= record {
   votes_no = record { amount_e8s = 400 : nat64 };
 };
 
+// voters are listed with their vote and voting power
+call DAO.list_ballots(record { proposal_id = alice_id; limit = opt (2 : nat32) });
+assert _.Ok.ballots[0] == record { voter = bob; vote = variant { Yes }; voting_power = record { amount_e8s = 200 : nat64 } };
+assert _.Ok.ballots[1] == record { voter = dory; vote = variant { No }; voting_power = record { amount_e8s = 400 : nat64 } };
+assert _.Ok.next_cursor == opt dory;
+call DAO.list_ballots(record { proposal_id = alice_id; start_after = opt dory });
+assert _.Ok.ballots[0].voter == cathy;
+assert _.Ok.next_cursor == (null : opt principal);
+
 // check proposal is executed
 call DAO.get_system_params();
 assert _.transfer_fee.amount_e8s == (10_000 : nat64);
@@ -108,27 +134,29 @@ assert _.transfer_fee.amount_e8s == (10_000 : nat64);
 // bob makes proposals
 identity bob;
 call DAO.submit_proposal(
-  record {
-    canister_id = DAO;
-    method = "transfer2";
-    message = encode DAO.transfer(record { to = alice; amount = record { amount_e8s = 100 } });
+  variant {
+    CallCanister = record {
+      canister_id = DAO;
+      method = "transfer2";
+      candid_args_text = "(record { to = principal \"aaaaa-aa\"; amount = record { amount_e8s = 100 : nat64 } })";
+    }
   },
 );
 let bob1 = _.Ok;
 call DAO.submit_proposal(
-  record {
-    canister_id = DAO;
-    method = "transfer2";
-    message = encode DAO.transfer(record { to = alice; amount = record { amount_e8s = 100 } });
+  variant {
+    CallCanister = record {
+      canister_id = DAO;
+      method = "transfer";
+      candid_args_text = "(record { to = ";
+    }
   },
 );
+assert _.Err ~= "Invalid Candid arguments";
+call DAO.submit_proposal(variant { Motion = record { "text" = "Bob should get a refund" } });
 let bob2 = _.Ok;
 call DAO.submit_proposal(
-  record {
-    canister_id = DAO;
-    method = "transfer";
-    message = encode DAO.transfer(record { to = alice; amount = record { amount_e8s = 100 } });
-  },
+  variant { TransferTreasury = record { to = alice; amount = record { amount_e8s = 100 } } },
 );
 assert _.Err ~= "Caller's account must have at least";
 
@@ -146,3 +174,18 @@ assert _.Ok == variant { Accepted };
 identity bob;
 call DAO.account_balance();
 assert _.amount_e8s == (100 : nat64);
+
+// state is preserved across upgrades
+upgrade(DAO, wasm, init);
+call DAO.account_balance();
+assert _.amount_e8s == (100 : nat64);
+call DAO.get_proposal(bob2);
+assert _? ~= record { id = bob2; proposer = bob; state = variant { Succeeded } };
+call DAO.list_proposals(record { proposer = opt bob; state = opt variant { Rejected } });
+assert _.proposals[0].id == bob1;
+call DAO.get_system_params();
+assert _.transfer_fee.amount_e8s == (10_000 : nat64);
+call DAO.submit_proposal(
+  variant { TransferTreasury = record { to = alice; amount = record { amount_e8s = 10 } } },
+);
+assert _.Ok == (3 : nat64);
@@ -6,6 +6,7 @@ dfx stop && dfx start --background --clean --host 127.0.0.1:8000
 dfx identity new minter --disable-encryption || true
 dfx identity use minter
 export MINT_ACC=$(dfx ledger account-id)
+export MINT_PRINCIPAL="principal \"$(dfx identity get-principal)\""
 
 dfx identity use default
 export LEDGER_ACC=$(dfx ledger account-id)
@@ -42,6 +43,22 @@ dfx canister call AkitaDIP20 setFee "(420)"
 dfx canister call GoldenDIP20 setFeeTo "($ROOT_PRINCIPAL)"
 dfx canister call GoldenDIP20 setFee "(420)" 
 
+### === DEPLOY ICRC-1 TOKEN =====
+
+dfx deploy icrc1_ledger --argument "(variant { Init = record {
+    token_symbol = \"ICRC\";
+    token_name = \"ICRC Coin\";
+    minting_account = record { owner = $MINT_PRINCIPAL };
+    transfer_fee = 10_000;
+    metadata = vec {};
+    initial_balances = vec { record { record { owner = $ROOT_PRINCIPAL }; 100_000_000_000 } };
+    archive_options = record {
+        num_blocks_to_archive = 1000;
+        trigger_threshold = 2000;
+        controller_id = $ROOT_PRINCIPAL;
+    };
+}})"
+
 ### === DEPLOY INTERNET IDENTITY =====
 
 II_FETCH_ROOT_KEY=1 dfx deploy internet_identity --no-wallet --argument '(null)'
@@ -1,5 +1,7 @@
 use candid::{CandidType, Deserialize, Nat, Principal};
 
+use crate::token::{TokenErr, TokenLedger, TransferKey};
+
 pub struct DIP20 {
     principal: Principal,
 }
@@ -30,16 +32,22 @@ pub struct Metadata {
     pub fee: Nat,
 }
 
+impl From<TxError> for TokenErr {
+    fn from(error: TxError) -> Self {
+        TokenErr::Rejected(format!("{:?}", error))
+    }
+}
+
 impl DIP20 {
     pub fn new(principal: Principal) -> Self {
         DIP20 { principal }
     }
 
-    pub async fn transfer(&self, target: Principal, amount: Nat) -> TxReceipt {
-        let call_result: Result<(TxReceipt,), _> =
-            ic_cdk::api::call::call(self.principal, "transfer", (target, amount)).await;
+    pub async fn transfer(&self, target: Principal, amount: Nat) -> Result<Nat, TokenErr> {
+        let (receipt,): (TxReceipt,) =
+            ic_cdk::api::call::call(self.principal, "transfer", (target, amount)).await?;
 
-        call_result.unwrap().0
+        Ok(receipt?)
     }
 
     pub async fn transfer_from(
@@ -47,24 +55,62 @@ impl DIP20 {
         source: Principal,
         target: Principal,
         amount: Nat,
-    ) -> TxReceipt {
-        let call_result: Result<(TxReceipt,), _> =
-            ic_cdk::api::call::call(self.principal, "transferFrom", (source, target, amount)).await;
+    ) -> Result<Nat, TokenErr> {
+        let (receipt,): (TxReceipt,) =
+            ic_cdk::api::call::call(self.principal, "transferFrom", (source, target, amount))
+                .await?;
 
-        call_result.unwrap().0
+        Ok(receipt?)
     }
 
-    pub async fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
-        let call_result: Result<(Nat,), _> =
-            ic_cdk::api::call::call(self.principal, "allowance", (owner, spender)).await;
+    pub async fn allowance(&self, owner: Principal, spender: Principal) -> Result<Nat, TokenErr> {
+        let (allowance,): (Nat,) =
+            ic_cdk::api::call::call(self.principal, "allowance", (owner, spender)).await?;
 
-        call_result.unwrap().0
+        Ok(allowance)
     }
 
-    pub async fn get_metadata(&self) -> Metadata {
-        let call_result: Result<(Metadata,), _> =
-            ic_cdk::api::call::call(self.principal, "getMetadata", ()).await;
+    pub async fn get_metadata(&self) -> Result<Metadata, TokenErr> {
+        let (metadata,): (Metadata,) =
+            ic_cdk::api::call::call(self.principal, "getMetadata", ()).await?;
+
+        Ok(metadata)
+    }
+}
+
+// DIP20 has no deduplication, so a transfer must never be sent twice.
+impl TokenLedger for DIP20 {
+    async fn symbol(&self) -> Result<String, TokenErr> {
+        Ok(self.get_metadata().await?.symbol)
+    }
+
+    async fn fee(&self) -> Result<Nat, TokenErr> {
+        Ok(self.get_metadata().await?.fee)
+    }
 
-        call_result.unwrap().0
+    fn deduplicates(&self) -> bool {
+        false
+    }
+
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: Nat,
+        _key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        DIP20::transfer(self, to, amount).await
+    }
+
+    async fn allowance(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        DIP20::allowance(self, owner, ic_cdk::api::id()).await
+    }
+
+    async fn transfer_from(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        _key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        DIP20::transfer_from(self, owner, ic_cdk::api::id(), amount).await
     }
 }
@@ -2,30 +2,96 @@ use std::collections::HashMap;
 
 use candid::{Nat, Principal};
 use ic_cdk::caller;
+use ic_stable_structures::StableBTreeMap;
 
+use crate::history::{self, TradeHistory};
+use crate::memory::{self, Memory, PrincipalKey, StableNat};
+use crate::order_book::{pair, pair_of, price_of, OrderBook};
+use crate::pool::{Pool, Pools, DEFAULT_POOL_FEE_BPS};
 use crate::types::*;
-use crate::{utils, OrderId};
-
-#[derive(Default)]
-pub struct Balances(pub HashMap<Principal, HashMap<Principal, Nat>>); // owner -> token_canister_id -> amount
-type Orders = HashMap<OrderId, Order>;
+use crate::utils::{self, BPS};
+use crate::OrderId;
+
+// owner -> token_canister_id -> amount, kept in stable memory
+pub struct Balances(StableBTreeMap<(PrincipalKey, PrincipalKey), StableNat, Memory>);
+type Orders = StableBTreeMap<OrderId, Order, Memory>; // kept in stable memory
+type OrderBooks = HashMap<(Principal, Principal), OrderBook>; // (base, quote) -> open orders
+type Fees = HashMap<(Principal, Principal), TradingFees>; // (base, quote) -> trading fees
+
+// The trading fees are collected in the balances of the exchange canister itself, one
+// balance per token. Only the owner can withdraw them.
+pub fn fee_account() -> Principal {
+    ic_cdk::api::id()
+}
 
-#[derive(Default)]
 pub struct Exchange {
     pub next_id: OrderId,
     pub balances: Balances,
     pub orders: Orders,
+    pub books: OrderBooks,
+    pub history: TradeHistory,
+    pub pools: Pools,
+    pub fees: Fees,
+}
+
+impl Default for Balances {
+    fn default() -> Self {
+        Balances(StableBTreeMap::init(memory::get(memory::BALANCES)))
+    }
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Exchange {
+            next_id: 0,
+            balances: Balances::default(),
+            orders: StableBTreeMap::init(memory::get(memory::ORDERS)),
+            books: OrderBooks::default(),
+            history: TradeHistory::default(),
+            pools: Pools::default(),
+            fees: Fees::default(),
+        }
+    }
 }
 
 impl Balances {
-    pub fn add_balance(&mut self, owner: &Principal, token_canister_id: &Principal, delta: Nat) {
-        let balances = self.0.entry(*owner).or_insert_with(HashMap::new);
+    pub fn get(&self, owner: &Principal, token_canister_id: &Principal) -> Nat {
+        self.0
+            .get(&(PrincipalKey(*owner), PrincipalKey(*token_canister_id)))
+            .map_or(utils::zero(), |v| v.0)
+    }
 
-        if let Some(x) = balances.get_mut(token_canister_id) {
-            *x += delta;
-        } else {
-            balances.insert(*token_canister_id, delta);
+    // Returns the balances of an owner as (token_canister_id, amount).
+    pub fn of(&self, owner: &Principal) -> Vec<(Principal, Nat)> {
+        self.0
+            .range((PrincipalKey(*owner), PrincipalKey::MIN)..)
+            .take_while(|((o, _), _)| o.0 == *owner)
+            .map(|((_, token), amount)| (token.0, amount.0))
+            .collect()
+    }
+
+    // Returns all balances as (owner, token_canister_id, amount).
+    pub fn all(&self) -> Vec<(Principal, Principal, Nat)> {
+        self.0
+            .iter()
+            .map(|((owner, token), amount)| (owner.0, token.0, amount.0))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear_new();
+    }
+
+    pub fn add_balance(&mut self, owner: &Principal, token_canister_id: &Principal, delta: Nat) {
+        // no need to keep an empty token record
+        if delta == utils::zero() {
+            return;
         }
+        let balance = self.get(owner, token_canister_id);
+        self.0.insert(
+            (PrincipalKey(*owner), PrincipalKey(*token_canister_id)),
+            StableNat(balance + delta),
+        );
     }
 
     // Tries to substract balance from user account. Checks for overflows
@@ -35,68 +101,136 @@ impl Balances {
         token_canister_id: &Principal,
         delta: Nat,
     ) -> bool {
-        if let Some(balances) = self.0.get_mut(owner) {
-            if let Some(x) = balances.get_mut(token_canister_id) {
-                if *x >= delta {
-                    *x -= delta;
-                    // no need to keep an empty token record
-                    if *x == utils::zero() {
-                        balances.remove(token_canister_id);
-                    }
-                    return true;
+        let key = (PrincipalKey(*owner), PrincipalKey(*token_canister_id));
+        match self.0.get(&key) {
+            Some(StableNat(x)) if x >= delta => {
+                // no need to keep an empty token record
+                if x == delta {
+                    self.0.remove(&key);
                 } else {
-                    return false;
+                    self.0.insert(key, StableNat(x - delta));
                 }
+                true
             }
+            _ => false,
         }
-
-        false
     }
 }
 
 impl Exchange {
     pub fn get_balance(&self, token_canister_id: Principal) -> Nat {
-        self.balances
-            .0
-            .get(&caller())
-            .and_then(|v| v.get(&token_canister_id))
-            .map_or(utils::zero(), |v| v.to_owned())
+        self.balances.get(&caller(), &token_canister_id)
     }
 
     pub fn get_balances(&self) -> Vec<Balance> {
-        match self.balances.0.get(&caller()) {
-            None => Vec::new(),
-            Some(v) => v
-                .iter()
-                .map(|(token_canister_id, amount)| Balance {
-                    owner: caller(),
-                    token: *token_canister_id,
-                    amount: amount.to_owned(),
-                })
-                .collect(),
-        }
+        self.balances
+            .of(&caller())
+            .into_iter()
+            .map(|(token_canister_id, amount)| Balance {
+                owner: caller(),
+                token: token_canister_id,
+                amount,
+            })
+            .collect()
     }
 
     pub fn get_all_balances(&self) -> Vec<Balance> {
         self.balances
-            .0
-            .iter()
-            .flat_map(|(owner, balances)| {
-                balances.iter().map(move |(token, amount)| Balance {
-                    owner: *owner,
-                    token: *token,
-                    amount: amount.to_owned(),
-                })
+            .all()
+            .into_iter()
+            .map(|(owner, token, amount)| Balance {
+                owner,
+                token,
+                amount,
             })
             .collect()
     }
 
     pub fn get_order(&self, order: OrderId) -> Option<Order> {
-        self.orders.get(&order).cloned()
+        self.orders.get(&order)
     }
 
     pub fn get_all_orders(&self) -> Vec<Order> {
-        self.orders.iter().map(|(_, o)| o.clone()).collect()
+        self.orders.values().collect()
+    }
+
+    pub fn get_order_book(&self, token_a: Principal, token_b: Principal) -> OrderBookSnapshot {
+        let (base, quote) = pair(token_a, token_b);
+        let orders = |ids: &mut dyn Iterator<Item = OrderId>| -> Vec<Order> {
+            ids.map(|id| self.orders.get(&id).unwrap()).collect()
+        };
+
+        match self.books.get(&(base, quote)) {
+            None => OrderBookSnapshot {
+                base,
+                quote,
+                asks: Vec::new(),
+                bids: Vec::new(),
+            },
+            Some(book) => OrderBookSnapshot {
+                base,
+                quote,
+                asks: orders(&mut book.asks()),
+                bids: orders(&mut book.bids()),
+            },
+        }
+    }
+
+    pub fn get_trade_history(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        before: Option<FillId>,
+        limit: Option<u32>,
+    ) -> Vec<Fill> {
+        let (base, quote) = pair(token_a, token_b);
+        self.history.trades(base, quote, before, limit)
+    }
+
+    pub fn get_fills(
+        &self,
+        user: Principal,
+        before: Option<FillId>,
+        limit: Option<u32>,
+    ) -> Vec<Fill> {
+        self.history.fills_of(user, before, limit)
+    }
+
+    pub fn get_candles(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        interval: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Vec<Candle> {
+        let (base, quote) = pair(token_a, token_b);
+        self.history.candles(base, quote, interval, from, to)
+    }
+
+    // Returns the trading fees of a token pair. Pairs without fees set trade for free.
+    pub fn get_trading_fees(&self, token_a: Principal, token_b: Principal) -> TradingFees {
+        self.fees
+            .get(&pair(token_a, token_b))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_trading_fees(&mut self, token_a: Principal, token_b: Principal, fees: TradingFees) {
+        self.fees.insert(pair(token_a, token_b), fees);
+    }
+
+    // Returns the collected trading fees, see `fee_account`.
+    pub fn get_collected_fees(&self) -> Vec<Balance> {
+        self.balances
+            .of(&fee_account())
+            .into_iter()
+            .map(|(token_canister_id, amount)| Balance {
+                owner: fee_account(),
+                token: token_canister_id,
+                amount,
+            })
+            .collect()
     }
 
     pub fn place_order(
@@ -107,49 +241,57 @@ impl Exchange {
         to_amount: Nat,
     ) -> OrderPlacementReceipt {
         ic_cdk::println!("place order");
-        if from_amount <= utils::zero() || to_amount <= utils::zero() {
-            return OrderPlacementReceipt::Err(OrderPlacementErr::InvalidOrder);
-        }
-
-        if self.check_for_sell_orders(from_token_canister_id) {
+        if from_amount <= utils::zero()
+            || to_amount <= utils::zero()
+            || from_token_canister_id == to_token_canister_id
+        {
             return OrderPlacementReceipt::Err(OrderPlacementErr::InvalidOrder);
         }
 
+        // Tokens offered by other open orders of the caller are not available.
         let balance = self.get_balance(from_token_canister_id);
-        if balance < from_amount {
+        let reserved = self.reserved_balance(&caller(), &from_token_canister_id);
+        if balance < reserved + from_amount.to_owned() {
             return OrderPlacementReceipt::Err(OrderPlacementErr::InvalidOrder);
         }
         let id = self.next_id();
-        self.orders.insert(
+        self.insert_order(Order {
             id,
-            Order {
-                id,
-                owner: caller(),
-                from: from_token_canister_id,
-                fromAmount: from_amount,
-                to: to_token_canister_id,
-                toAmount: to_amount,
-            },
-        );
+            owner: caller(),
+            from: from_token_canister_id,
+            fromAmount: from_amount,
+            to: to_token_canister_id,
+            toAmount: to_amount,
+            fee: Some(utils::zero()),
+        });
         self.resolve_order(id)?;
 
-        if let Some(o) = self.orders.get(&id) {
-            OrderPlacementReceipt::Ok(Some(o.clone()))
-        } else {
-            OrderPlacementReceipt::Ok(None)
-        }
+        OrderPlacementReceipt::Ok(self.orders.get(&id))
     }
 
-    pub fn check_for_sell_orders(&self, from_token_canister_id: Principal) -> bool {
+    // Returns the amount of a token the owner offers in open orders.
+    pub fn reserved_balance(&self, owner: &Principal, token_canister_id: &Principal) -> Nat {
         self.orders
             .values()
-            .any(|v| (v.from == from_token_canister_id) && (v.owner == caller()))
+            .filter(|o| o.owner == *owner && o.from == *token_canister_id)
+            .fold(utils::zero(), |sum, o| sum + o.fromAmount.to_owned())
+    }
+
+    // Returns the balance of a token the owner does not offer in open orders.
+    pub fn available_balance(&self, owner: &Principal, token_canister_id: &Principal) -> Nat {
+        let balance = self.balances.get(owner, token_canister_id);
+        let reserved = self.reserved_balance(owner, token_canister_id);
+        if balance <= reserved {
+            return utils::zero();
+        }
+
+        balance - reserved
     }
 
     pub fn cancel_order(&mut self, order: OrderId) -> CancelOrderReceipt {
         if let Some(o) = self.orders.get(&order) {
             if o.owner == caller() {
-                self.orders.remove(&order);
+                self.remove_order(order);
                 CancelOrderReceipt::Ok(order)
             } else {
                 CancelOrderReceipt::Err(CancelOrderErr::NotAllowed)
@@ -159,150 +301,479 @@ impl Exchange {
         }
     }
 
-    fn resolve_order(&mut self, id: OrderId) -> Result<(), OrderPlacementErr> {
-        ic_cdk::println!("resolve order");
-        let mut matches = Vec::new();
-        let a = self.orders.get(&id).unwrap();
-        for (order, b) in self.orders.iter() {
-            if *order == id {
-                continue;
-            }
+    pub fn remove_orders_of(&mut self, owner: &Principal) {
+        let ids: Vec<OrderId> = self
+            .orders
+            .values()
+            .filter(|o| o.owner == *owner)
+            .map(|o| o.id)
+            .collect();
+        for id in ids {
+            self.remove_order(id);
+        }
+    }
 
-            if a.from == b.to && a.to == b.from {
-                // Simplified to use multiplication from
-                // (a.fromAmount / a.toAmount) * (b.fromAmount / b.toAmount) >= 1
-                // which checks that this pair of trades is profitable.
-                if a.fromAmount.to_owned() * b.fromAmount.to_owned()
-                    >= a.toAmount.to_owned() * b.toAmount.to_owned()
-                {
-                    ic_cdk::println!(
-                        "match {}: {} -> {}, {}: {} -> {}",
-                        id,
-                        a.fromAmount,
-                        a.toAmount,
-                        *order,
-                        b.fromAmount,
-                        b.toAmount
-                    );
-                    matches.push((a.to_owned(), b.to_owned()));
-                }
-            }
+    pub fn clear_orders(&mut self) {
+        self.orders.clear_new();
+        self.books.clear();
+    }
+
+    pub fn get_pool(&self, token_a: Principal, token_b: Principal) -> Option<PoolSnapshot> {
+        let (base, quote) = pair(token_a, token_b);
+        self.pools.get(base, quote).as_ref().map(Pool::snapshot)
+    }
+
+    pub fn get_pool_shares(&self, token_a: Principal, token_b: Principal) -> Nat {
+        let (base, quote) = pair(token_a, token_b);
+        self.pools.shares_of(base, quote, &caller())
+    }
+
+    // Sets the fee of the pool of a token pair, creating the pool if it does not exist yet.
+    pub fn set_pool_fee(
+        &mut self,
+        token_a: Principal,
+        token_b: Principal,
+        fee_bps: u32,
+    ) -> PoolFeeReceipt {
+        if token_a == token_b || fee_bps >= BPS {
+            return Err(PoolErr::InvalidAmount);
         }
-        for m in matches {
-            let mut a_to_amount: Nat = utils::zero();
-            let mut b_to_amount: Nat = utils::zero();
-            let (a, b) = m;
-            // Check if some orders can be completed in their entirety.
-            if b.fromAmount >= a.toAmount {
-                a_to_amount = a.toAmount.to_owned();
-            }
-            if a.fromAmount >= b.toAmount {
-                b_to_amount = b.toAmount.to_owned();
+
+        let (base, quote) = pair(token_a, token_b);
+        let mut pool = self
+            .pools
+            .get(base, quote)
+            .unwrap_or_else(|| Pool::new(base, quote, fee_bps));
+        pool.fee_bps = fee_bps;
+        self.pools.insert(pool);
+        Ok(())
+    }
+
+    // Adds at most `amount_a` and `amount_b` to the pool of the pair, see
+    // `Pool::liquidity_for`, and returns the caller's new shares.
+    pub fn add_liquidity(
+        &mut self,
+        token_a: Principal,
+        amount_a: Nat,
+        token_b: Principal,
+        amount_b: Nat,
+    ) -> AddLiquidityReceipt {
+        if token_a == token_b {
+            return Err(PoolErr::InvalidAmount);
+        }
+
+        let (base, quote) = pair(token_a, token_b);
+        let (max_base, max_quote) = if token_a == base {
+            (amount_a, amount_b)
+        } else {
+            (amount_b, amount_a)
+        };
+        let available_base = self.available_balance(&caller(), &base);
+        let available_quote = self.available_balance(&caller(), &quote);
+
+        let pool = self
+            .pools
+            .get(base, quote)
+            .unwrap_or_else(|| Pool::new(base, quote, DEFAULT_POOL_FEE_BPS));
+        let (shares, base_amount, quote_amount) = pool
+            .liquidity_for(max_base, max_quote)
+            .ok_or(PoolErr::InvalidAmount)?;
+        if base_amount > available_base || quote_amount > available_quote {
+            return Err(PoolErr::BalanceLow);
+        }
+
+        self.balances
+            .subtract_balance(&caller(), &base, base_amount.to_owned());
+        self.balances
+            .subtract_balance(&caller(), &quote, quote_amount.to_owned());
+        self.pools
+            .add_liquidity(pool, caller(), shares.to_owned(), base_amount, quote_amount);
+
+        Ok(shares)
+    }
+
+    // Burns `shares` of the caller and credits the tokens they are worth to the caller.
+    pub fn remove_liquidity(
+        &mut self,
+        token_a: Principal,
+        token_b: Principal,
+        shares: Nat,
+    ) -> RemoveLiquidityReceipt {
+        let (base, quote) = pair(token_a, token_b);
+        let (base_amount, quote_amount) =
+            self.pools.remove_liquidity(base, quote, caller(), shares)?;
+
+        self.balances
+            .add_balance(&caller(), &base, base_amount.to_owned());
+        self.balances
+            .add_balance(&caller(), &quote, quote_amount.to_owned());
+
+        Ok(PoolAmounts {
+            base: base_amount,
+            quote: quote_amount,
+        })
+    }
+
+    // Returns how much of `to` a swap of `amount` of `from` would pay out now.
+    pub fn get_swap_quote(&self, from: Principal, amount: Nat, to: Principal) -> SwapReceipt {
+        if from == to || amount == utils::zero() {
+            return Err(PoolErr::InvalidAmount);
+        }
+
+        let (base, quote) = pair(from, to);
+        let pool = self
+            .pools
+            .get(base, quote)
+            .ok_or(PoolErr::InsufficientLiquidity)?;
+        let amount_out = pool.swap_output(&from, amount);
+        if amount_out == utils::zero() {
+            return Err(PoolErr::InsufficientLiquidity);
+        }
+
+        Ok(amount_out)
+    }
+
+    // Swaps `amount` of `from` for `to` through the pool of the pair. Fails if the swap
+    // would pay out less than `min_amount_out`.
+    pub fn swap(
+        &mut self,
+        from: Principal,
+        amount: Nat,
+        to: Principal,
+        min_amount_out: Nat,
+    ) -> SwapReceipt {
+        let amount_out = self.get_swap_quote(from, amount.to_owned(), to)?;
+        if amount_out < min_amount_out {
+            return Err(PoolErr::SlippageExceeded);
+        }
+        if amount > self.available_balance(&caller(), &from) {
+            return Err(PoolErr::BalanceLow);
+        }
+
+        let (base, quote) = pair(from, to);
+        let mut pool = self.pools.get(base, quote).unwrap();
+        pool.swap(&from, amount.to_owned(), amount_out.to_owned());
+        self.pools.insert(pool);
+        self.balances.subtract_balance(&caller(), &from, amount);
+        self.balances
+            .add_balance(&caller(), &to, amount_out.to_owned());
+
+        Ok(amount_out)
+    }
+
+    pub fn clear_pools(&mut self) {
+        self.pools.clear();
+    }
+
+    pub fn insert_order(&mut self, order: Order) {
+        insert_into_book(&mut self.books, &order);
+        self.orders.insert(order.id, order);
+    }
+
+    // Rebuilds the order books, which are kept on the heap, from the orders in stable memory.
+    pub fn rebuild_books(&mut self) {
+        self.books.clear();
+        for (_, order) in self.orders.iter() {
+            insert_into_book(&mut self.books, &order);
+        }
+    }
+
+    fn remove_order(&mut self, id: OrderId) -> Option<Order> {
+        let order = self.orders.remove(&id)?;
+        let pair = pair_of(&order);
+        if let Some(book) = self.books.get_mut(&pair) {
+            book.remove(&order);
+            if book.is_empty() {
+                self.books.remove(&pair);
             }
-            // Check if some orders can be completed partially.
-            if check_orders(
-                a.to_owned(),
-                b.to_owned(),
-                &mut a_to_amount,
-                b_to_amount.to_owned(),
-            ) {
+        }
+        Some(order)
+    }
+
+    // Matches a new order against the open orders on the opposite side of its order book,
+    // best price first and, at the same price, oldest first. Every fill is executed at the
+    // price of the order that was already in the book.
+    fn resolve_order(&mut self, id: OrderId) -> Result<(), OrderPlacementErr> {
+        ic_cdk::println!("resolve order");
+        let taker = self.orders.get(&id).unwrap();
+        let counter_orders = self.books[&pair_of(&taker)].counter_orders(&taker);
+
+        for maker_id in counter_orders {
+            let taker = match self.orders.get(&id) {
+                Some(taker) => taker,
+                None => break,
+            };
+            let maker = self.orders.get(&maker_id).unwrap();
+            if maker.owner == taker.owner {
                 continue;
             }
-            if check_orders(
-                b.to_owned(),
-                a.to_owned(),
-                &mut b_to_amount,
-                a_to_amount.to_owned(),
-            ) {
-                continue;
+
+            // The maker's price must be at least as good as the taker's, i.e.
+            // (maker.fromAmount / maker.toAmount) >= (taker.toAmount / taker.fromAmount).
+            // Counter orders are sorted by price, so no later order can match either.
+            if maker.fromAmount.to_owned() * taker.fromAmount.to_owned()
+                < taker.toAmount.to_owned() * maker.toAmount.to_owned()
+            {
+                break;
             }
 
-            if a_to_amount > utils::zero() && b_to_amount > utils::zero() {
-                self.process_trade(a.id, b.id, a_to_amount, b_to_amount)?;
+            match fill_at_maker_price(&taker, &maker) {
+                Some((taker_amount, maker_amount)) => {
+                    self.process_trade(id, maker_id, taker_amount, maker_amount)?
+                }
+                None => break,
             }
         }
 
         Ok(())
     }
 
+    // Exchanges `taker_amount` of the taker's tokens for `maker_amount` of the maker's tokens
+    // and keeps the unfilled remainder of both orders in the book. Both sides pay the fee of
+    // their role out of the tokens they receive, see `TradingFees`.
     fn process_trade(
         &mut self,
-        a: OrderId,
-        b: OrderId,
-        a_to_amount: Nat,
-        b_to_amount: Nat,
+        taker: OrderId,
+        maker: OrderId,
+        taker_amount: Nat,
+        maker_amount: Nat,
     ) -> Result<(), OrderPlacementErr> {
-        ic_cdk::println!("process trade {} {} {} {}", a, b, a_to_amount, b_to_amount);
-
-        let Exchange {
-            orders, balances, ..
-        } = self;
-
-        let mut order_a = orders.remove(&a).unwrap();
-        let mut order_b = orders.remove(&b).unwrap();
-
-        // Calculate "cost" to each
-        let a_from_amount =
-            (a_to_amount.to_owned() * order_a.fromAmount.to_owned()) / order_a.toAmount.to_owned();
-        let b_from_amount =
-            (b_to_amount.to_owned() * order_b.fromAmount.to_owned()) / order_b.toAmount.to_owned();
-
-        // Update order with remaining tokens
-        order_a.fromAmount -= a_from_amount.to_owned();
-        order_a.toAmount -= a_to_amount.to_owned();
+        ic_cdk::println!(
+            "process trade {} {} {} {}",
+            taker,
+            maker,
+            taker_amount,
+            maker_amount
+        );
 
-        order_b.fromAmount -= b_from_amount.to_owned();
-        order_b.toAmount -= b_to_amount.to_owned();
+        let mut taker = self.remove_order(taker).unwrap();
+        let mut maker = self.remove_order(maker).unwrap();
+        let fees = self.get_trading_fees(taker.from, taker.to);
+        let taker_fee = fee_of(maker_amount.to_owned(), fees.taker_bps);
+        let maker_fee = fee_of(taker_amount.to_owned(), fees.maker_bps);
+        self.record_fill(
+            &taker,
+            &maker,
+            (taker_amount.to_owned(), maker_amount.to_owned()),
+            (taker_fee.to_owned(), maker_fee.to_owned()),
+        );
 
         // Update DEX balances
-        balances.subtract_balance(&order_a.owner, &order_a.from, a_from_amount.to_owned());
-        balances.add_balance(&order_a.owner, &order_a.to, a_to_amount.to_owned());
-
-        balances.subtract_balance(&order_b.owner, &order_b.from, b_from_amount.to_owned());
-        balances.add_balance(&order_b.owner, &order_b.to, b_to_amount.to_owned());
+        let balances = &mut self.balances;
+        balances.subtract_balance(&taker.owner, &taker.from, taker_amount.to_owned());
+        balances.add_balance(
+            &maker.owner,
+            &maker.to,
+            taker_amount.to_owned() - maker_fee.to_owned(),
+        );
 
-        // The DEX keeps any tokens not required to satisfy the parties.
-        let dex_amount_a = a_from_amount - b_to_amount;
-        if dex_amount_a > utils::zero() {
-            balances.add_balance(&ic_cdk::id(), &order_a.from, dex_amount_a);
-        }
+        balances.subtract_balance(&maker.owner, &maker.from, maker_amount.to_owned());
+        balances.add_balance(
+            &taker.owner,
+            &taker.to,
+            maker_amount.to_owned() - taker_fee.to_owned(),
+        );
 
-        let dex_amount_b = b_from_amount - a_to_amount;
-        if dex_amount_b > utils::zero() {
-            balances.add_balance(&ic_cdk::id(), &order_b.from, dex_amount_b);
-        }
+        // Collect the fees
+        balances.add_balance(&fee_account(), &taker.to, taker_fee.to_owned());
+        balances.add_balance(&fee_account(), &maker.to, maker_fee.to_owned());
+        add_fee(&mut taker, taker_fee);
+        add_fee(&mut maker, maker_fee);
 
-        // Maintain the order only if not empty
-        if order_a.fromAmount != utils::zero() {
-            orders.insert(order_a.id, order_a);
+        // Maintain the orders only if not empty
+        if let Some(order) = remaining_order(taker, taker_amount) {
+            self.insert_order(order);
         }
-
-        if order_b.fromAmount != utils::zero() {
-            orders.insert(order_b.id, order_b);
+        if let Some(order) = remaining_order(maker, maker_amount) {
+            self.insert_order(order);
         }
 
         Ok(())
     }
 
+    // Appends a trade to the history. The price is the maker's, as it was before the trade.
+    // The amounts and the fees are given as (taker's, maker's).
+    fn record_fill(
+        &mut self,
+        taker: &Order,
+        maker: &Order,
+        (taker_amount, maker_amount): (Nat, Nat),
+        (taker_fee, maker_fee): (Nat, Nat),
+    ) {
+        let (base, quote) = pair_of(taker);
+        let (base_amount, quote_amount) =
+            history::base_and_quote(base, taker.from, taker_amount, maker_amount);
+        let taker_side = if taker.from == base {
+            Side::Sell
+        } else {
+            Side::Buy
+        };
+
+        self.history.record(Fill {
+            id: self.history.next_id(),
+            timestamp: ic_cdk::api::time(),
+            base,
+            quote,
+            taker: taker.owner,
+            taker_order: taker.id,
+            taker_side,
+            maker: maker.owner,
+            maker_order: maker.id,
+            base_amount,
+            quote_amount,
+            price: price_of(maker),
+            maker_fee,
+            taker_fee,
+        });
+    }
+
     fn next_id(&mut self) -> OrderId {
         self.next_id += 1;
         self.next_id
     }
 }
 
-fn check_orders(
-    first: Order,
-    second: Order,
-    first_to_amount: &mut Nat,
-    second_to_amount: Nat,
-) -> bool {
-    if *first_to_amount == utils::zero() && second_to_amount > utils::zero() {
-        *first_to_amount = second.fromAmount;
-        // Verify that we can complete the partial order with natural number tokens remaining.
-        if ((first_to_amount.to_owned() * first.fromAmount) % first.toAmount) != utils::zero() {
-            return true;
+fn insert_into_book(books: &mut OrderBooks, order: &Order) {
+    let (base, quote) = pair_of(order);
+    books
+        .entry((base, quote))
+        .or_insert_with(|| OrderBook::new(base))
+        .insert(order);
+}
+
+// Returns how much the taker and the maker pay when the taker's order is filled as far as
+// possible at the maker's price. The maker never receives less than its price: the amount
+// the taker pays is rounded up. Returns None if not even one token of the maker can be
+// bought without exceeding the taker's price.
+fn fill_at_maker_price(taker: &Order, maker: &Order) -> Option<(Nat, Nat)> {
+    let affordable =
+        taker.fromAmount.to_owned() * maker.fromAmount.to_owned() / maker.toAmount.to_owned();
+    let maker_amount = std::cmp::min(maker.fromAmount.to_owned(), affordable);
+    if maker_amount == utils::zero() {
+        return None;
+    }
+
+    let taker_amount = utils::div_ceil(
+        maker_amount.to_owned() * maker.toAmount.to_owned(),
+        maker.fromAmount.to_owned(),
+    );
+    if maker_amount.to_owned() * taker.fromAmount.to_owned()
+        < taker_amount.to_owned() * taker.toAmount.to_owned()
+    {
+        return None;
+    }
+
+    Some((taker_amount, maker_amount))
+}
+
+// Returns the fee of `fee_bps` basis points of an amount, rounded down.
+fn fee_of(amount: Nat, fee_bps: u32) -> Nat {
+    amount * fee_bps / BPS
+}
+
+fn add_fee(order: &mut Order, fee: Nat) {
+    let paid = order.fee.take().unwrap_or_else(utils::zero);
+    order.fee = Some(paid + fee);
+}
+
+// Returns what is left of an order after `paid` of its tokens were traded. The remaining
+// `toAmount` is rounded up, so the price of the order never gets worse for its owner.
+fn remaining_order(order: Order, paid: Nat) -> Option<Order> {
+    let from_amount = order.fromAmount.to_owned() - paid;
+    if from_amount == utils::zero() {
+        return None;
+    }
+
+    let to_amount = utils::div_ceil(
+        from_amount.to_owned() * order.toAmount.to_owned(),
+        order.fromAmount.to_owned(),
+    );
+    Some(Order {
+        fromAmount: from_amount,
+        toAmount: to_amount,
+        ..order
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u8 = 1;
+    const QUOTE: u8 = 2;
+
+    fn order(id: OrderId, from: u8, from_amount: u64, to: u8, to_amount: u64) -> Order {
+        Order {
+            id,
+            owner: Principal::anonymous(),
+            from: Principal::from_slice(&[from]),
+            fromAmount: Nat::from(from_amount),
+            to: Principal::from_slice(&[to]),
+            toAmount: Nat::from(to_amount),
+            fee: Some(utils::zero()),
         }
     }
 
-    false
+    fn amounts(taker_amount: u64, maker_amount: u64) -> Option<(Nat, Nat)> {
+        Some((Nat::from(taker_amount), Nat::from(maker_amount)))
+    }
+
+    #[test]
+    fn taker_pays_the_maker_price() {
+        // The maker sells 100 base at 2, the taker would pay up to 3.
+        let maker = order(0, BASE, 100, QUOTE, 200);
+        let taker = order(1, QUOTE, 300, BASE, 100);
+
+        assert_eq!(fill_at_maker_price(&taker, &maker), amounts(200, 100));
+    }
+
+    #[test]
+    fn maker_is_filled_partially() {
+        let maker = order(0, BASE, 100, QUOTE, 200);
+        let taker = order(1, QUOTE, 50, BASE, 25);
+
+        assert_eq!(fill_at_maker_price(&taker, &maker), amounts(50, 25));
+
+        let remaining = remaining_order(maker, Nat::from(25u64)).unwrap();
+        assert_eq!(remaining.fromAmount, Nat::from(75u64));
+        assert_eq!(remaining.toAmount, Nat::from(150u64));
+    }
+
+    #[test]
+    fn amount_paid_to_maker_is_rounded_up() {
+        // The maker sells 3 base for 10 quote, i.e. at 3.33.
+        let maker = order(0, BASE, 3, QUOTE, 10);
+
+        let taker = order(1, QUOTE, 4, BASE, 1);
+        assert_eq!(fill_at_maker_price(&taker, &maker), amounts(4, 1));
+
+        let taker = order(1, QUOTE, 10, BASE, 3);
+        assert_eq!(fill_at_maker_price(&taker, &maker), amounts(10, 3));
+    }
+
+    #[test]
+    fn taker_price_is_never_exceeded() {
+        let maker = order(0, BASE, 3, QUOTE, 10);
+
+        // Rounded up, 2 base would cost 7 quote, more than the taker's 3 per base.
+        let taker = order(1, QUOTE, 9, BASE, 3);
+        assert_eq!(fill_at_maker_price(&taker, &maker), None);
+
+        // Not even one base is affordable.
+        let taker = order(1, QUOTE, 3, BASE, 1);
+        assert_eq!(fill_at_maker_price(&taker, &maker), None);
+    }
+
+    #[test]
+    fn remaining_order_keeps_its_price() {
+        // 300 quote for 100 base, after paying 200: 100 quote for 33.33 base, rounded up.
+        let taker = order(1, QUOTE, 300, BASE, 100);
+
+        let remaining = remaining_order(taker.clone(), Nat::from(200u64)).unwrap();
+        assert_eq!(remaining.fromAmount, Nat::from(100u64));
+        assert_eq!(remaining.toAmount, Nat::from(34u64));
+        assert_eq!(remaining.id, taker.id);
+
+        assert!(remaining_order(taker, Nat::from(300u64)).is_none());
+    }
 }
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use candid::{Nat, Principal};
+use ic_stable_structures::StableBTreeMap;
+
+use crate::memory::{self, Memory};
+use crate::types::*;
+
+// The number of fills returned by a history query if the caller does not set a limit
+pub const DEFAULT_HISTORY_LIMIT: u32 = 100;
+
+// The maximum number of fills returned by a history query
+pub const MAX_HISTORY_LIMIT: u32 = 1_000;
+
+// The append-only log of all trades, kept in stable memory. The indexes by token pair and
+// by user are kept on the heap and rebuilt after an upgrade.
+pub struct TradeHistory {
+    fills: StableBTreeMap<FillId, Fill, Memory>,
+    by_pair: HashMap<(Principal, Principal), Vec<FillId>>, // (base, quote) -> fills
+    by_user: HashMap<Principal, Vec<FillId>>,              // taker or maker -> fills
+}
+
+impl Default for TradeHistory {
+    fn default() -> Self {
+        TradeHistory {
+            fills: StableBTreeMap::init(memory::get(memory::FILLS)),
+            by_pair: HashMap::new(),
+            by_user: HashMap::new(),
+        }
+    }
+}
+
+impl TradeHistory {
+    pub fn next_id(&self) -> FillId {
+        self.fills.len()
+    }
+
+    // Appends a fill to the log. Its id must be `next_id()`.
+    pub fn record(&mut self, fill: Fill) {
+        assert_eq!(fill.id, self.next_id());
+
+        index(&mut self.by_pair, &mut self.by_user, &fill);
+        self.fills.insert(fill.id, fill);
+    }
+
+    pub fn rebuild_indexes(&mut self) {
+        self.by_pair.clear();
+        self.by_user.clear();
+        for (_, fill) in self.fills.iter() {
+            index(&mut self.by_pair, &mut self.by_user, &fill);
+        }
+    }
+
+    // Returns the trades of a pair, newest first, starting before the fill `before`.
+    pub fn trades(
+        &self,
+        base: Principal,
+        quote: Principal,
+        before: Option<FillId>,
+        limit: Option<u32>,
+    ) -> Vec<Fill> {
+        self.page(self.by_pair.get(&(base, quote)), before, limit)
+    }
+
+    // Returns the fills of a user as taker or maker, newest first, starting before the fill
+    // `before`.
+    pub fn fills_of(
+        &self,
+        user: Principal,
+        before: Option<FillId>,
+        limit: Option<u32>,
+    ) -> Vec<Fill> {
+        self.page(self.by_user.get(&user), before, limit)
+    }
+
+    // Aggregates the trades of a pair in [from, to) into candles of `interval` nanoseconds,
+    // oldest first. Intervals without trades are left out.
+    pub fn candles(
+        &self,
+        base: Principal,
+        quote: Principal,
+        interval: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Vec<Candle> {
+        let mut candles: Vec<Candle> = Vec::new();
+        if interval == 0 {
+            return candles;
+        }
+
+        let ids = self.by_pair.get(&(base, quote)).map_or(&[][..], |ids| ids);
+        let fills = ids
+            .iter()
+            .map(|id| self.fills.get(id).unwrap())
+            .filter(|fill| {
+                fill.timestamp >= from.unwrap_or(0) && fill.timestamp < to.unwrap_or(u64::MAX)
+            });
+
+        for fill in fills {
+            let start = fill.timestamp - fill.timestamp % interval;
+            match candles.last_mut() {
+                Some(candle) if candle.start == start => {
+                    if fill.price > candle.high {
+                        candle.high = fill.price.to_owned();
+                    }
+                    if fill.price < candle.low {
+                        candle.low = fill.price.to_owned();
+                    }
+                    candle.close = fill.price.to_owned();
+                    candle.base_volume += fill.base_amount.to_owned();
+                    candle.quote_volume += fill.quote_amount.to_owned();
+                    candle.trades += 1;
+                }
+                _ => candles.push(Candle {
+                    start,
+                    open: fill.price.to_owned(),
+                    high: fill.price.to_owned(),
+                    low: fill.price.to_owned(),
+                    close: fill.price.to_owned(),
+                    base_volume: fill.base_amount.to_owned(),
+                    quote_volume: fill.quote_amount.to_owned(),
+                    trades: 1,
+                }),
+            }
+        }
+
+        candles
+    }
+
+    fn page(
+        &self,
+        ids: Option<&Vec<FillId>>,
+        before: Option<FillId>,
+        limit: Option<u32>,
+    ) -> Vec<Fill> {
+        let limit = limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .min(MAX_HISTORY_LIMIT) as usize;
+
+        ids.map_or(&[][..], |ids| ids)
+            .iter()
+            .rev()
+            .filter(|id| **id < before.unwrap_or(FillId::MAX))
+            .take(limit)
+            .map(|id| self.fills.get(id).unwrap())
+            .collect()
+    }
+}
+
+// Adds a fill to the indexes by token pair and by user.
+fn index(
+    by_pair: &mut HashMap<(Principal, Principal), Vec<FillId>>,
+    by_user: &mut HashMap<Principal, Vec<FillId>>,
+    fill: &Fill,
+) {
+    by_pair
+        .entry((fill.base, fill.quote))
+        .or_default()
+        .push(fill.id);
+    by_user.entry(fill.taker).or_default().push(fill.id);
+    if fill.maker != fill.taker {
+        by_user.entry(fill.maker).or_default().push(fill.id);
+    }
+}
+
+// Returns the amounts of a trade as (base amount, quote amount).
+pub fn base_and_quote(
+    base: Principal,
+    sold_token: Principal,
+    sold_amount: Nat,
+    bought_amount: Nat,
+) -> (Nat, Nat) {
+    if sold_token == base {
+        (sold_amount, bought_amount)
+    } else {
+        (bought_amount, sold_amount)
+    }
+}
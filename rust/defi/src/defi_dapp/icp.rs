@@ -0,0 +1,134 @@
+use std::convert::TryInto;
+
+use candid::{Nat, Principal};
+use ic_ledger_types::{
+    AccountIdentifier, Memo, Timestamp, Tokens, TransferError, DEFAULT_SUBACCOUNT,
+};
+
+use crate::token::{TokenErr, TokenLedger, TransferKey};
+use crate::utils::{self, principal_to_subaccount};
+
+pub const ICP_FEE: u64 = 10_000;
+
+// The ICP ledger. Users deposit ICP by transferring it to their deposit address, see
+// `getDepositAddress`, which is a subaccount of the exchange.
+pub struct IcpLedger {
+    principal: Principal,
+}
+
+impl From<TransferError> for TokenErr {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::TxDuplicate { .. } => TokenErr::Duplicate,
+            TransferError::TxTooOld { .. } => TokenErr::TooOld,
+            error => TokenErr::Rejected(error.to_string()),
+        }
+    }
+}
+
+impl IcpLedger {
+    pub fn new(principal: Principal) -> Self {
+        IcpLedger { principal }
+    }
+
+    async fn transfer_icp(
+        &self,
+        from_subaccount: ic_ledger_types::Subaccount,
+        to: AccountIdentifier,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        let e8s = amount
+            .0
+            .try_into()
+            .map_err(|_| TokenErr::Rejected("amount too large".to_string()))?;
+        let transfer_args = ic_ledger_types::TransferArgs {
+            memo: Memo(key.memo),
+            amount: Tokens::from_e8s(e8s),
+            fee: Tokens::from_e8s(ICP_FEE),
+            from_subaccount: Some(from_subaccount),
+            to,
+            created_at_time: Some(Timestamp {
+                timestamp_nanos: key.created_at_time,
+            }),
+        };
+        let block_index = ic_ledger_types::transfer(self.principal, transfer_args).await??;
+
+        Ok(block_index.into())
+    }
+}
+
+impl TokenLedger for IcpLedger {
+    async fn symbol(&self) -> Result<String, TokenErr> {
+        Ok("ICP".to_string())
+    }
+
+    async fn fee(&self) -> Result<Nat, TokenErr> {
+        Ok(ICP_FEE.into())
+    }
+
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        let account_id = AccountIdentifier::new(&to, &DEFAULT_SUBACCOUNT);
+        let block_index = self
+            .transfer_icp(DEFAULT_SUBACCOUNT, account_id, amount.to_owned(), key)
+            .await?;
+
+        ic_cdk::println!("Withdrawal of {} ICP to account {:?}", amount, &account_id);
+
+        Ok(block_index)
+    }
+
+    async fn allowance(&self, _owner: Principal) -> Result<Nat, TokenErr> {
+        Err(TokenErr::NotSupported)
+    }
+
+    async fn transfer_from(
+        &self,
+        _owner: Principal,
+        _amount: Nat,
+        _key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        Err(TokenErr::NotSupported)
+    }
+
+    async fn available_deposit(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        let canister_id = ic_cdk::api::id();
+        let account = AccountIdentifier::new(&canister_id, &principal_to_subaccount(&owner));
+
+        let balance_args = ic_ledger_types::AccountBalanceArgs { account };
+        let balance = ic_ledger_types::account_balance(self.principal, balance_args).await?;
+
+        if balance.e8s() < ICP_FEE {
+            return Ok(utils::zero());
+        }
+
+        Ok((balance.e8s() - ICP_FEE).into())
+    }
+
+    async fn collect_deposit(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        let canister_id = ic_cdk::api::id();
+        let account = AccountIdentifier::new(&canister_id, &principal_to_subaccount(&owner));
+        let block_index = self
+            .transfer_icp(
+                principal_to_subaccount(&owner),
+                AccountIdentifier::new(&canister_id, &DEFAULT_SUBACCOUNT),
+                amount.to_owned(),
+                key,
+            )
+            .await?;
+
+        ic_cdk::println!("Deposit of {} ICP in account {:?}", amount, &account);
+
+        Ok(block_index)
+    }
+}
@@ -0,0 +1,244 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_ledger_types::Subaccount;
+
+use crate::token::{approved_deposit, TokenErr, TokenLedger, TransferKey};
+use crate::utils::{self, principal_to_subaccount};
+
+// An ICRC-1 ledger, optionally with the ICRC-2 approve and transfer_from extension.
+// Without ICRC-2, users deposit tokens by transferring them to their deposit account, see
+// `getDepositAccount`, which is a subaccount of the exchange.
+pub struct Icrc {
+    principal: Principal,
+    icrc2: bool,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+impl From<TransferError> for TokenErr {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::Duplicate { .. } => TokenErr::Duplicate,
+            TransferError::TooOld => TokenErr::TooOld,
+            error => TokenErr::Rejected(format!("{:?}", error)),
+        }
+    }
+}
+
+impl From<TransferFromError> for TokenErr {
+    fn from(error: TransferFromError) -> Self {
+        match error {
+            TransferFromError::Duplicate { .. } => TokenErr::Duplicate,
+            TransferFromError::TooOld => TokenErr::TooOld,
+            error => TokenErr::Rejected(format!("{:?}", error)),
+        }
+    }
+}
+
+// Returns the account of the exchange to which `owner` transfers ICRC-1 deposits.
+pub fn deposit_account(owner: &Principal) -> Account {
+    Account {
+        owner: ic_cdk::api::id(),
+        subaccount: Some(principal_to_subaccount(owner)),
+    }
+}
+
+fn account_of(owner: Principal) -> Account {
+    Account {
+        owner,
+        subaccount: None,
+    }
+}
+
+fn memo_of(key: &TransferKey) -> Option<Vec<u8>> {
+    Some(key.memo.to_be_bytes().to_vec())
+}
+
+impl Icrc {
+    pub fn new(principal: Principal, icrc2: bool) -> Self {
+        Icrc { principal, icrc2 }
+    }
+
+    async fn balance_of(&self, account: Account) -> Result<Nat, TokenErr> {
+        let (balance,): (Nat,) =
+            ic_cdk::api::call::call(self.principal, "icrc1_balance_of", (account,)).await?;
+
+        Ok(balance)
+    }
+
+    async fn icrc1_transfer(&self, args: TransferArg) -> Result<Nat, TokenErr> {
+        let (result,): (Result<Nat, TransferError>,) =
+            ic_cdk::api::call::call(self.principal, "icrc1_transfer", (args,)).await?;
+
+        Ok(result?)
+    }
+}
+
+impl TokenLedger for Icrc {
+    async fn symbol(&self) -> Result<String, TokenErr> {
+        let (symbol,): (String,) =
+            ic_cdk::api::call::call(self.principal, "icrc1_symbol", ()).await?;
+
+        Ok(symbol)
+    }
+
+    async fn fee(&self) -> Result<Nat, TokenErr> {
+        let (fee,): (Nat,) = ic_cdk::api::call::call(self.principal, "icrc1_fee", ()).await?;
+
+        Ok(fee)
+    }
+
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        self.icrc1_transfer(TransferArg {
+            from_subaccount: None,
+            to: account_of(to),
+            amount,
+            fee: None,
+            memo: memo_of(key),
+            created_at_time: Some(key.created_at_time),
+        })
+        .await
+    }
+
+    async fn allowance(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        if !self.icrc2 {
+            return Err(TokenErr::NotSupported);
+        }
+
+        let args = AllowanceArgs {
+            account: account_of(owner),
+            spender: account_of(ic_cdk::api::id()),
+        };
+        let (allowance,): (Allowance,) =
+            ic_cdk::api::call::call(self.principal, "icrc2_allowance", (args,)).await?;
+
+        Ok(allowance.allowance)
+    }
+
+    async fn transfer_from(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        if !self.icrc2 {
+            return Err(TokenErr::NotSupported);
+        }
+
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: account_of(owner),
+            to: account_of(ic_cdk::api::id()),
+            amount,
+            fee: None,
+            memo: memo_of(key),
+            created_at_time: Some(key.created_at_time),
+        };
+        let (result,): (Result<Nat, TransferFromError>,) =
+            ic_cdk::api::call::call(self.principal, "icrc2_transfer_from", (args,)).await?;
+
+        Ok(result?)
+    }
+
+    async fn available_deposit(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        if self.icrc2 {
+            return approved_deposit(self, owner).await;
+        }
+
+        let fee = self.fee().await?;
+        let balance = self.balance_of(deposit_account(&owner)).await?;
+        if balance <= fee {
+            return Ok(utils::zero());
+        }
+
+        Ok(balance - fee)
+    }
+
+    async fn collect_deposit(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        if self.icrc2 {
+            return self.transfer_from(owner, amount, key).await;
+        }
+
+        // Sweep the deposit account of the owner into the exchange's account
+        self.icrc1_transfer(TransferArg {
+            from_subaccount: Some(principal_to_subaccount(&owner)),
+            to: account_of(ic_cdk::api::id()),
+            amount,
+            fee: None,
+            memo: memo_of(key),
+            created_at_time: Some(key.created_at_time),
+        })
+        .await
+    }
+}
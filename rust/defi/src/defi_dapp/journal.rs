@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use candid::{Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use crate::token::{TokenErr, TransferKey};
+use crate::types::*;
+
+// Operations that are pending for longer than this are stuck: the message that sent their
+// transfer did not settle them, e.g. because it trapped after the transfer returned.
+pub const STUCK_AFTER_NS: u64 = 5 * 60 * 1_000_000_000;
+
+// The deposits and withdrawals whose transfers are in flight or stuck. An operation is
+// recorded before its transfer is sent and removed once the outcome of the transfer is
+// applied to the balances, so every operation is settled at most once.
+#[derive(Default)]
+pub struct Journal {
+    pub next_id: OperationId,
+    pub operations: BTreeMap<OperationId, Operation>,
+}
+
+impl Journal {
+    pub fn start(
+        &mut self,
+        kind: OperationKind,
+        owner: Principal,
+        token: Principal,
+        amount: Nat,
+    ) -> Operation {
+        let operation = Operation {
+            id: self.next_id,
+            kind,
+            owner,
+            token,
+            amount,
+            created_at: ic_cdk::api::time(),
+        };
+        self.next_id += 1;
+        self.operations.insert(operation.id, operation.clone());
+        operation
+    }
+
+    pub fn get(&self, id: OperationId) -> Option<&Operation> {
+        self.operations.get(&id)
+    }
+
+    pub fn finish(&mut self, id: OperationId) -> Option<Operation> {
+        self.operations.remove(&id)
+    }
+
+    pub fn stuck(&self, now: u64) -> Vec<Operation> {
+        self.operations
+            .values()
+            .filter(|o| is_stuck(o, now))
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn is_stuck(operation: &Operation, now: u64) -> bool {
+    now.saturating_sub(operation.created_at) >= STUCK_AFTER_NS
+}
+
+// The transfer of an operation is always sent with the same key, so retrying it is safe
+// with ledgers that deduplicate transfers.
+pub fn transfer_key(operation: &Operation) -> TransferKey {
+    TransferKey {
+        memo: operation.id,
+        created_at_time: operation.created_at,
+    }
+}
+
+// Returns whether the ledger executed a transfer, or None if that is unknown.
+//
+// The system only rejects a call itself if it could not deliver it, and a ledger rejects a
+// call explicitly before it executes the transfer. A ledger that trapped may have executed
+// the transfer before, and a reply that cannot be decoded traps the exchange, so both leave
+// the operation in the journal.
+pub fn executed(result: &Result<Nat, TokenErr>) -> Option<bool> {
+    match result {
+        Ok(_) | Err(TokenErr::Duplicate) => Some(true),
+        Err(TokenErr::TooOld) => None,
+        Err(TokenErr::CallFailed(code, _)) => match code {
+            RejectionCode::SysFatal
+            | RejectionCode::SysTransient
+            | RejectionCode::DestinationInvalid
+            | RejectionCode::CanisterReject => Some(false),
+            RejectionCode::NoError | RejectionCode::CanisterError | RejectionCode::Unknown => None,
+        },
+        Err(_) => Some(false),
+    }
+}
@@ -1,26 +1,32 @@
 use std::cell::RefCell;
-use std::convert::TryInto;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
 use candid::{candid_method, export_service, Nat, Principal};
 use ic_cdk::caller;
 use ic_cdk_macros::*;
-use ic_ledger_types::{
-    AccountIdentifier, Memo, Tokens, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
-};
+use ic_ledger_types::{AccountIdentifier, MAINNET_LEDGER_CANISTER_ID};
 
 mod dip20;
 mod exchange;
+mod history;
+mod icp;
+mod icrc;
+mod journal;
+mod memory;
+mod order_book;
+mod pool;
 mod stable;
+mod token;
 mod types;
 mod utils;
 
-use dip20::DIP20;
 use exchange::Exchange;
+use journal::Journal;
+use token::{Token, TokenErr, TokenLedger, TokenStandard};
 use types::*;
 use utils::principal_to_subaccount;
 
-const ICP_FEE: u64 = 10_000;
-
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
 }
@@ -29,84 +35,94 @@ thread_local! {
 pub struct State {
     owner: Option<Principal>,
     ledger: Option<Principal>,
+    tokens: HashMap<Principal, TokenStandard>,
     exchange: Exchange,
+    journal: Journal,
 }
 
 #[update]
 #[candid_method(update)]
 pub async fn deposit(token_canister_id: Principal) -> DepositReceipt {
     let caller = caller();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
-
-    let amount = if token_canister_id == ledger_canister_id {
-        deposit_icp(caller).await?
-    } else {
-        deposit_token(caller, token_canister_id).await?
-    };
-    STATE.with(|s| {
-        s.borrow_mut()
-            .exchange
-            .balances
-            .add_balance(&caller, &token_canister_id, amount.to_owned())
-    });
-    DepositReceipt::Ok(amount)
-}
-
-async fn deposit_icp(caller: Principal) -> Result<Nat, DepositErr> {
-    let canister_id = ic_cdk::api::id();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+    let token = token(token_canister_id);
 
-    let account = AccountIdentifier::new(&canister_id, &principal_to_subaccount(&caller));
-
-    let balance_args = ic_ledger_types::AccountBalanceArgs { account };
-    let balance = ic_ledger_types::account_balance(ledger_canister_id, balance_args)
+    let amount = token
+        .available_deposit(caller)
         .await
         .map_err(|_| DepositErr::TransferFailure)?;
-
-    if balance.e8s() < ICP_FEE {
+    if amount == utils::zero() {
         return Err(DepositErr::BalanceLow);
     }
 
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: balance - Tokens::from_e8s(ICP_FEE),
-        fee: Tokens::from_e8s(ICP_FEE),
-        from_subaccount: Some(principal_to_subaccount(&caller)),
-        to: AccountIdentifier::new(&canister_id, &DEFAULT_SUBACCOUNT),
-        created_at_time: None,
-    };
-    ic_ledger_types::transfer(ledger_canister_id, transfer_args)
-        .await
-        .map_err(|_| DepositErr::TransferFailure)?
-        .map_err(|_| DepositErr::TransferFailure)?;
-
-    ic_cdk::println!(
-        "Deposit of {} ICP in account {:?}",
-        balance - Tokens::from_e8s(ICP_FEE),
-        &account
-    );
+    // The deposit is credited once the transfer to the exchange returned
+    let operation = STATE.with(|s| {
+        s.borrow_mut().journal.start(
+            OperationKind::Deposit,
+            caller,
+            token_canister_id,
+            amount.to_owned(),
+        )
+    });
+    let result = token
+        .collect_deposit(
+            caller,
+            amount.to_owned(),
+            &journal::transfer_key(&operation),
+        )
+        .await;
 
-    Ok((balance.e8s() - ICP_FEE).into())
+    if settle(operation.id, &result) != Some(true) {
+        return Err(DepositErr::TransferFailure);
+    }
+    DepositReceipt::Ok(amount)
 }
 
-async fn deposit_token(caller: Principal, token: Principal) -> Result<Nat, DepositErr> {
-    let token = DIP20::new(token);
-    let dip_fee = token.get_metadata().await.fee;
+// Applies the outcome of the transfer of a journaled operation to the balances: deposits
+// are credited if the transfer was executed, withdrawals are refunded if it was not.
+// Returns whether the transfer was executed, or None if that is unknown, in which case the
+// operation stays in the journal. An operation that was settled already is left alone.
+fn settle(id: OperationId, result: &Result<Nat, TokenErr>) -> Option<bool> {
+    if let Err(e) = result {
+        ic_cdk::println!("transfer of operation {} failed: {}", id, e);
+    }
 
-    let allowance = token.allowance(caller, ic_cdk::api::id()).await;
+    let executed = journal::executed(result)?;
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if let Some(operation) = state.journal.finish(id) {
+            apply(&mut state, operation, executed);
+        }
+    });
+    Some(executed)
+}
 
-    let available = allowance - dip_fee;
+fn apply(state: &mut State, operation: Operation, executed: bool) {
+    match (operation.kind, executed) {
+        (OperationKind::Deposit, true) | (OperationKind::Withdraw { .. }, false) => state
+            .exchange
+            .balances
+            .add_balance(&operation.owner, &operation.token, operation.amount),
+        _ => {}
+    }
+}
 
-    token
-        .transfer_from(caller, ic_cdk::api::id(), available.to_owned())
-        .await
-        .map_err(|_| DepositErr::TransferFailure)?;
+// Returns the standard of a token canister. Tokens that were not registered are DIP20
+// tokens, except for the ICP ledger.
+fn token_standard(token_canister_id: Principal) -> TokenStandard {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let ledger_canister_id = state.ledger.unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+
+        match state.tokens.get(&token_canister_id) {
+            Some(standard) => *standard,
+            None if token_canister_id == ledger_canister_id => TokenStandard::Icp,
+            None => TokenStandard::Dip20,
+        }
+    })
+}
 
-    Ok(available)
+fn token(token_canister_id: Principal) -> Token {
+    Token::new(token_standard(token_canister_id), token_canister_id)
 }
 
 #[query(name = "getBalance")]
@@ -139,6 +155,49 @@ pub fn get_orders() -> Vec<Order> {
     STATE.with(|s| s.borrow().exchange.get_all_orders())
 }
 
+#[query(name = "getOrderBook")]
+#[candid_method(query, rename = "getOrderBook")]
+pub fn get_order_book(token_a: Principal, token_b: Principal) -> OrderBookSnapshot {
+    STATE.with(|s| s.borrow().exchange.get_order_book(token_a, token_b))
+}
+
+#[query(name = "getTradeHistory")]
+#[candid_method(query, rename = "getTradeHistory")]
+pub fn get_trade_history(
+    token_a: Principal,
+    token_b: Principal,
+    before: Option<FillId>,
+    limit: Option<u32>,
+) -> Vec<Fill> {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_trade_history(token_a, token_b, before, limit)
+    })
+}
+
+#[query(name = "getFills")]
+#[candid_method(query, rename = "getFills")]
+pub fn get_fills(user: Principal, before: Option<FillId>, limit: Option<u32>) -> Vec<Fill> {
+    STATE.with(|s| s.borrow().exchange.get_fills(user, before, limit))
+}
+
+#[query(name = "getCandles")]
+#[candid_method(query, rename = "getCandles")]
+pub fn get_candles(
+    token_a: Principal,
+    token_b: Principal,
+    interval: u64,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Vec<Candle> {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_candles(token_a, token_b, interval, from, to)
+    })
+}
+
 #[update(name = "getDepositAddress")]
 #[candid_method(update, rename = "getDepositAddress")]
 pub fn get_deposit_address() -> AccountIdentifier {
@@ -151,15 +210,33 @@ pub fn get_deposit_address() -> AccountIdentifier {
 #[update(name = "getSymbol")]
 #[candid_method(update, rename = "getSymbol")]
 pub async fn get_symbol(token_canister_id: Principal) -> String {
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+    token(token_canister_id)
+        .symbol()
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to get the symbol: {}", e)))
+}
 
-    if token_canister_id == ledger_canister_id {
-        "ICP".to_string()
-    } else {
-        DIP20::new(token_canister_id).get_metadata().await.symbol
-    }
+#[update(name = "getDepositAccount")]
+#[candid_method(update, rename = "getDepositAccount")]
+pub fn get_deposit_account() -> icrc::Account {
+    icrc::deposit_account(&caller())
+}
+
+#[query(name = "getTokenStandard")]
+#[candid_method(query, rename = "getTokenStandard")]
+pub fn get_token_standard(token_canister_id: Principal) -> TokenStandard {
+    token_standard(token_canister_id)
+}
+
+#[update(name = "registerToken")]
+#[candid_method(update, rename = "registerToken")]
+pub fn register_token(token_canister_id: Principal, standard: TokenStandard) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+
+        assert!(state.owner.unwrap() == caller());
+        state.tokens.insert(token_canister_id, standard);
+    })
 }
 
 #[update(name = "placeOrder")]
@@ -186,6 +263,100 @@ pub fn cancel_order(order: OrderId) -> CancelOrderReceipt {
     STATE.with(|s| s.borrow_mut().exchange.cancel_order(order))
 }
 
+#[query(name = "getTradingFees")]
+#[candid_method(query, rename = "getTradingFees")]
+pub fn get_trading_fees(token_a: Principal, token_b: Principal) -> TradingFees {
+    STATE.with(|s| s.borrow().exchange.get_trading_fees(token_a, token_b))
+}
+
+// Sets the maker and taker fees of a token pair in basis points (owner only).
+#[update(name = "setTradingFees")]
+#[candid_method(update, rename = "setTradingFees")]
+pub fn set_trading_fees(token_a: Principal, token_b: Principal, fees: TradingFees) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+
+        assert!(state.owner.unwrap() == caller());
+        assert!(fees.maker_bps < utils::BPS && fees.taker_bps < utils::BPS);
+        state.exchange.set_trading_fees(token_a, token_b, fees);
+    })
+}
+
+#[query(name = "getCollectedFees")]
+#[candid_method(query, rename = "getCollectedFees")]
+pub fn get_collected_fees() -> Vec<Balance> {
+    STATE.with(|s| s.borrow().exchange.get_collected_fees())
+}
+
+#[query(name = "getPool")]
+#[candid_method(query, rename = "getPool")]
+pub fn get_pool(token_a: Principal, token_b: Principal) -> Option<PoolSnapshot> {
+    STATE.with(|s| s.borrow().exchange.get_pool(token_a, token_b))
+}
+
+#[query(name = "getPoolShares")]
+#[candid_method(query, rename = "getPoolShares")]
+pub fn get_pool_shares(token_a: Principal, token_b: Principal) -> Nat {
+    STATE.with(|s| s.borrow().exchange.get_pool_shares(token_a, token_b))
+}
+
+// Sets the fee of the pool of a token pair in basis points (owner only).
+#[update(name = "setPoolFee")]
+#[candid_method(update, rename = "setPoolFee")]
+pub fn set_pool_fee(token_a: Principal, token_b: Principal, fee_bps: u32) -> PoolFeeReceipt {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+
+        assert!(state.owner.unwrap() == caller());
+        state.exchange.set_pool_fee(token_a, token_b, fee_bps)
+    })
+}
+
+#[update(name = "addLiquidity")]
+#[candid_method(update, rename = "addLiquidity")]
+pub fn add_liquidity(
+    token_a: Principal,
+    amount_a: Nat,
+    token_b: Principal,
+    amount_b: Nat,
+) -> AddLiquidityReceipt {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .exchange
+            .add_liquidity(token_a, amount_a, token_b, amount_b)
+    })
+}
+
+#[update(name = "removeLiquidity")]
+#[candid_method(update, rename = "removeLiquidity")]
+pub fn remove_liquidity(
+    token_a: Principal,
+    token_b: Principal,
+    shares: Nat,
+) -> RemoveLiquidityReceipt {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .exchange
+            .remove_liquidity(token_a, token_b, shares)
+    })
+}
+
+#[query(name = "getSwapQuote")]
+#[candid_method(query, rename = "getSwapQuote")]
+pub fn get_swap_quote(from: Principal, amount: Nat, to: Principal) -> SwapReceipt {
+    STATE.with(|s| s.borrow().exchange.get_swap_quote(from, amount, to))
+}
+
+#[update]
+#[candid_method(update)]
+pub fn swap(from: Principal, amount: Nat, to: Principal, min_amount_out: Nat) -> SwapReceipt {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .exchange
+            .swap(from, amount, to, min_amount_out)
+    })
+}
+
 #[update]
 #[candid_method(update)]
 pub async fn withdraw(
@@ -194,119 +365,150 @@ pub async fn withdraw(
     address: Principal,
 ) -> WithdrawReceipt {
     let caller = caller();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
 
     // Close all currently open orders to avoid completing orders
     // without funds.
-    STATE.with(|s| {
-        s.borrow_mut()
-            .exchange
-            .orders
-            .retain(|_, v| v.owner != caller);
-    });
+    STATE.with(|s| s.borrow_mut().exchange.remove_orders_of(&caller));
 
-    if token_canister_id == ledger_canister_id {
-        let account_id = AccountIdentifier::new(&address, &DEFAULT_SUBACCOUNT);
-        withdraw_icp(&amount, account_id).await
-    } else {
-        withdraw_token(token_canister_id, &amount, address).await
-    }
+    withdraw_from(caller, token_canister_id, amount, address).await
 }
 
-async fn withdraw_icp(amount: &Nat, account_id: AccountIdentifier) -> Result<Nat, WithdrawErr> {
-    let caller = caller();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
-
-    let sufficient_balance = STATE.with(|s| {
-        s.borrow_mut().exchange.balances.subtract_balance(
-            &caller,
-            &ledger_canister_id,
-            amount.to_owned() + ICP_FEE,
-        )
-    });
-    if !sufficient_balance {
-        return Err(WithdrawErr::BalanceLow);
-    }
+// Withdraws collected trading fees (owner only), see `getCollectedFees`.
+#[update(name = "withdrawFees")]
+#[candid_method(update, rename = "withdrawFees")]
+pub async fn withdraw_fees(
+    token_canister_id: Principal,
+    amount: Nat,
+    address: Principal,
+) -> WithdrawReceipt {
+    STATE.with(|s| assert!(s.borrow().owner.unwrap() == caller()));
 
-    let transfer_amount = Tokens::from_e8s(
-        (amount.to_owned() + ICP_FEE)
-            .0
-            .try_into()
-            .map_err(|_| WithdrawErr::TransferFailure)?,
-    );
-
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: transfer_amount,
-        fee: Tokens::from_e8s(ICP_FEE),
-        from_subaccount: Some(DEFAULT_SUBACCOUNT),
-        to: account_id,
-        created_at_time: None,
-    };
-    let icp_reciept = ic_ledger_types::transfer(ledger_canister_id, transfer_args)
+    withdraw_from(exchange::fee_account(), token_canister_id, amount, address).await
+}
+
+// Transfers `amount` from the balance of `owner` to `address`. The fee of the transfer is
+// debited from the balance too.
+async fn withdraw_from(
+    owner: Principal,
+    token_canister_id: Principal,
+    amount: Nat,
+    address: Principal,
+) -> WithdrawReceipt {
+    let token = token(token_canister_id);
+
+    let fee = token
+        .fee()
         .await
-        .map_err(|_| WithdrawErr::TransferFailure)
-        .and_then(|v| v.map_err(|_| WithdrawErr::TransferFailure));
-
-    if let Err(e) = icp_reciept {
-        STATE.with(|s| {
-            s.borrow_mut().exchange.balances.add_balance(
-                &caller,
-                &ledger_canister_id,
-                amount.to_owned() + ICP_FEE,
-            )
-        });
-
-        return Err(e);
+        .map_err(|_| WithdrawErr::TransferFailure)?;
+    let amount = amount + fee;
+
+    // The balance is debited before the transfer and refunded if the transfer fails
+    let operation = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let sufficient_balance =
+            state
+                .exchange
+                .balances
+                .subtract_balance(&owner, &token_canister_id, amount.to_owned());
+        if !sufficient_balance {
+            return None;
+        }
+        Some(state.journal.start(
+            OperationKind::Withdraw { to: address },
+            owner,
+            token_canister_id,
+            amount.to_owned(),
+        ))
+    });
+    let operation = operation.ok_or(WithdrawErr::BalanceLow)?;
+
+    let result = token
+        .transfer(
+            address,
+            amount.to_owned(),
+            &journal::transfer_key(&operation),
+        )
+        .await;
+
+    if settle(operation.id, &result) != Some(true) {
+        return Err(WithdrawErr::TransferFailure);
     }
+    Ok(amount)
+}
 
-    ic_cdk::println!("Withdrawal of {} ICP to account {:?}", amount, &account_id);
+// Lists the deposits and withdrawals that are pending for too long (owner only).
+#[query(name = "getStuckOperations")]
+#[candid_method(query, rename = "getStuckOperations")]
+pub fn get_stuck_operations() -> Vec<Operation> {
+    STATE.with(|s| {
+        let state = s.borrow();
 
-    Ok(amount.to_owned() + ICP_FEE)
+        assert!(state.owner.unwrap() == caller());
+        state.journal.stuck(ic_cdk::api::time())
+    })
 }
 
-async fn withdraw_token(
-    token: Principal,
-    amount: &Nat,
-    address: Principal,
-) -> Result<Nat, WithdrawErr> {
-    let caller = caller();
-    let dip = DIP20::new(token);
-    let dip_fee = dip.get_metadata().await.fee;
-
-    let sufficient_balance = STATE.with(|s| {
-        s.borrow_mut().exchange.balances.subtract_balance(
-            &caller,
-            &token,
-            amount.to_owned() + dip_fee.to_owned(),
-        )
+// Sends the transfer of a stuck operation again and settles the operation (owner only).
+// Only transfers to ledgers that deduplicate transfers can be retried.
+#[update(name = "retryOperation")]
+#[candid_method(update, rename = "retryOperation")]
+pub async fn retry_operation(id: OperationId) -> OperationReceipt {
+    let operation = STATE.with(|s| {
+        let state = s.borrow();
+
+        assert!(state.owner.unwrap() == caller());
+        state.journal.get(id).cloned()
     });
-    if !sufficient_balance {
-        return Err(WithdrawErr::BalanceLow);
+    let operation = operation.ok_or(OperationErr::NotExistingOperation)?;
+    if !journal::is_stuck(&operation, ic_cdk::api::time()) {
+        return Err(OperationErr::NotStuck);
     }
 
-    let tx_receipt = dip
-        .transfer(address, amount.to_owned() + dip_fee.to_owned())
-        .await
-        .map_err(|_| WithdrawErr::TransferFailure);
-
-    if let Err(e) = tx_receipt {
-        STATE.with(|s| {
-            s.borrow_mut().exchange.balances.add_balance(
-                &caller,
-                &token,
-                amount.to_owned() + dip_fee.to_owned(),
-            )
-        });
-
-        return Err(e);
+    let token = token(operation.token);
+    if !token.deduplicates() {
+        return Err(OperationErr::NotRetryable);
+    }
+
+    let key = journal::transfer_key(&operation);
+    let amount = operation.amount.to_owned();
+    let result = match operation.kind {
+        OperationKind::Deposit => {
+            token
+                .collect_deposit(operation.owner, amount.to_owned(), &key)
+                .await
+        }
+        OperationKind::Withdraw { to } => token.transfer(to, amount.to_owned(), &key).await,
+    };
+
+    match settle(id, &result) {
+        Some(true) => Ok(amount),
+        Some(false) => Err(OperationErr::TransferFailure),
+        None => Err(OperationErr::NotRetryable),
     }
+}
+
+// Settles a stuck operation whose outcome the owner looked up on the token's ledger (owner
+// only). `executed` tells whether the transfer of the operation was executed.
+#[update(name = "resolveOperation")]
+#[candid_method(update, rename = "resolveOperation")]
+pub fn resolve_operation(id: OperationId, executed: bool) -> OperationReceipt {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
 
-    Ok(amount.to_owned() + dip_fee)
+        assert!(state.owner.unwrap() == caller());
+        match state.journal.get(id) {
+            None => return Err(OperationErr::NotExistingOperation),
+            Some(o) if !journal::is_stuck(o, ic_cdk::api::time()) => {
+                return Err(OperationErr::NotStuck)
+            }
+            Some(_) => {}
+        }
+
+        let operation = state.journal.finish(id).unwrap();
+        let amount = operation.amount.to_owned();
+        apply(&mut state, operation, executed);
+        Ok(amount)
+    })
 }
 
 #[query]
@@ -340,8 +542,9 @@ pub fn clear() {
         let mut state = s.borrow_mut();
 
         assert!(state.owner.unwrap() == caller());
-        state.exchange.orders.clear();
-        state.exchange.balances.0.clear();
+        state.exchange.clear_orders();
+        state.exchange.clear_pools();
+        state.exchange.balances.clear();
     })
 }
 
@@ -354,10 +557,8 @@ fn init(ledger: Option<Principal>) {
     });
 }
 
-// NOTE: Converting and storing state like this should not be used in production.
-// If the state becomes too large, it can prevent future upgrades. This
-// is left in as a tool during development. If removed, native types
-// can be used throughout, instead.
+// Balances and orders live in stable memory and need no saving. Only the rest of the state
+// is saved, see `stable.rs`.
 #[pre_upgrade]
 fn pre_upgrade() {
     let state = STATE.with(|s| s.take());
@@ -365,20 +566,25 @@ fn pre_upgrade() {
     // Transform into stable state
     let stable_state: stable::StableState = state.into();
 
-    ic_cdk::storage::stable_save((stable_state,)).expect("failed to save stable state");
+    let bytes = candid::encode_one(&stable_state).expect("failed to save stable state");
+    memory::save_upgrade_bytes(&bytes);
 }
 
-// NOTE: Converting and storing state like this should not be used in production.
-// If the state becomes too large, it can prevent future upgrades. This
-// is left in as a tool during development. If removed, native types
-// can be used throughout, instead.
 #[post_upgrade]
 fn post_upgrade() {
-    let (stable_state,): (stable::StableState,) =
-        ic_cdk::storage::stable_restore().expect("failed to restore stable state");
-
-    // Transform from stable state
-    let state = stable_state.into();
+    // Versions before stable structures saved the whole state with `stable_save`. It must be
+    // read before the memory manager takes over the stable memory.
+    let state = if memory::is_legacy_layout() {
+        let (legacy_state,): (stable::LegacyState,) =
+            ic_cdk::storage::stable_restore().expect("failed to restore stable state");
+        State::try_from(legacy_state)
+    } else {
+        let bytes = memory::load_upgrade_bytes();
+        let stable_state: stable::StableState =
+            candid::decode_one(&bytes).expect("failed to restore stable state");
+        State::try_from(stable_state)
+    }
+    .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to restore stable state: {}", e)));
 
     STATE.with(|s| {
         s.replace(state);
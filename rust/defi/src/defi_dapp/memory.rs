@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+
+use candid::{Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, Storable};
+use num_bigint::BigUint;
+
+use crate::pool::Pool;
+use crate::types::{Fill, Order};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// The stable memory is split into virtual memories, one per stable structure. The state
+// that is not kept in a stable structure is saved to `UPGRADES` in `pre_upgrade`.
+pub const UPGRADES: MemoryId = MemoryId::new(0);
+pub const BALANCES: MemoryId = MemoryId::new(1);
+pub const ORDERS: MemoryId = MemoryId::new(2);
+pub const FILLS: MemoryId = MemoryId::new(3);
+pub const POOLS: MemoryId = MemoryId::new(4);
+pub const POOL_SHARES: MemoryId = MemoryId::new(5);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Writes `bytes` to the upgrades memory, prefixed with their length.
+pub fn save_upgrade_bytes(bytes: &[u8]) {
+    let mut memory = get(UPGRADES);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u32).to_le_bytes())
+        .and_then(|_| writer.write(bytes))
+        .expect("failed to grow the upgrades memory");
+}
+
+// Reads the bytes written by `save_upgrade_bytes`.
+pub fn load_upgrade_bytes() -> Vec<u8> {
+    let memory = get(UPGRADES);
+    let mut len = [0; 4];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    memory.read(4, &mut bytes);
+    bytes
+}
+
+// Whether the stable memory was written by `stable_save` of a version that did not use
+// stable structures yet. It must be checked before the memory manager is used.
+pub fn is_legacy_layout() -> bool {
+    if ic_cdk::api::stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 4];
+    ic_cdk::api::stable::stable64_read(0, &mut magic);
+    &magic == b"DIDL"
+}
+
+// A principal as a key of a stable structure. Keys are ordered by their bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrincipalKey(pub Principal);
+
+impl PrincipalKey {
+    // The smallest key, i.e. the one without bytes
+    pub const MIN: PrincipalKey = PrincipalKey(Principal::management_canister());
+}
+
+impl Ord for PrincipalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.as_slice().cmp(other.0.as_slice())
+    }
+}
+
+impl PartialOrd for PrincipalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(&bytes))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 29,
+        is_fixed_size: false,
+    };
+}
+
+// A `Nat` as a value of a stable structure, stored as its little-endian bytes.
+#[derive(Clone, Debug)]
+pub struct StableNat(pub Nat);
+
+impl Storable for StableNat {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0 .0.to_bytes_le())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StableNat(Nat(BigUint::from_bytes_le(&bytes)))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Order {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Order).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Fill {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Fill).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Pool {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Pool).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
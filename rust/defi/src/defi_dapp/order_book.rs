@@ -0,0 +1,217 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BTreeSet;
+
+use candid::Principal;
+
+use crate::types::*;
+
+// Prices are compared exactly by cross-multiplication, so 1/2 == 2/4.
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.quote.to_owned() * other.base.to_owned())
+            .cmp(&(other.quote.to_owned() * self.base.to_owned()))
+    }
+}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Price {}
+
+// Returns the pair of tokens an order trades. The token with the smaller principal is
+// the base token, the other one the quote token.
+pub fn pair_of(order: &Order) -> (Principal, Principal) {
+    pair(order.from, order.to)
+}
+
+// Returns the (base, quote) pair of two tokens, see `pair_of`.
+pub fn pair(token_a: Principal, token_b: Principal) -> (Principal, Principal) {
+    if token_a < token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    }
+}
+
+// Returns the price of an order in its pair, see `Price`.
+pub fn price_of(order: &Order) -> Price {
+    let (base, _) = pair_of(order);
+    if order.from == base {
+        price_of_ask(order)
+    } else {
+        price_of_bid(order)
+    }
+}
+
+// The open orders of one token pair, sorted by price and then by time (i.e. order id).
+// Asks sell the base token, bids buy it.
+pub struct OrderBook {
+    base: Principal,
+    asks: BTreeSet<(Price, OrderId)>,
+    bids: BTreeSet<(Reverse<Price>, OrderId)>,
+}
+
+impl OrderBook {
+    pub fn new(base: Principal) -> Self {
+        OrderBook {
+            base,
+            asks: BTreeSet::new(),
+            bids: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.asks.is_empty() && self.bids.is_empty()
+    }
+
+    pub fn insert(&mut self, order: &Order) {
+        if self.is_ask(order) {
+            self.asks.insert((price_of_ask(order), order.id));
+        } else {
+            self.bids.insert((Reverse(price_of_bid(order)), order.id));
+        }
+    }
+
+    pub fn remove(&mut self, order: &Order) {
+        if self.is_ask(order) {
+            self.asks.remove(&(price_of_ask(order), order.id));
+        } else {
+            self.bids.remove(&(Reverse(price_of_bid(order)), order.id));
+        }
+    }
+
+    // Returns the orders on the opposite side of the given order, best price first.
+    // Orders with the same price are returned in the order they were placed.
+    pub fn counter_orders(&self, order: &Order) -> Vec<OrderId> {
+        if self.is_ask(order) {
+            self.bids.iter().map(|(_, id)| *id).collect()
+        } else {
+            self.asks.iter().map(|(_, id)| *id).collect()
+        }
+    }
+
+    pub fn asks(&self) -> impl Iterator<Item = OrderId> + '_ {
+        self.asks.iter().map(|(_, id)| *id)
+    }
+
+    pub fn bids(&self) -> impl Iterator<Item = OrderId> + '_ {
+        self.bids.iter().map(|(_, id)| *id)
+    }
+
+    fn is_ask(&self, order: &Order) -> bool {
+        order.from == self.base
+    }
+}
+
+// An ask sells `fromAmount` of the base token for `toAmount` of the quote token.
+fn price_of_ask(order: &Order) -> Price {
+    Price {
+        quote: order.toAmount.to_owned(),
+        base: order.fromAmount.to_owned(),
+    }
+}
+
+// A bid sells `fromAmount` of the quote token for `toAmount` of the base token.
+fn price_of_bid(order: &Order) -> Price {
+    Price {
+        quote: order.fromAmount.to_owned(),
+        base: order.toAmount.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+
+    const BASE: u8 = 1;
+    const QUOTE: u8 = 2;
+
+    fn token(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn price(quote: u64, base: u64) -> Price {
+        Price {
+            quote: Nat::from(quote),
+            base: Nat::from(base),
+        }
+    }
+
+    fn order(id: OrderId, from: u8, from_amount: u64, to: u8, to_amount: u64) -> Order {
+        Order {
+            id,
+            owner: Principal::anonymous(),
+            from: token(from),
+            fromAmount: Nat::from(from_amount),
+            to: token(to),
+            toAmount: Nat::from(to_amount),
+            fee: Some(Nat::from(0u64)),
+        }
+    }
+
+    #[test]
+    fn prices_are_compared_exactly() {
+        assert_eq!(price(1, 2), price(2, 4));
+        assert!(price(2, 3) < price(3, 4));
+        assert!(price(10, 3) > price(3, 1));
+        assert_eq!(price(10, 3).cmp(&price(20, 6)), Ordering::Equal);
+    }
+
+    #[test]
+    fn price_of_order_is_quote_per_base() {
+        assert_eq!(price_of_ask(&order(0, BASE, 100, QUOTE, 200)), price(2, 1));
+        assert_eq!(price_of_bid(&order(1, QUOTE, 300, BASE, 100)), price(3, 1));
+    }
+
+    #[test]
+    fn asks_are_sorted_by_lowest_price_then_time() {
+        let mut book = OrderBook::new(token(BASE));
+        book.insert(&order(5, BASE, 100, QUOTE, 200));
+        book.insert(&order(3, BASE, 50, QUOTE, 100));
+        book.insert(&order(7, BASE, 100, QUOTE, 150));
+        book.insert(&order(4, BASE, 10, QUOTE, 30));
+
+        assert_eq!(book.asks().collect::<Vec<_>>(), vec![7, 3, 5, 4]);
+    }
+
+    #[test]
+    fn bids_are_sorted_by_highest_price_then_time() {
+        let mut book = OrderBook::new(token(BASE));
+        book.insert(&order(5, QUOTE, 200, BASE, 100));
+        book.insert(&order(3, QUOTE, 100, BASE, 50));
+        book.insert(&order(7, QUOTE, 300, BASE, 100));
+        book.insert(&order(4, QUOTE, 10, BASE, 10));
+
+        assert_eq!(book.bids().collect::<Vec<_>>(), vec![7, 3, 5, 4]);
+        assert_eq!(
+            book.counter_orders(&order(8, BASE, 100, QUOTE, 100)),
+            vec![7, 3, 5, 4]
+        );
+    }
+
+    #[test]
+    fn removed_orders_leave_the_book() {
+        let mut book = OrderBook::new(token(BASE));
+        let ask = order(1, BASE, 100, QUOTE, 200);
+        let bid = order(2, QUOTE, 100, BASE, 100);
+        book.insert(&ask);
+        book.insert(&bid);
+
+        book.remove(&ask);
+        assert_eq!(book.asks().count(), 0);
+        assert!(!book.is_empty());
+
+        book.remove(&bid);
+        assert!(book.is_empty());
+    }
+}
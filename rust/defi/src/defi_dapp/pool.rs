@@ -0,0 +1,231 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::StableBTreeMap;
+
+use crate::memory::{self, Memory, PrincipalKey, StableNat};
+use crate::types::*;
+use crate::utils::{self, BPS};
+
+// The fee of new pools in basis points of the swapped amount
+pub const DEFAULT_POOL_FEE_BPS: u32 = 30;
+
+// The shares that are burned when liquidity is first added to a pool. Since a pool can never
+// have fewer shares, a share cannot be made so expensive that later providers lose most of
+// their deposit to rounding.
+pub const MINIMUM_SHARES: u64 = 1_000;
+
+// A constant-product liquidity pool of one token pair: a swap keeps
+// `reserve_base * reserve_quote` constant, not counting the fee. The fee stays in the pool
+// and is shared by its liquidity providers in proportion to their shares, which are kept in
+// `Pools`.
+#[derive(CandidType, Clone, Deserialize)]
+pub struct Pool {
+    pub base: Principal,
+    pub quote: Principal,
+    pub reserve_base: Nat,
+    pub reserve_quote: Nat,
+    pub fee_bps: u32,
+    pub total_shares: Nat,
+}
+
+impl Pool {
+    pub fn new(base: Principal, quote: Principal, fee_bps: u32) -> Self {
+        Pool {
+            base,
+            quote,
+            reserve_base: utils::zero(),
+            reserve_quote: utils::zero(),
+            fee_bps,
+            total_shares: utils::zero(),
+        }
+    }
+
+    // Returns the shares for adding at most `max_base` and `max_quote` to the pool, and the
+    // amounts that are actually added: (shares, base amount, quote amount). Liquidity is
+    // added at the ratio of the reserves and the amounts are rounded up, so existing shares
+    // never lose value. The first liquidity provider sets the ratio and gets
+    // `sqrt(base amount * quote amount) - MINIMUM_SHARES` shares. Returns None if that is no
+    // shares at all.
+    pub fn liquidity_for(&self, max_base: Nat, max_quote: Nat) -> Option<(Nat, Nat, Nat)> {
+        if self.total_shares == utils::zero() {
+            let shares = Nat((max_base.to_owned() * max_quote.to_owned()).0.sqrt());
+            if shares <= MINIMUM_SHARES {
+                return None;
+            }
+            return Some((shares - Nat::from(MINIMUM_SHARES), max_base, max_quote));
+        }
+
+        let shares = std::cmp::min(
+            max_base * self.total_shares.to_owned() / self.reserve_base.to_owned(),
+            max_quote * self.total_shares.to_owned() / self.reserve_quote.to_owned(),
+        );
+        if shares == utils::zero() {
+            return None;
+        }
+
+        let base_amount = utils::div_ceil(
+            shares.to_owned() * self.reserve_base.to_owned(),
+            self.total_shares.to_owned(),
+        );
+        let quote_amount = utils::div_ceil(
+            shares.to_owned() * self.reserve_quote.to_owned(),
+            self.total_shares.to_owned(),
+        );
+        Some((shares, base_amount, quote_amount))
+    }
+
+    // Adds the amounts to the reserves and issues `shares`. The first liquidity also burns
+    // `MINIMUM_SHARES`, which are counted in the total but owned by no one.
+    fn add_liquidity(&mut self, shares: Nat, base_amount: Nat, quote_amount: Nat) {
+        if self.total_shares == utils::zero() {
+            self.total_shares += Nat::from(MINIMUM_SHARES);
+        }
+        self.reserve_base += base_amount;
+        self.reserve_quote += quote_amount;
+        self.total_shares += shares;
+    }
+
+    // Redeems `shares` and returns the amounts of both tokens they are worth, rounded down:
+    // (base amount, quote amount).
+    fn remove_liquidity(&mut self, shares: Nat) -> (Nat, Nat) {
+        let base_amount =
+            shares.to_owned() * self.reserve_base.to_owned() / self.total_shares.to_owned();
+        let quote_amount =
+            shares.to_owned() * self.reserve_quote.to_owned() / self.total_shares.to_owned();
+
+        self.reserve_base -= base_amount.to_owned();
+        self.reserve_quote -= quote_amount.to_owned();
+        self.total_shares -= shares;
+
+        (base_amount, quote_amount)
+    }
+
+    // Returns how much of the other token a swap of `amount` of `from` pays out, after the fee.
+    pub fn swap_output(&self, from: &Principal, amount: Nat) -> Nat {
+        let (reserve_in, reserve_out) = self.reserves(from);
+        if reserve_in == utils::zero() || reserve_out == utils::zero() {
+            return utils::zero();
+        }
+
+        let amount_with_fee = amount * (BPS - self.fee_bps);
+        amount_with_fee.to_owned() * reserve_out / (reserve_in * BPS + amount_with_fee)
+    }
+
+    pub fn swap(&mut self, from: &Principal, amount_in: Nat, amount_out: Nat) {
+        if *from == self.base {
+            self.reserve_base += amount_in;
+            self.reserve_quote -= amount_out;
+        } else {
+            self.reserve_quote += amount_in;
+            self.reserve_base -= amount_out;
+        }
+    }
+
+    pub fn snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            base: self.base,
+            quote: self.quote,
+            reserve_base: self.reserve_base.to_owned(),
+            reserve_quote: self.reserve_quote.to_owned(),
+            fee_bps: self.fee_bps,
+            total_shares: self.total_shares.to_owned(),
+        }
+    }
+
+    // Returns the reserves as (reserve of `from`, reserve of the other token).
+    fn reserves(&self, from: &Principal) -> (Nat, Nat) {
+        if *from == self.base {
+            (self.reserve_base.to_owned(), self.reserve_quote.to_owned())
+        } else {
+            (self.reserve_quote.to_owned(), self.reserve_base.to_owned())
+        }
+    }
+}
+
+// The liquidity pools and the shares of their providers, kept in stable memory
+pub struct Pools {
+    pools: StableBTreeMap<(PrincipalKey, PrincipalKey), Pool, Memory>, // (base, quote) -> pool
+    shares: StableBTreeMap<(PrincipalKey, PrincipalKey, PrincipalKey), StableNat, Memory>, // (base, quote, provider) -> shares
+}
+
+impl Default for Pools {
+    fn default() -> Self {
+        Pools {
+            pools: StableBTreeMap::init(memory::get(memory::POOLS)),
+            shares: StableBTreeMap::init(memory::get(memory::POOL_SHARES)),
+        }
+    }
+}
+
+impl Pools {
+    pub fn get(&self, base: Principal, quote: Principal) -> Option<Pool> {
+        self.pools.get(&(PrincipalKey(base), PrincipalKey(quote)))
+    }
+
+    pub fn insert(&mut self, pool: Pool) {
+        self.pools
+            .insert((PrincipalKey(pool.base), PrincipalKey(pool.quote)), pool);
+    }
+
+    pub fn shares_of(&self, base: Principal, quote: Principal, owner: &Principal) -> Nat {
+        self.shares
+            .get(&(
+                PrincipalKey(base),
+                PrincipalKey(quote),
+                PrincipalKey(*owner),
+            ))
+            .map_or(utils::zero(), |v| v.0)
+    }
+
+    // Adds liquidity to `pool`, see `Pool::liquidity_for`, and credits `shares` to `owner`.
+    pub fn add_liquidity(
+        &mut self,
+        mut pool: Pool,
+        owner: Principal,
+        shares: Nat,
+        base_amount: Nat,
+        quote_amount: Nat,
+    ) {
+        let owned = self.shares_of(pool.base, pool.quote, &owner);
+        self.set_shares(pool.base, pool.quote, owner, owned + shares.to_owned());
+        pool.add_liquidity(shares, base_amount, quote_amount);
+        self.insert(pool);
+    }
+
+    // Burns shares of `owner` and returns the amounts of both tokens they are worth, rounded
+    // down: (base amount, quote amount).
+    pub fn remove_liquidity(
+        &mut self,
+        base: Principal,
+        quote: Principal,
+        owner: Principal,
+        shares: Nat,
+    ) -> Result<(Nat, Nat), PoolErr> {
+        let mut pool = self
+            .get(base, quote)
+            .ok_or(PoolErr::InsufficientLiquidity)?;
+        let owned = self.shares_of(base, quote, &owner);
+        if shares == utils::zero() || shares > owned {
+            return Err(PoolErr::InvalidAmount);
+        }
+
+        let amounts = pool.remove_liquidity(shares.to_owned());
+        self.set_shares(base, quote, owner, owned - shares);
+        self.insert(pool);
+        Ok(amounts)
+    }
+
+    pub fn clear(&mut self) {
+        self.pools.clear_new();
+        self.shares.clear_new();
+    }
+
+    // Sets the shares of `owner`, removing the entry once they are zero.
+    fn set_shares(&mut self, base: Principal, quote: Principal, owner: Principal, shares: Nat) {
+        let key = (PrincipalKey(base), PrincipalKey(quote), PrincipalKey(owner));
+        if shares == utils::zero() {
+            self.shares.remove(&key);
+        } else {
+            self.shares.insert(key, StableNat(shares));
+        }
+    }
+}
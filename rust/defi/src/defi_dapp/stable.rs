@@ -1,121 +1,132 @@
-// NOTE: Converting and storing state like this should not be used in production.
-// If the state becomes too large, it can prevent future upgrades. This
-// is left in as a tool during development. If removed, native types
-// can be used throughout, instead.
+// Balances, orders, liquidity pools and the trade history are kept in stable structures, see
+// `memory.rs`. The rest of the state is converted to the types below and saved to stable memory
+// in `pre_upgrade`.
 
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
 
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use serde::{Deserialize, Serialize};
 
-use crate::exchange::{Balances, Exchange};
+use crate::exchange::Exchange;
+use crate::journal::Journal;
+use crate::token::TokenStandard;
 use crate::types::*;
-use crate::{OrderId, State};
+use crate::{utils, OrderId, State};
 
-#[derive(CandidType, Clone, Deserialize, Serialize)]
-pub struct StableOrder {
-    pub id: OrderId,
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct StableOperation {
+    pub id: OperationId,
+    pub kind: OperationKind,
     pub owner: Principal,
-    pub from: Principal,
-    pub from_amount: String,
-    pub to: Principal,
-    pub to_amount: String,
+    pub token: Principal,
+    pub amount: String,
+    pub created_at: u64,
 }
 
-impl From<Order> for StableOrder {
-    fn from(input: Order) -> Self {
-        StableOrder {
+impl From<Operation> for StableOperation {
+    fn from(input: Operation) -> Self {
+        StableOperation {
             id: input.id,
+            kind: input.kind,
             owner: input.owner,
-            from: input.from,
-            from_amount: input.fromAmount.to_string(),
-            to: input.to,
-            to_amount: input.toAmount.to_string(),
+            token: input.token,
+            amount: input.amount.to_string(),
+            created_at: input.created_at,
         }
     }
 }
 
-impl From<StableOrder> for Order {
-    fn from(input: StableOrder) -> Self {
-        Order {
+// Fails with the ID of the operation if its amount is not a valid number.
+impl TryFrom<StableOperation> for Operation {
+    type Error = String;
+
+    fn try_from(input: StableOperation) -> Result<Self, Self::Error> {
+        let amount = input
+            .amount
+            .parse::<Nat>()
+            .map_err(|e| format!("invalid amount of operation {}: {}", input.id, e))?;
+
+        Ok(Operation {
             id: input.id,
+            kind: input.kind,
             owner: input.owner,
-            from: input.from,
-            fromAmount: input.from_amount.parse().unwrap(),
-            to: input.to,
-            toAmount: input.to_amount.parse().unwrap(),
-        }
+            token: input.token,
+            amount,
+            created_at: input.created_at,
+        })
     }
 }
 
 #[derive(CandidType, Deserialize, Serialize)]
-pub struct StableBalances(pub HashMap<Principal, HashMap<Principal, String>>); // owner -> token_canister_id -> amount
+pub struct StableJournal {
+    pub next_id: OperationId,
+    pub operations: Vec<StableOperation>,
+}
 
-impl From<Balances> for StableBalances {
-    fn from(input: Balances) -> Self {
-        StableBalances(
-            input
-                .0
-                .into_iter()
-                .map(|(k, v)| (k, v.into_iter().map(|(k, v)| (k, v.to_string())).collect()))
-                .collect(),
-        )
+impl From<Journal> for StableJournal {
+    fn from(input: Journal) -> Self {
+        StableJournal {
+            next_id: input.next_id,
+            operations: input.operations.into_values().map(|v| v.into()).collect(),
+        }
     }
 }
 
-impl From<StableBalances> for Balances {
-    fn from(input: StableBalances) -> Self {
-        Balances(
-            input
-                .0
+impl TryFrom<StableJournal> for Journal {
+    type Error = String;
+
+    fn try_from(input: StableJournal) -> Result<Self, Self::Error> {
+        Ok(Journal {
+            next_id: input.next_id,
+            operations: input
+                .operations
                 .into_iter()
-                .map(|(k, v)| {
-                    (
-                        k,
-                        v.into_iter()
-                            .map(|(k, v)| (k, v.parse().unwrap()))
-                            .collect(),
-                    )
-                })
-                .collect(),
-        )
+                .map(|v| Ok((v.id, v.try_into()?)))
+                .collect::<Result<_, String>>()?,
+        })
     }
 }
 
-type StableOrders = HashMap<OrderId, StableOrder>;
-
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct StableExchange {
     pub next_id: OrderId,
-    pub balances: StableBalances,
-    pub orders: StableOrders,
+    // (base, quote, fees), None when upgrading from a version without trading fees
+    pub fees: Option<Vec<(Principal, Principal, TradingFees)>>,
 }
 
 impl From<Exchange> for StableExchange {
     fn from(input: Exchange) -> Self {
         StableExchange {
             next_id: input.next_id,
-            balances: input.balances.into(),
-            orders: input
-                .orders
-                .into_iter()
-                .map(|(k, v)| (k, v.into()))
-                .collect(),
+            fees: Some(
+                input
+                    .fees
+                    .into_iter()
+                    .map(|((base, quote), fees)| (base, quote, fees))
+                    .collect(),
+            ),
         }
     }
 }
 
-impl From<StableExchange> for Exchange {
-    fn from(input: StableExchange) -> Self {
-        Exchange {
+impl TryFrom<StableExchange> for Exchange {
+    type Error = String;
+
+    fn try_from(input: StableExchange) -> Result<Self, Self::Error> {
+        let mut exchange = Exchange {
             next_id: input.next_id,
-            balances: input.balances.into(),
-            orders: input
-                .orders
+            fees: input
+                .fees
+                .unwrap_or_default()
                 .into_iter()
-                .map(|(k, v)| (k, v.into()))
+                .map(|(base, quote, fees)| ((base, quote), fees))
                 .collect(),
-        }
+            ..Default::default()
+        };
+        exchange.rebuild_books();
+        exchange.history.rebuild_indexes();
+        Ok(exchange)
     }
 }
 
@@ -123,7 +134,9 @@ impl From<StableExchange> for Exchange {
 pub struct StableState {
     owner: Option<Principal>,
     ledger: Option<Principal>,
+    tokens: HashMap<Principal, TokenStandard>,
     exchange: StableExchange,
+    journal: StableJournal,
 }
 
 impl From<State> for StableState {
@@ -131,17 +144,178 @@ impl From<State> for StableState {
         StableState {
             owner: input.owner,
             ledger: input.ledger,
+            tokens: input.tokens,
             exchange: input.exchange.into(),
+            journal: input.journal.into(),
         }
     }
 }
 
-impl From<StableState> for State {
-    fn from(input: StableState) -> Self {
-        State {
+impl TryFrom<StableState> for State {
+    type Error = String;
+
+    fn try_from(input: StableState) -> Result<Self, Self::Error> {
+        Ok(State {
             owner: input.owner,
             ledger: input.ledger,
-            exchange: input.exchange.into(),
+            tokens: input.tokens,
+            exchange: input.exchange.try_into()?,
+            journal: input.journal.try_into()?,
+        })
+    }
+}
+
+// The state as saved with `stable_save` by versions that did not use stable structures.
+// It is only read once, when upgrading from such a version.
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct StableOrder {
+    pub id: OrderId,
+    pub owner: Principal,
+    pub from: Principal,
+    pub from_amount: String,
+    pub to: Principal,
+    pub to_amount: String,
+}
+
+// Fails with the field and the ID of the order if an amount is not a valid number.
+impl TryFrom<StableOrder> for Order {
+    type Error = String;
+
+    fn try_from(input: StableOrder) -> Result<Self, Self::Error> {
+        let parse = |field: &str, value: &str| {
+            value
+                .parse::<Nat>()
+                .map_err(|e| format!("invalid {} of order {}: {}", field, input.id, e))
+        };
+
+        Ok(Order {
+            id: input.id,
+            owner: input.owner,
+            from: input.from,
+            fromAmount: parse("from_amount", &input.from_amount)?,
+            to: input.to,
+            toAmount: parse("to_amount", &input.to_amount)?,
+            fee: Some(utils::zero()),
+        })
+    }
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct StableFill {
+    pub id: FillId,
+    pub timestamp: u64,
+    pub base: Principal,
+    pub quote: Principal,
+    pub taker: Principal,
+    pub taker_order: OrderId,
+    pub taker_side: Side,
+    pub maker: Principal,
+    pub maker_order: OrderId,
+    pub base_amount: String,
+    pub quote_amount: String,
+    pub price_quote: String,
+    pub price_base: String,
+    // None when upgrading from a version without trading fees
+    pub maker_fee: Option<String>,
+    pub taker_fee: Option<String>,
+}
+
+// Fails with the field and the ID of the fill if an amount is not a valid number.
+impl TryFrom<StableFill> for Fill {
+    type Error = String;
+
+    fn try_from(input: StableFill) -> Result<Self, Self::Error> {
+        let parse = |field: &str, value: &str| {
+            value
+                .parse::<Nat>()
+                .map_err(|e| format!("invalid {} of fill {}: {}", field, input.id, e))
+        };
+        let parse_fee = |field: &str, value: &Option<String>| {
+            value
+                .as_ref()
+                .map_or(Ok(utils::zero()), |v| parse(field, v))
+        };
+
+        Ok(Fill {
+            id: input.id,
+            timestamp: input.timestamp,
+            base: input.base,
+            quote: input.quote,
+            taker: input.taker,
+            taker_order: input.taker_order,
+            taker_side: input.taker_side.clone(),
+            maker: input.maker,
+            maker_order: input.maker_order,
+            base_amount: parse("base_amount", &input.base_amount)?,
+            quote_amount: parse("quote_amount", &input.quote_amount)?,
+            price: Price {
+                quote: parse("price_quote", &input.price_quote)?,
+                base: parse("price_base", &input.price_base)?,
+            },
+            maker_fee: parse_fee("maker_fee", &input.maker_fee)?,
+            taker_fee: parse_fee("taker_fee", &input.taker_fee)?,
+        })
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct StableBalances(pub HashMap<Principal, HashMap<Principal, String>>); // owner -> token_canister_id -> amount
+
+type StableOrders = HashMap<OrderId, StableOrder>;
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct LegacyExchange {
+    pub next_id: OrderId,
+    pub balances: StableBalances,
+    pub orders: StableOrders,
+    pub fills: Option<Vec<StableFill>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct LegacyState {
+    owner: Option<Principal>,
+    ledger: Option<Principal>,
+    tokens: Option<HashMap<Principal, TokenStandard>>,
+    exchange: LegacyExchange,
+    journal: Option<StableJournal>,
+}
+
+// Moves the balances, orders and trade history into the stable structures, failing if an
+// amount is not a valid number.
+impl TryFrom<LegacyState> for State {
+    type Error = String;
+
+    fn try_from(input: LegacyState) -> Result<Self, Self::Error> {
+        let mut exchange = Exchange {
+            next_id: input.exchange.next_id,
+            ..Default::default()
+        };
+        for fill in input.exchange.fills.unwrap_or_default() {
+            exchange.history.record(fill.try_into()?);
         }
+        for (owner, balances) in input.exchange.balances.0 {
+            for (token, amount) in balances {
+                let amount = amount.parse::<Nat>().map_err(|e| {
+                    format!("invalid balance of {} in token {}: {}", owner, token, e)
+                })?;
+                exchange.balances.add_balance(&owner, &token, amount);
+            }
+        }
+        for order in input.exchange.orders.into_values() {
+            exchange.insert_order(order.try_into()?);
+        }
+
+        Ok(State {
+            owner: input.owner,
+            ledger: input.ledger,
+            tokens: input.tokens.unwrap_or_default(),
+            exchange,
+            journal: input
+                .journal
+                .map(Journal::try_from)
+                .transpose()?
+                .unwrap_or_default(),
+        })
     }
 }
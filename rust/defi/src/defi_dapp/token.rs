@@ -0,0 +1,224 @@
+use std::fmt;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+use serde::Serialize;
+
+use crate::dip20::DIP20;
+use crate::icp::IcpLedger;
+use crate::icrc::Icrc;
+use crate::utils;
+
+// The interface a token canister implements. `Icp` is the ICP ledger's own interface.
+// Deposits of `Icp` and `Icrc1` tokens are transferred to the user's deposit subaccount of
+// the exchange first, deposits of `Dip20` and `Icrc2` tokens are approved to the exchange.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum TokenStandard {
+    Icp,
+    Dip20,
+    Icrc1,
+    Icrc2,
+}
+
+#[derive(Debug)]
+pub enum TokenErr {
+    // The call to the token canister was rejected with the given code. Whether the call was
+    // executed depends on the code, see `journal::executed`.
+    CallFailed(RejectionCode, String),
+    // The token canister refused the transfer
+    Rejected(String),
+    // The ledger already executed a transfer with the same `TransferKey`
+    Duplicate,
+    // The `TransferKey` is too old for the ledger to tell whether it executed the transfer
+    TooOld,
+    // The operation is not part of the token's standard
+    NotSupported,
+}
+
+impl fmt::Display for TokenErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenErr::CallFailed(code, message) => {
+                write!(f, "call failed: {:?}: {}", code, message)
+            }
+            TokenErr::Rejected(message) => write!(f, "rejected: {}", message),
+            TokenErr::Duplicate => write!(f, "duplicate transfer"),
+            TokenErr::TooOld => write!(f, "transfer too old"),
+            TokenErr::NotSupported => write!(f, "not supported"),
+        }
+    }
+}
+
+impl From<(RejectionCode, String)> for TokenErr {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        TokenErr::CallFailed(code, message)
+    }
+}
+
+// Identifies a transfer. Ledgers that deduplicate transfers execute a transfer only once,
+// even if it is sent again with the same key.
+pub struct TransferKey {
+    pub memo: u64,
+    pub created_at_time: u64,
+}
+
+// What the exchange needs from a token canister. Amounts are in the token's smallest unit
+// and accounts are the default accounts of the principals, except where noted.
+pub trait TokenLedger {
+    async fn symbol(&self) -> Result<String, TokenErr>;
+
+    async fn fee(&self) -> Result<Nat, TokenErr>;
+
+    // Whether sending a transfer again with the same `TransferKey` is safe, see `TransferKey`.
+    fn deduplicates(&self) -> bool {
+        true
+    }
+
+    // Transfers `amount` from the exchange to `to`. The fee is paid by the exchange.
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr>;
+
+    // Returns how much the exchange may transfer from `owner`.
+    async fn allowance(&self, owner: Principal) -> Result<Nat, TokenErr>;
+
+    // Transfers `amount` from `owner` to the exchange, within the allowance.
+    async fn transfer_from(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr>;
+
+    // Returns how much of the deposit of `owner` the exchange can collect, after the fee. By
+    // default, the deposit is what `owner` approved, see `approved_deposit`.
+    async fn available_deposit(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        approved_deposit(self, owner).await
+    }
+
+    // Moves `amount` of the deposit of `owner` to the exchange.
+    async fn collect_deposit(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        self.transfer_from(owner, amount, key).await
+    }
+}
+
+// Returns what `owner` approved to the exchange, minus the fee of the transfer.
+pub async fn approved_deposit<T: TokenLedger + ?Sized>(
+    token: &T,
+    owner: Principal,
+) -> Result<Nat, TokenErr> {
+    let fee = token.fee().await?;
+    let allowance = token.allowance(owner).await?;
+    if allowance <= fee {
+        return Ok(utils::zero());
+    }
+
+    Ok(allowance - fee)
+}
+
+// A token canister of any of the supported standards.
+pub enum Token {
+    Icp(IcpLedger),
+    Dip20(DIP20),
+    Icrc(Icrc),
+}
+
+impl Token {
+    pub fn new(standard: TokenStandard, principal: Principal) -> Self {
+        match standard {
+            TokenStandard::Icp => Token::Icp(IcpLedger::new(principal)),
+            TokenStandard::Dip20 => Token::Dip20(DIP20::new(principal)),
+            TokenStandard::Icrc1 => Token::Icrc(Icrc::new(principal, false)),
+            TokenStandard::Icrc2 => Token::Icrc(Icrc::new(principal, true)),
+        }
+    }
+}
+
+impl TokenLedger for Token {
+    async fn symbol(&self) -> Result<String, TokenErr> {
+        match self {
+            Token::Icp(token) => token.symbol().await,
+            Token::Dip20(token) => TokenLedger::symbol(token).await,
+            Token::Icrc(token) => token.symbol().await,
+        }
+    }
+
+    async fn fee(&self) -> Result<Nat, TokenErr> {
+        match self {
+            Token::Icp(token) => token.fee().await,
+            Token::Dip20(token) => TokenLedger::fee(token).await,
+            Token::Icrc(token) => token.fee().await,
+        }
+    }
+
+    fn deduplicates(&self) -> bool {
+        match self {
+            Token::Icp(token) => token.deduplicates(),
+            Token::Dip20(token) => token.deduplicates(),
+            Token::Icrc(token) => token.deduplicates(),
+        }
+    }
+
+    async fn transfer(
+        &self,
+        to: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        match self {
+            Token::Icp(token) => token.transfer(to, amount, key).await,
+            Token::Dip20(token) => TokenLedger::transfer(token, to, amount, key).await,
+            Token::Icrc(token) => token.transfer(to, amount, key).await,
+        }
+    }
+
+    async fn allowance(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        match self {
+            Token::Icp(token) => token.allowance(owner).await,
+            Token::Dip20(token) => TokenLedger::allowance(token, owner).await,
+            Token::Icrc(token) => token.allowance(owner).await,
+        }
+    }
+
+    async fn transfer_from(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        match self {
+            Token::Icp(token) => token.transfer_from(owner, amount, key).await,
+            Token::Dip20(token) => TokenLedger::transfer_from(token, owner, amount, key).await,
+            Token::Icrc(token) => token.transfer_from(owner, amount, key).await,
+        }
+    }
+
+    async fn available_deposit(&self, owner: Principal) -> Result<Nat, TokenErr> {
+        match self {
+            Token::Icp(token) => token.available_deposit(owner).await,
+            Token::Dip20(token) => token.available_deposit(owner).await,
+            Token::Icrc(token) => token.available_deposit(owner).await,
+        }
+    }
+
+    async fn collect_deposit(
+        &self,
+        owner: Principal,
+        amount: Nat,
+        key: &TransferKey,
+    ) -> Result<Nat, TokenErr> {
+        match self {
+            Token::Icp(token) => token.collect_deposit(owner, amount, key).await,
+            Token::Dip20(token) => token.collect_deposit(owner, amount, key).await,
+            Token::Icrc(token) => token.collect_deposit(owner, amount, key).await,
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use candid::{CandidType, Nat, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
 
 pub type OrderId = u32;
 
 #[allow(non_snake_case)]
-#[derive(CandidType, Clone)]
+#[derive(CandidType, Clone, Deserialize)]
 pub struct Order {
     pub id: OrderId,
     pub owner: Principal,
@@ -11,6 +12,100 @@ pub struct Order {
     pub fromAmount: Nat,
     pub to: Principal,
     pub toAmount: Nat,
+    // The trading fees the owner paid so far, in the `to` token. None for orders placed
+    // before trading fees were introduced.
+    pub fee: Option<Nat>,
+}
+
+// The trading fees of a token pair in basis points of the amount a party receives. The maker
+// is the order that was already in the order book, the taker the new order.
+#[derive(CandidType, Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct TradingFees {
+    pub maker_bps: u32,
+    pub taker_bps: u32,
+}
+
+// The price of an order or trade in units of the quote token per unit of the base token
+// of its pair, i.e. `quote / base`. The token with the smaller principal is the base token.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct Price {
+    pub quote: Nat,
+    pub base: Nat,
+}
+
+// The open orders of a token pair. Asks sell the base token, bids buy it. Both are sorted
+// by price, best first, and then by the time they were placed.
+#[derive(CandidType)]
+pub struct OrderBookSnapshot {
+    pub base: Principal,
+    pub quote: Principal,
+    pub asks: Vec<Order>,
+    pub bids: Vec<Order>,
+}
+
+pub type FillId = u64;
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+// A trade between a new order (the taker) and an order that was already in the order
+// book (the maker). The trade is executed at the maker's price. The amounts are what the
+// two orders paid; each side received them minus its fee, in the token it bought.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct Fill {
+    pub id: FillId,
+    pub timestamp: u64,
+    pub base: Principal,
+    pub quote: Principal,
+    pub taker: Principal,
+    pub taker_order: OrderId,
+    // Whether the taker bought or sold the base token
+    pub taker_side: Side,
+    pub maker: Principal,
+    pub maker_order: OrderId,
+    pub base_amount: Nat,
+    pub quote_amount: Nat,
+    pub price: Price,
+    pub maker_fee: Nat,
+    pub taker_fee: Nat,
+}
+
+// The trades of a token pair within one interval. Prices are the prices of the first,
+// highest, lowest and last trade, volumes the traded amounts of both tokens.
+#[derive(CandidType, Clone, Debug)]
+pub struct Candle {
+    pub start: u64,
+    pub open: Price,
+    pub high: Price,
+    pub low: Price,
+    pub close: Price,
+    pub base_volume: Nat,
+    pub quote_volume: Nat,
+    pub trades: u64,
+}
+
+pub type OperationId = u64;
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub enum OperationKind {
+    Deposit,
+    Withdraw { to: Principal },
+}
+
+// A deposit or withdrawal whose transfer was sent to the token canister, but whose outcome
+// was not applied to the balances yet.
+#[derive(CandidType, Clone, Debug)]
+pub struct Operation {
+    pub id: OperationId,
+    pub kind: OperationKind,
+    pub owner: Principal,
+    pub token: Principal,
+    // The amount credited to (deposits) or debited from (withdrawals) the owner's balance
+    pub amount: Nat,
+    pub created_at: u64,
 }
 
 #[derive(CandidType)]
@@ -20,6 +115,22 @@ pub struct Balance {
     pub amount: Nat,
 }
 
+#[derive(CandidType)]
+pub struct PoolSnapshot {
+    pub base: Principal,
+    pub quote: Principal,
+    pub reserve_base: Nat,
+    pub reserve_quote: Nat,
+    pub fee_bps: u32,
+    pub total_shares: Nat,
+}
+
+#[derive(CandidType)]
+pub struct PoolAmounts {
+    pub base: Nat,
+    pub quote: Nat,
+}
+
 pub type CancelOrderReceipt = Result<OrderId, CancelOrderErr>;
 
 #[derive(CandidType)]
@@ -51,3 +162,26 @@ pub enum WithdrawErr {
     BalanceLow,
     TransferFailure,
 }
+
+pub type OperationReceipt = Result<Nat, OperationErr>;
+
+#[derive(CandidType)]
+pub enum OperationErr {
+    NotExistingOperation,
+    NotStuck,
+    NotRetryable,
+    TransferFailure,
+}
+
+pub type AddLiquidityReceipt = Result<Nat, PoolErr>;
+pub type RemoveLiquidityReceipt = Result<PoolAmounts, PoolErr>;
+pub type SwapReceipt = Result<Nat, PoolErr>;
+pub type PoolFeeReceipt = Result<(), PoolErr>;
+
+#[derive(CandidType, Debug)]
+pub enum PoolErr {
+    InvalidAmount,
+    BalanceLow,
+    InsufficientLiquidity,
+    SlippageExceeded,
+}
@@ -5,10 +5,18 @@ use ic_ledger_types::Subaccount;
 use num_bigint::BigUint;
 use num_traits::Zero;
 
+// Fees are in basis points, i.e. 1/10000
+pub const BPS: u32 = 10_000;
+
 pub fn zero() -> Nat {
     Nat(BigUint::zero())
 }
 
+// Divides and rounds the result up.
+pub fn div_ceil(dividend: Nat, divisor: Nat) -> Nat {
+    (dividend + divisor.to_owned() - 1u32) / divisor
+}
+
 pub fn principal_to_subaccount(principal_id: &Principal) -> Subaccount {
     let mut subaccount = [0; std::mem::size_of::<Subaccount>()];
     let principal_id = principal_id.as_slice();
@@ -61,6 +61,24 @@ echo "expect user2 96 GoldenDIP20"
 dfx canister call defi_dapp getAllBalances | grep -B1 -A2 $GoldenDIP20 | grep -A2 $USER2 | grep "amount = 96"
 echo "expect user2 2 AkitaDIP20"
 dfx canister call defi_dapp getAllBalances | grep -B1 -A2 $AkitaDIP20 | grep -A2 $USER2 | grep "amount = 2"
+echo "testing ICRC-1 deposits"
+dfx identity use default
+export ICRC1=$(dfx canister id icrc1_ledger)
+dfx canister call defi_dapp clear
+dfx canister call defi_dapp registerToken "(principal \"${ICRC1}\", variant { Icrc1 })"
+export ICRC1_DEPOSIT_ACCOUNT=$(dfx canister call defi_dapp getDepositAccount | tr -d '\n' | sed 's/,)/)/')
+dfx canister call icrc1_ledger icrc1_transfer "(record { to = $ICRC1_DEPOSIT_ACCOUNT; amount = 1_000_000 })"
+echo "Check that the deposit is credited less the fee of sweeping the deposit account"
+dfx canister call defi_dapp deposit "(principal \"${ICRC1}\")" | grep "Ok = 990_000 :"
+dfx canister call defi_dapp getBalance "(principal \"${ICRC1}\")" | grep "(990_000 : nat)"
+echo "testing a failed withdrawal"
+dfx identity use default
+dfx canister call defi_dapp clear
+dfx canister call defi_dapp credit "(principal \"${USER1}\", principal \"${AkitaDIP20}\", 1_000_000_000_000: nat)"
+dfx identity use user1
+echo "Check that a withdrawal the token canister rejects, for lack of funds of the exchange, is refunded"
+dfx canister call defi_dapp withdraw "(principal \"${AkitaDIP20}\", 999_999_000_000: nat, principal \"${USER1}\")" | grep TransferFailure
+dfx canister call defi_dapp getBalance "(principal \"${AkitaDIP20}\")" | grep "(1_000_000_000_000 : nat)"
 echo "testing imbalanced trades"
 dfx identity use default
 dfx canister call defi_dapp clear
@@ -81,9 +99,9 @@ dfx identity use user1
 dfx canister call defi_dapp placeOrder "(principal \"${AkitaDIP20}\" : principal, 9: nat, principal \"${GoldenDIP20}\", 3: nat)"
 dfx canister call defi_dapp getOrders
 dfx canister call defi_dapp getAllBalances
-echo "Check that it partially executed"
-dfx canister call defi_dapp getAllBalances | grep "amount = 5"
-echo "testing imbalanced trades with non-natural number trade amounts"
+echo "Check that it partially executed at the price of user2's order: user1 keeps 8 AkitaDIP20"
+dfx canister call defi_dapp getAllBalances | grep -B1 -A2 $AkitaDIP20 | grep -A2 $USER1 | grep "amount = 8"
+echo "testing imbalanced trades with a different limit price"
 dfx identity use default
 dfx canister call defi_dapp clear
 dfx identity use user1
@@ -103,7 +121,75 @@ dfx identity use user1
 dfx canister call defi_dapp placeOrder "(principal \"${AkitaDIP20}\" : principal, 9: nat, principal \"${GoldenDIP20}\", 4: nat)"
 dfx canister call defi_dapp getOrders
 dfx canister call defi_dapp getAllBalances
-echo "Check that it did not execute"
-dfx canister call defi_dapp getAllBalances | grep "amount = 9"
+echo "Check that it executed at the price of user2's order and the rest stays open"
+dfx canister call defi_dapp getAllBalances | grep -B1 -A2 $AkitaDIP20 | grep -A2 $USER1 | grep "amount = 8"
+dfx canister call defi_dapp getOrderBook "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\")"
+dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, null)"
+echo "Check that the last trade is user1 selling 1 AkitaDIP20 to user2 for 2 GoldenDIP20"
+dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, opt 1)" | grep "taker = principal \"${USER1}\""
+dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, opt 1)" | grep "maker = principal \"${USER2}\""
+dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, opt 1)" | egrep "(base|quote)_amount = 1 :"
+dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, opt 1)" | egrep "(base|quote)_amount = 2 :"
+echo "Check that the trade is the last fill of user1 and of user2"
+dfx canister call defi_dapp getFills "(principal \"${USER1}\", null, opt 1)" | grep "maker = principal \"${USER2}\""
+dfx canister call defi_dapp getFills "(principal \"${USER2}\", null, opt 1)" | grep "taker = principal \"${USER1}\""
+dfx canister call defi_dapp getCandles "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", 3_600_000_000_000, null, null)"
+echo "Check that a candle starting at the last trade holds only that trade"
+export LAST_TRADE=$(dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, opt 1)" | grep "timestamp = " | sed -E 's/.*timestamp = ([0-9_]+) .*/\1/')
+dfx canister call defi_dapp getCandles "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", 1, opt ${LAST_TRADE}, null)" | grep "trades = 1 :"
+dfx canister call defi_dapp getCandles "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", 1, opt ${LAST_TRADE}, null)" | egrep "(base|quote)_volume = 1 :"
+dfx canister call defi_dapp getCandles "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", 1, opt ${LAST_TRADE}, null)" | egrep "(base|quote)_volume = 2 :"
+echo "testing upgrades"
+dfx identity use default
+dfx deploy defi_dapp --upgrade-unchanged --argument "(opt principal \"$(dfx canister id ledger)\")"
+echo "Check that balances, open orders, registered tokens and the trade history survive the upgrade"
+dfx canister call defi_dapp getTokenStandard "(principal \"${ICRC1}\")" | grep Icrc1
+dfx identity use user1
+dfx canister call defi_dapp getBalance "(principal \"${AkitaDIP20}\")" | grep "(8 : nat)"
+dfx canister call defi_dapp getOrders | grep "fromAmount = 8 :"
+dfx canister call defi_dapp getOrderBook "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\")" | grep "owner = principal \"${USER1}\""
+dfx canister call defi_dapp getTradeHistory "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", null, opt 1)" | grep "maker = principal \"${USER2}\""
+dfx canister call defi_dapp getFills "(principal \"${USER1}\", null, opt 1)" | grep "maker = principal \"${USER2}\""
+echo "testing trading fees"
+dfx identity use default
+dfx canister call defi_dapp clear
+dfx canister call defi_dapp setTradingFees "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", record { maker_bps = 100: nat32; taker_bps = 200: nat32 })"
+dfx canister call defi_dapp credit "(principal \"${USER1}\", principal \"${AkitaDIP20}\", 100: nat)"
+dfx canister call defi_dapp credit "(principal \"${USER2}\", principal \"${GoldenDIP20}\", 100: nat)"
+dfx identity use user2
+dfx canister call defi_dapp placeOrder "(principal \"${GoldenDIP20}\" : principal, 100: nat, principal \"${AkitaDIP20}\", 100: nat)"
+dfx identity use user1
+dfx canister call defi_dapp placeOrder "(principal \"${AkitaDIP20}\" : principal, 100: nat, principal \"${GoldenDIP20}\", 100: nat)"
+echo "Check that the taker paid 2% and the maker 1% of what they received"
+dfx canister call defi_dapp getBalance "(principal \"${GoldenDIP20}\")" | grep "(98 : nat)"
+dfx identity use user2
+dfx canister call defi_dapp getBalance "(principal \"${AkitaDIP20}\")" | grep "(99 : nat)"
+dfx identity use default
+dfx canister call defi_dapp getCollectedFees
+echo "Check that the fee ledger holds 2 GoldenDIP20 and 1 AkitaDIP20"
+dfx canister call defi_dapp getCollectedFees | grep -B1 -A2 $GoldenDIP20 | grep "amount = 2 :"
+dfx canister call defi_dapp getCollectedFees | grep -B1 -A2 $AkitaDIP20 | grep "amount = 1 :"
+dfx canister call defi_dapp setTradingFees "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", record { maker_bps = 0: nat32; taker_bps = 0: nat32 })"
+echo "testing the liquidity pool"
+dfx identity use default
+dfx canister call defi_dapp clear
+dfx canister call defi_dapp credit "(principal \"${USER1}\", principal \"${AkitaDIP20}\", 2000: nat)"
+dfx canister call defi_dapp credit "(principal \"${USER1}\", principal \"${GoldenDIP20}\", 2000: nat)"
+dfx canister call defi_dapp credit "(principal \"${USER2}\", principal \"${GoldenDIP20}\", 100: nat)"
+dfx identity use user1
+echo "Check that a first deposit worth no more than the locked minimum shares fails"
+dfx canister call defi_dapp addLiquidity "(principal \"${AkitaDIP20}\", 1000: nat, principal \"${GoldenDIP20}\", 1000: nat)" | grep InvalidAmount
+echo "Check that the first deposit gets sqrt(2000 * 2000) shares less the 1000 locked ones"
+dfx canister call defi_dapp addLiquidity "(principal \"${AkitaDIP20}\", 2000: nat, principal \"${GoldenDIP20}\", 2000: nat)" | grep "Ok = 1_000"
+dfx canister call defi_dapp getPool "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\")"
+dfx identity use user2
+dfx canister call defi_dapp getSwapQuote "(principal \"${GoldenDIP20}\", 100: nat, principal \"${AkitaDIP20}\")"
+echo "Check that a swap below the minimum amount fails"
+dfx canister call defi_dapp swap "(principal \"${GoldenDIP20}\", 100: nat, principal \"${AkitaDIP20}\", 95: nat)" | grep SlippageExceeded
+echo "Check that the swap pays out 100 * 0.997 * 2000 / (2000 + 100 * 0.997) AkitaDIP20"
+dfx canister call defi_dapp swap "(principal \"${GoldenDIP20}\", 100: nat, principal \"${AkitaDIP20}\", 94: nat)" | grep "Ok = 94"
+dfx identity use user1
+dfx canister call defi_dapp removeLiquidity "(principal \"${AkitaDIP20}\", principal \"${GoldenDIP20}\", 1000: nat)"
+dfx canister call defi_dapp getAllBalances
 dfx identity use default
 echo "PASS"
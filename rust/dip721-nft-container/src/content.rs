@@ -0,0 +1,226 @@
+// Storage of large NFT content in stable memory, uploaded in chunks.
+//
+// Stable memory layout:
+// [0, 16)                 header: MAGIC, then the offset of the upgrade state
+// [16, content end)       committed and reserved content, in upload order
+// [content end, ...)      the upgrade state, length-prefixed, written by pre_upgrade
+//
+// Content is immutable once committed, so it is never copied on upgrade. Uploads only live on the heap,
+// so uploads that are not committed before an upgrade are discarded, and the space they reserved is lost.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use candid::CandidType;
+use ic_cdk::{
+    api::{self, stable},
+    export::candid,
+};
+use ic_certified_map::Hash;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{State, STATE};
+
+const MAGIC: &[u8; 8] = b"DIP721\0\x01";
+const HEADER_SIZE: u64 = 16;
+const PAGE_SIZE: u64 = 64 * 1024;
+// The size of the body of each HTTP response when streaming content
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+thread_local! {
+    static CONTENT_END: Cell<u64> = Cell::new(HEADER_SIZE);
+    static UPLOADS: RefCell<HashMap<u64, Upload>> = RefCell::default();
+    static NEXT_UPLOAD_ID: Cell<u64> = Cell::new(0);
+}
+
+// Content that was uploaded and verified.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Content {
+    offset: u64,
+    pub size: u64,
+    pub sha256: Hash,
+}
+
+impl Content {
+    // Reads `len` bytes of the content, starting at `start`.
+    pub fn read(&self, start: u64, len: u64) -> Vec<u8> {
+        let len = len.min(self.size.saturating_sub(start));
+        if len == 0 {
+            return Vec::new();
+        }
+        let mut buf = vec![0; len as usize];
+        stable::stable64_read(self.offset + start, &mut buf);
+        buf
+    }
+}
+
+struct Upload {
+    offset: u64,
+    size: u64,
+    written: u64,
+    sha256: Hash,
+    hasher: Sha256,
+}
+
+#[derive(CandidType, Deserialize)]
+enum UploadError {
+    Unauthorized,
+    NoSuchUpload,
+    InvalidHash,
+    SizeExceeded,
+    Incomplete,
+    HashMismatch,
+    OutOfMemory,
+}
+
+type UploadResult<T> = Result<T, UploadError>;
+
+// Grows stable memory so that it holds at least `end` bytes.
+fn ensure_capacity(end: u64) -> Result<(), stable::StableMemoryError> {
+    let pages = end
+        .checked_add(PAGE_SIZE - 1)
+        .ok_or(stable::StableMemoryError())?
+        / PAGE_SIZE;
+    let size = stable::stable64_size();
+    if pages > size {
+        stable::stable64_grow(pages - size)?;
+    }
+    Ok(())
+}
+
+// Reserves `size` bytes of stable memory after the content stored so far. Fails if the content and the
+// length prefix of the upgrade state would not fit in stable memory.
+fn reserve(size: u64) -> UploadResult<u64> {
+    CONTENT_END.with(|end| {
+        let offset = end.get();
+        let new_end = offset.checked_add(size).ok_or(UploadError::OutOfMemory)?;
+        let state_end = new_end.checked_add(8).ok_or(UploadError::OutOfMemory)?;
+        ensure_capacity(state_end).map_err(|_| UploadError::OutOfMemory)?;
+        end.set(new_end);
+        Ok(offset)
+    })
+}
+
+fn authorize() -> UploadResult<()> {
+    let caller = api::caller();
+    if STATE.with(|state| state.borrow().custodians.contains(&caller)) {
+        Ok(())
+    } else {
+        Err(UploadError::Unauthorized)
+    }
+}
+
+// Starts an upload of `size` bytes with the given SHA-256 hash, and returns its ID.
+#[update]
+fn begin_upload(size: u64, sha256: Vec<u8>) -> UploadResult<u64> {
+    authorize()?;
+    let sha256 = Hash::try_from(sha256.as_slice()).map_err(|_| UploadError::InvalidHash)?;
+    let offset = reserve(size)?;
+    let upload_id = NEXT_UPLOAD_ID.with(|id| id.replace(id.get() + 1));
+    let upload = Upload {
+        offset,
+        size,
+        written: 0,
+        sha256,
+        hasher: Sha256::new(),
+    };
+    UPLOADS.with(|uploads| uploads.borrow_mut().insert(upload_id, upload));
+    Ok(upload_id)
+}
+
+// Appends the next chunk to an upload.
+#[update]
+fn append_upload(upload_id: u64, chunk: Vec<u8>) -> UploadResult<()> {
+    authorize()?;
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        let upload = uploads
+            .get_mut(&upload_id)
+            .ok_or(UploadError::NoSuchUpload)?;
+        let len = chunk.len() as u64;
+        if upload.written + len > upload.size {
+            return Err(UploadError::SizeExceeded);
+        }
+        stable::stable64_write(upload.offset + upload.written, &chunk);
+        upload.hasher.update(&chunk);
+        upload.written += len;
+        Ok(())
+    })
+}
+
+// Verifies an upload and stores it as content, whose ID can be used in the metadata of new NFTs.
+// A failed verification ends the upload.
+#[update]
+fn commit_upload(upload_id: u64) -> UploadResult<u64> {
+    authorize()?;
+    let upload = UPLOADS
+        .with(|uploads| uploads.borrow_mut().remove(&upload_id))
+        .ok_or(UploadError::NoSuchUpload)?;
+    if upload.written != upload.size {
+        UPLOADS.with(|uploads| uploads.borrow_mut().insert(upload_id, upload));
+        return Err(UploadError::Incomplete);
+    }
+    if upload.hasher.finalize()[..] != upload.sha256 {
+        return Err(UploadError::HashMismatch);
+    }
+    let content = Content {
+        offset: upload.offset,
+        size: upload.size,
+        sha256: upload.sha256,
+    };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let contents = state.contents.get_or_insert_with(Vec::new);
+        contents.push(content);
+        Ok(contents.len() as u64 - 1)
+    })
+}
+
+// Writes the upgrade state after the content and points the header to it.
+pub fn save_state(bytes: &[u8]) {
+    let offset = CONTENT_END.with(Cell::get);
+    let len = bytes.len() as u64;
+    let end = offset
+        .checked_add(8)
+        .and_then(|start| start.checked_add(len));
+    if end.map_or(true, |end| ensure_capacity(end).is_err()) {
+        api::trap("Out of stable memory");
+    }
+    stable::stable64_write(offset, &len.to_le_bytes());
+    stable::stable64_write(offset + 8, bytes);
+    stable::stable64_write(0, MAGIC);
+    stable::stable64_write(MAGIC.len() as u64, &offset.to_le_bytes());
+}
+
+// Reads the upgrade state written by `save_state`, or returns None if stable memory holds the state of a
+// version that stored all content on the heap.
+pub fn restore_state() -> Option<Vec<u8>> {
+    if stable::stable64_size() == 0 {
+        return None;
+    }
+    let mut header = [0; HEADER_SIZE as usize];
+    stable::stable64_read(0, &mut header);
+    if header[..MAGIC.len()] != MAGIC[..] {
+        return None;
+    }
+    let read_u64 = |offset| {
+        let mut buf = [0; 8];
+        stable::stable64_read(offset, &mut buf);
+        u64::from_le_bytes(buf)
+    };
+    let offset = read_u64(MAGIC.len() as u64);
+    let mut bytes = vec![0; read_u64(offset) as usize];
+    stable::stable64_read(offset + 8, &mut bytes);
+    CONTENT_END.with(|end| end.set(offset));
+    Some(bytes)
+}
+
+impl State {
+    pub fn content(&self, content_id: u64) -> Option<&Content> {
+        self.contents
+            .as_ref()?
+            .get(usize::try_from(content_id).ok()?)
+    }
+}
@@ -0,0 +1,170 @@
+// Transaction history (DIP721 TransactionHistory interface).
+// Every state change that returns a transaction ID is appended to the log, which is never modified afterwards.
+// Transactions from before the log existed are not in it.
+
+use candid::{CandidType, Principal};
+use ic_cdk::{api, export::candid};
+use serde::Deserialize;
+
+use crate::{Error, Result, State, STATE};
+
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum TransactionType {
+    Transfer {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    TransferFrom {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    Approve {
+        token_id: u64,
+        from: Principal,
+        to: Principal,
+    },
+    SetApprovalForAll {
+        from: Principal,
+        to: Principal,
+    },
+    // Revokes the approval of `to`, or all approvals if `to` is None, for one NFT or for all NFTs of `from`
+    Revoke {
+        token_id: Option<u64>,
+        from: Principal,
+        to: Option<Principal>,
+    },
+    Mint {
+        token_id: u64,
+        to: Principal,
+    },
+    Burn {
+        token_id: u64,
+    },
+}
+
+impl TransactionType {
+    fn token_id(&self) -> Option<u64> {
+        match *self {
+            Self::Transfer { token_id, .. }
+            | Self::TransferFrom { token_id, .. }
+            | Self::Approve { token_id, .. }
+            | Self::Mint { token_id, .. }
+            | Self::Burn { token_id } => Some(token_id),
+            Self::Revoke { token_id, .. } => token_id,
+            Self::SetApprovalForAll { .. } => None,
+        }
+    }
+
+    fn involves(&self, user: &Principal) -> bool {
+        match self {
+            Self::Transfer { from, to, .. }
+            | Self::TransferFrom { from, to, .. }
+            | Self::Approve { from, to, .. }
+            | Self::SetApprovalForAll { from, to } => from == user || to == user,
+            Self::Revoke { from, to, .. } => from == user || to.as_ref() == Some(user),
+            Self::Mint { to, .. } => to == user,
+            Self::Burn { .. } => false,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Transaction {
+    txid: u128,
+    time: u64,
+    caller: Principal,
+    transaction_type: TransactionType,
+}
+
+impl State {
+    // Assigns the next transaction ID to a state change and appends it to the log.
+    pub fn record(&mut self, transaction_type: TransactionType) -> u128 {
+        let txid = self.next_txid();
+        self.transactions
+            .get_or_insert_with(Vec::new)
+            .push(Transaction {
+                txid,
+                time: api::time(),
+                caller: api::caller(),
+                transaction_type,
+            });
+        txid
+    }
+
+    fn transactions(&self) -> &[Transaction] {
+        self.transactions.as_deref().unwrap_or(&[])
+    }
+}
+
+// Returns up to `limit` of the given transactions, newest first, starting before `before`.
+fn page<'a>(
+    transactions: impl DoubleEndedIterator<Item = &'a Transaction>,
+    before: Option<u128>,
+    limit: Option<u32>,
+) -> Vec<Transaction> {
+    let limit = limit
+        .map(|limit| limit as usize)
+        .unwrap_or(DEFAULT_LIMIT)
+        .min(MAX_LIMIT);
+    transactions
+        .rev()
+        .filter(|tx| before.map_or(true, |before| tx.txid < before))
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+#[query(name = "getTransactionDip721")]
+fn get_transaction(txid: u128) -> Result<Transaction> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let transactions = state.transactions();
+        let index = transactions
+            .binary_search_by_key(&txid, |tx| tx.txid)
+            .map_err(|_| Error::Other)?;
+        Ok(transactions[index].clone())
+    })
+}
+
+#[query(name = "getTransactionsDip721")]
+fn get_transactions(before: Option<u128>, limit: Option<u32>) -> Vec<Transaction> {
+    STATE.with(|state| page(state.borrow().transactions().iter(), before, limit))
+}
+
+#[query(name = "getTokenTransactionsDip721")]
+fn get_token_transactions(
+    token_id: u64,
+    before: Option<u128>,
+    limit: Option<u32>,
+) -> Vec<Transaction> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let transactions = state
+            .transactions()
+            .iter()
+            .filter(|tx| tx.transaction_type.token_id() == Some(token_id));
+        page(transactions, before, limit)
+    })
+}
+
+// Returns the transactions `user` made or took part in.
+#[query(name = "getUserTransactionsDip721")]
+fn get_user_transactions(
+    user: Principal,
+    before: Option<u128>,
+    limit: Option<u32>,
+) -> Vec<Transaction> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let transactions = state
+            .transactions()
+            .iter()
+            .filter(|tx| tx.caller == user || tx.transaction_type.involves(&user));
+        page(transactions, before, limit)
+    })
+}
@@ -1,19 +1,20 @@
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::iter::FromIterator;
-use std::{cell::RefCell, collections::HashMap};
 
-use candid::CandidType;
+use candid::{CandidType, Func};
 use ic_cdk::{
     api::{self, call},
     export::candid,
 };
-use ic_certified_map::{AsHashTree, Hash, RbTree};
-use percent_encoding::percent_decode_str;
+use ic_certified_map::{fork, labeled, AsHashTree, Hash, HashTree, RbTree};
 use serde::{Deserialize, Serialize};
 use serde_cbor::Serializer;
 use sha2::{Digest, Sha256};
 
-use crate::{MetadataPurpose, MetadataVal, STATE};
+use crate::content::{Content, CHUNK_SIZE};
+use crate::{MetadataPart, MetadataPurpose, MetadataVal, Nft, State, STATE};
 
 #[derive(CandidType, Deserialize)]
 struct HttpRequest {
@@ -28,11 +29,63 @@ struct HttpResponse<'a> {
     status_code: u16,
     headers: HashMap<&'a str, Cow<'a, str>>,
     body: Cow<'a, [u8]>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StreamingCallbackToken {
+    content_id: u64,
+    index: u64,
+}
+
+#[derive(CandidType)]
+enum StreamingStrategy {
+    Callback {
+        callback: Func,
+        token: StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingCallbackToken>,
+}
+
+// A response the canister serves, and certifies ahead of time.
+struct Asset<'a> {
+    status_code: u16,
+    content_type: Option<Cow<'a, str>>,
+    body: Body<'a>,
+}
+
+enum Body<'a> {
+    Data(Cow<'a, [u8]>),
+    // uploaded content, which is streamed if it does not fit into one response
+    Content(u64, &'a Content),
+}
+
+impl Asset<'_> {
+    fn text(status_code: u16, text: impl Into<Cow<'static, str>>) -> Asset<'static> {
+        let body = match text.into() {
+            Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+            Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+        };
+        Asset {
+            status_code,
+            content_type: Some("text/plain; charset=utf-8".into()),
+            body: Body::Data(body),
+        }
+    }
+
+    fn not_found() -> Asset<'static> {
+        Asset::text(404, "Not found")
+    }
 }
 
 // This could reply with a lot of data. To return this data from the function would require it to be cloned,
 // because the thread_local! closure prevents us from returning data borrowed from inside it.
-// Luckily, it doesn't actually get returned from the exported WASM function, that's just an abstraction. 
+// Luckily, it doesn't actually get returned from the exported WASM function, that's just an abstraction.
 // What happens is it gets fed to call::reply, and we can do that explicitly to save the cost of cloning the data.
 // #[query] calls call::reply unconditionally, and calling it twice would trap, so we use #[export_name] directly.
 // This requires duplicating the rest of the abstraction #[query] provides for us, like setting up the panic handler with
@@ -45,15 +98,19 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
     let req = call::arg_data::<(HttpRequest,)>().0;
     STATE.with(|state| {
         let state = state.borrow();
-        let url = req.url.split('?').next().unwrap_or("/");
+        let path = req.url.split('?').next().unwrap_or("/");
+        // every path the canister serves is certified, every other path gets the certified fallback
+        let (asset, expr_path) = match route(&state, path) {
+            Some(asset) => (asset, expr_path(&segments(path), EXACT)),
+            None => (Asset::not_found(), fallback_expr_path()),
+        };
         let cert = format!(
-            "certificate=:{}:, tree=:{}:",
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
             base64::encode(api::data_certificate().unwrap()),
-            witness(&url)
+            witness(path, &expr_path),
+            cbor(&expr_path),
         )
         .into();
-        let mut path = url[1..].split('/')
-            .map(|segment| percent_decode_str(segment).decode_utf8().unwrap());
         let mut headers = HashMap::from_iter([
             (
                 "Content-Security-Policy",
@@ -61,6 +118,7 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
                     .into(),
             ),
             ("IC-Certificate", cert),
+            ("IC-CertificateExpression", CERTIFICATE_EXPRESSION.into()),
         ]);
         if cfg!(mainnet) {
             headers.insert(
@@ -68,116 +126,491 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
                 "max-age=31536000; includeSubDomains".into(),
             );
         }
-        let root = path.next().unwrap_or_else(|| "".into());
-        let body;
-        let mut code = 200;
-        if root == "" {
-            body = format!("Total NFTs: {}", state.nfts.len())
-                .into_bytes()
-                .into();
-        } else {
-            if let Ok(num) = root.parse::<usize>() {
-                // /:something
-                if let Some(nft) = state.nfts.get(num) {
-                    // /:nft
-                    let img = path.next().unwrap_or_else(|| "".into());
-                    if img == "" {
-                        // /:nft/
-                        let part = nft
-                            .metadata
-                            .iter()
-                            .find(|x| x.purpose == MetadataPurpose::Rendered)
-                            .or_else(|| nft.metadata.get(0));
-                        if let Some(part) = part {
-                            // default metadata: first non-preview metadata, or if there is none, first metadata
-                            body = part.data.as_slice().into();
-                            if let Some(MetadataVal::TextContent(mime)) =
-                                part.key_val_data.get("contentType")
-                            {
-                                headers.insert("Content-Type", mime.as_str().into());
-                            }
-                        } else {
-                            // no metadata to be found
-                            body = b"No metadata for this NFT"[..].into();
-                        }
-                    } else {
-                        // /:nft/:something
-                        if let Ok(num) = img.parse::<usize>() {
-                            // /:nft/:number
-                            if let Some(part) = nft.metadata.get(num) {
-                                // /:nft/:id
-                                body = part.data.as_slice().into();
-                                if let Some(MetadataVal::TextContent(mime)) =
-                                    part.key_val_data.get("contentType")
-                                {
-                                    headers.insert("Content-Type", mime.as_str().into());
-                                }
-                            } else {
-                                code = 404;
-                                body = b"No such metadata part"[..].into();
-                            }
-                        } else {
-                            code = 400;
-                            body = format!("Invalid metadata ID {}", img).into_bytes().into();
-                        }
-                    }
+        if let Some(content_type) = asset.content_type {
+            headers.insert("Content-Type", content_type);
+        }
+        let (body, streaming_strategy) = match asset.body {
+            Body::Data(data) => (data, None),
+            Body::Content(content_id, content) => {
+                let strategy = if content.size > CHUNK_SIZE {
+                    Some(StreamingStrategy::Callback {
+                        callback: Func {
+                            principal: api::id(),
+                            method: "http_request_streaming_callback".to_string(),
+                        },
+                        token: StreamingCallbackToken {
+                            content_id,
+                            index: 1,
+                        },
+                    })
                 } else {
-                    code = 404;
-                    body = b"No such NFT"[..].into();
-                }
-            } else {
-                code = 400;
-                body = format!("Invalid NFT ID {}", root).into_bytes().into();
+                    None
+                };
+                (content.read(0, CHUNK_SIZE).into(), strategy)
             }
-        }
+        };
         call::reply((HttpResponse {
-            status_code: code,
+            status_code: asset.status_code,
             headers,
             body,
+            streaming_strategy,
         },));
     });
 }
 
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let content = state
+            .content(token.content_id)
+            .unwrap_or_else(|| api::trap("No such content"));
+        let start = token.index * CHUNK_SIZE;
+        let next = if start + CHUNK_SIZE < content.size {
+            Some(StreamingCallbackToken {
+                content_id: token.content_id,
+                index: token.index + 1,
+            })
+        } else {
+            None
+        };
+        StreamingCallbackHttpResponse {
+            body: content.read(start, CHUNK_SIZE),
+            token: next,
+        }
+    })
+}
+
+// Parses an ID, rejecting forms like `01` so that every response is served at exactly one path.
+fn parse_id(segment: &str) -> Option<usize> {
+    segment
+        .parse()
+        .ok()
+        .filter(|id: &usize| id.to_string() == segment)
+}
+
+// Returns the response for a path, or None if nothing is served there.
+fn route<'a>(state: &'a State, path: &str) -> Option<Asset<'a>> {
+    let mut segments = path.strip_prefix('/')?.split('/');
+    let root = segments.next()?;
+    if root.is_empty() {
+        // /
+        return match segments.next() {
+            None => Some(Asset::text(
+                200,
+                format!("Total NFTs: {}", state.nfts.len()),
+            )),
+            Some(_) => None,
+        };
+    }
+    // /:nft
+    let nft = state.nfts.get(parse_id(root)?)?;
+    let asset = match segments.next() {
+        // default metadata: first non-preview metadata, or if there is none, first metadata
+        None => match default_part(nft) {
+            Some(part) => part_asset(state, part),
+            None => Asset::text(200, "No metadata for this NFT"),
+        },
+        // /:nft/metadata.json
+        Some("metadata.json") => Asset {
+            status_code: 200,
+            content_type: Some("application/json".into()),
+            body: Body::Data(metadata_json(state, nft).into_bytes().into()),
+        },
+        // /:nft/:id
+        Some(id) => part_asset(state, nft.metadata.get(parse_id(id)?)?),
+    };
+    match segments.next() {
+        None => Some(asset),
+        Some(_) => None,
+    }
+}
+
+fn default_part(nft: &Nft) -> Option<&MetadataPart> {
+    nft.metadata
+        .iter()
+        .find(|x| x.purpose == MetadataPurpose::Rendered)
+        .or_else(|| nft.metadata.get(0))
+}
+
+fn part_asset<'a>(state: &'a State, part: &'a MetadataPart) -> Asset<'a> {
+    let content_type = match part.key_val_data.get("contentType") {
+        Some(MetadataVal::TextContent(mime)) => Some(mime.as_str().into()),
+        _ => None,
+    };
+    let body = match part
+        .content_id
+        .and_then(|id| Some((id, state.content(id)?)))
+    {
+        Some((content_id, content)) => Body::Content(content_id, content),
+        None => Body::Data(part.data.as_slice().into()),
+    };
+    Asset {
+        status_code: 200,
+        content_type,
+        body,
+    }
+}
+
+// Renders the owner, royalty and metadata of an NFT as JSON. The data of the metadata parts is not included, it is served at
+// their `location`.
+fn metadata_json(state: &State, nft: &Nft) -> String {
+    let parts: Vec<_> = nft
+        .metadata
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            let purpose = match part.purpose {
+                MetadataPurpose::Preview => "Preview",
+                MetadataPurpose::Rendered => "Rendered",
+            };
+            let mut key_val_data: Vec<_> = part.key_val_data.iter().collect();
+            key_val_data.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let key_val_data: Vec<_> = key_val_data
+                .into_iter()
+                .map(|(key, val)| {
+                    let val = match val {
+                        MetadataVal::TextContent(text) => json_string(text),
+                        MetadataVal::BlobContent(blob) => json_string(&base64::encode(blob)),
+                        MetadataVal::NatContent(n) => n.to_string(),
+                        MetadataVal::Nat8Content(n) => n.to_string(),
+                        MetadataVal::Nat16Content(n) => n.to_string(),
+                        MetadataVal::Nat32Content(n) => n.to_string(),
+                        MetadataVal::Nat64Content(n) => n.to_string(),
+                    };
+                    format!("{}:{}", json_string(key), val)
+                })
+                .collect();
+            format!(
+                r#"{{"purpose":"{}","location":"/{}/{}","key_val_data":{{{}}}}}"#,
+                purpose,
+                nft.id,
+                i,
+                key_val_data.join(",")
+            )
+        })
+        .collect();
+    let royalty = match state.royalty(nft.id) {
+        Some(royalty) => format!(
+            r#"{{"recipient":"{}","bps":{}}}"#,
+            royalty.recipient, royalty.bps
+        ),
+        None => "null".to_string(),
+    };
+    format!(
+        r#"{{"id":{},"owner":"{}","royalty":{},"metadata":[{}]}}"#,
+        nft.id,
+        nft.owner,
+        royalty,
+        parts.join(",")
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// ---------------------------------
+// certification (response verification v2)
+// ---------------------------------
+
+const EXACT: &str = "<$>";
+const WILDCARD: &str = "<*>";
+// Every response certifies its status, its Content-Type and its body.
+const CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"content-type\"]}}}})";
+
+// A node of the `http_expr` tree, which maps paths to the hashes of the responses served at them.
+enum Node {
+    Branch(RbTree<Vec<u8>, Node>),
+    Leaf,
+}
+
+impl Node {
+    fn branch(label: &[u8], child: Node) -> Self {
+        Self::Branch(RbTree::from_iter([(label.to_vec(), child)]))
+    }
+}
+
+impl AsHashTree for Node {
+    fn root_hash(&self) -> Hash {
+        match self {
+            Self::Branch(tree) => tree.root_hash(),
+            Self::Leaf => ic_certified_map::leaf_hash(&[]),
+        }
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            Self::Branch(tree) => tree.as_hash_tree(),
+            Self::Leaf => HashTree::Leaf(Cow::Borrowed(&[])),
+        }
+    }
+}
+
 thread_local! {
-    // sha256("Total NFTs: 0") = 83d0f670865c367ce95f595959abec46ed7b64033ecee9ed772e78793f3bc10f
-    pub static HASHES: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::from_iter([("/".to_string(), *b"\x83\xd0\xf6\x70\x86\x5c\x36\x7c\xe9\x5f\x59\x59\x59\xab\xec\x46\xed\x7b\x64\x03\x3e\xce\xe9\xed\x77\x2e\x78\x79\x3f\x3b\xc1\x0f")]));
-}
-
-pub fn add_hash(tkid: u64) {
-    crate::STATE.with(|state| {
-        HASHES.with(|hashes| {
-            let state = state.borrow();
-            let mut hashes = hashes.borrow_mut();
-            let nft = state.nfts.get(tkid as usize)?;
-            let mut default = false;
-            for (i, metadata) in nft.metadata.iter().enumerate() {
-                let hash = Sha256::digest(&metadata.data);
-                hashes.insert(format!("/{}/{}", tkid, i), hash.into());
-                if !default && matches!(metadata.purpose, MetadataPurpose::Rendered) {
-                    default = true;
-                    hashes.insert(format!("/{}", tkid), hash.into());
-                }
-            }
-            hashes.insert(
-                "/".to_string(),
-                Sha256::digest(format!("Total NFTs: {}", state.nfts.len())).into(),
-            );
-            let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
-            api::set_certified_data(&cert);
-            Some(())
+    static TREE: RefCell<RbTree<Vec<u8>, Node>> = RefCell::new(RbTree::new());
+}
+
+// The segments of a URL path without its leading `/`: `/` is `[""]`, `/1/metadata.json` is `["1", "metadata.json"]`.
+fn segments(path: &str) -> Vec<&str> {
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
+
+// The path of a response in the certification tree: `http_expr`, the segments of the URL path, then `<$>` for a
+// response at exactly this path or `<*>` for a response to every path below it that has no response of its own.
+// This is also the `expr_path` of the `IC-Certificate` header.
+fn expr_path(segments: &[&str], terminator: &str) -> Vec<String> {
+    ["http_expr"]
+        .iter()
+        .chain(segments)
+        .chain([terminator].iter())
+        .map(|segment| segment.to_string())
+        .collect()
+}
+
+fn fallback_expr_path() -> Vec<String> {
+    expr_path(&[], WILDCARD)
+}
+
+fn tree_path(expr_path: &[String]) -> Vec<&[u8]> {
+    expr_path.iter().map(|segment| segment.as_bytes()).collect()
+}
+
+fn insert(tree: &mut RbTree<Vec<u8>, Node>, path: &[&[u8]], node: Node) {
+    let (first, rest) = path.split_first().unwrap();
+    if rest.is_empty() {
+        tree.insert(first.to_vec(), node);
+        return;
+    }
+    if tree.get(first).is_none() {
+        tree.insert(first.to_vec(), Node::Branch(RbTree::new()));
+    }
+    tree.modify(first, |child| {
+        if let Node::Branch(child) = child {
+            insert(child, rest, node);
+        }
+    });
+}
+
+// Representation-independent hash of a map of header names to values.
+fn headers_hash(headers: &[(&str, HeaderValue)]) -> Hash {
+    let mut pairs: Vec<Vec<u8>> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value_hash = match value {
+                HeaderValue::Text(text) => Sha256::digest(text.as_bytes()),
+                HeaderValue::Nat(n) => Sha256::digest(&leb128(*n)),
+            };
+            let mut pair = Sha256::digest(name.as_bytes()).to_vec();
+            pair.extend_from_slice(&value_hash);
+            pair
         })
+        .collect();
+    pairs.sort();
+    Sha256::digest(&pairs.concat()).into()
+}
+
+enum HeaderValue<'a> {
+    Text(&'a str),
+    Nat(u64),
+}
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn response_hash(asset: &Asset) -> Hash {
+    let mut headers = vec![
+        (
+            ":ic-cert-status",
+            HeaderValue::Nat(asset.status_code.into()),
+        ),
+        (
+            "ic-certificateexpression",
+            HeaderValue::Text(CERTIFICATE_EXPRESSION),
+        ),
+    ];
+    if let Some(content_type) = &asset.content_type {
+        headers.push(("content-type", HeaderValue::Text(content_type)));
+    }
+    let body_hash: Hash = match &asset.body {
+        Body::Data(data) => Sha256::digest(data).into(),
+        // uploaded content was verified against its hash when it was committed
+        Body::Content(_, content) => content.sha256,
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&headers_hash(&headers));
+    hasher.update(&body_hash);
+    hasher.finalize().into()
+}
+
+fn certify(tree: &mut RbTree<Vec<u8>, Node>, expr_path: &[String], asset: &Asset) {
+    let expr_hash = Sha256::digest(CERTIFICATE_EXPRESSION.as_bytes());
+    // no request certification, so the request hash is empty
+    let node = Node::branch(
+        &expr_hash,
+        Node::branch(b"", Node::branch(&response_hash(asset), Node::Leaf)),
+    );
+    insert(tree, &tree_path(expr_path), node);
+}
+
+fn certify_path(tree: &mut RbTree<Vec<u8>, Node>, state: &State, path: &str) {
+    if let Some(asset) = route(state, path) {
+        certify(tree, &expr_path(&segments(path), EXACT), &asset);
+    }
+}
+
+fn with_tree(f: impl FnOnce(&mut RbTree<Vec<u8>, Node>)) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        f(&mut tree);
+        api::set_certified_data(&tree.root_hash());
     });
 }
 
-fn witness(name: &str) -> String {
-    HASHES.with(|hashes| {
-        let hashes = hashes.borrow();
-        let witness = hashes.witness(name.as_bytes());
-        let tree = ic_certified_map::labeled(b"http_assets", witness);
-        let mut data = vec![];
-        let mut serializer = Serializer::new(&mut data);
-        serializer.self_describe().unwrap();
-        tree.serialize(&mut serializer).unwrap();
-        base64::encode(data)
+// Certifies every response, on install and after upgrades.
+pub(crate) fn init_hashes(state: &State) {
+    with_tree(|tree| {
+        *tree = RbTree::new();
+        certify(tree, &fallback_expr_path(), &Asset::not_found());
+        certify_path(tree, state, "/");
+        for tkid in 0..state.nfts.len() {
+            certify_nft(tree, state, tkid);
+        }
+    });
+}
+
+fn certify_nft(tree: &mut RbTree<Vec<u8>, Node>, state: &State, tkid: usize) {
+    certify_path(tree, state, &format!("/{}", tkid));
+    certify_path(tree, state, &format!("/{}/metadata.json", tkid));
+    for i in 0..state.nfts[tkid].metadata.len() {
+        certify_path(tree, state, &format!("/{}/{}", tkid, i));
+    }
+}
+
+// Certifies the responses of a new NFT.
+pub(crate) fn add_hash(state: &State, tkid: u64) {
+    with_tree(|tree| {
+        certify_path(tree, state, "/");
+        certify_nft(tree, state, tkid as usize);
+    });
+}
+
+// Certifies the metadata JSON of an NFT again after its owner or royalty changed.
+pub(crate) fn update_metadata_hash(state: &State, tkid: u64) {
+    with_tree(|tree| certify_path(tree, state, &format!("/{}/metadata.json", tkid)));
+}
+
+fn path_witness<'a>(tree: &'a RbTree<Vec<u8>, Node>, path: &[&[u8]]) -> HashTree<'a> {
+    match path.split_first() {
+        None => tree.as_hash_tree(),
+        Some((first, rest)) => tree.nested_witness(first, |child| match child {
+            Node::Branch(child) => path_witness(child, rest),
+            Node::Leaf => child.as_hash_tree(),
+        }),
+    }
+}
+
+// Combines two witnesses of the same tree.
+fn merge<'a>(a: HashTree<'a>, b: HashTree<'a>) -> HashTree<'a> {
+    match (a, b) {
+        (HashTree::Pruned(_), b) => b,
+        (a, HashTree::Pruned(_)) => a,
+        (HashTree::Fork(a), HashTree::Fork(b)) => {
+            let (a_left, a_right) = *a;
+            let (b_left, b_right) = *b;
+            fork(merge(a_left, b_left), merge(a_right, b_right))
+        }
+        (HashTree::Labeled(label, a), HashTree::Labeled(_, b)) => labeled(label, merge(*a, *b)),
+        (a, _) => a,
+    }
+}
+
+// Creates a minimal tree that proves the response at `served` for a request to `path`. A response to a path without
+// a response of its own also has to prove that there is no more specific response for it.
+fn witness(path: &str, served: &[String]) -> String {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let mut witness = path_witness(&tree, &tree_path(served));
+        if served.last().map(String::as_str) == Some(WILDCARD) {
+            // `served` is `http_expr`, the segments it covers and `<*>`
+            let segments = segments(path);
+            let more_specific = (served.len() - 1..=segments.len())
+                .map(|len| expr_path(&segments[..len], WILDCARD))
+                .chain([expr_path(&segments, EXACT)]);
+            for specific in more_specific {
+                witness = merge(witness, path_witness(&tree, &tree_path(&specific)));
+            }
+        }
+        cbor(&witness)
     })
 }
+
+fn cbor(value: &impl Serialize) -> String {
+    let mut data = vec![];
+    let mut serializer = Serializer::new(&mut data);
+    serializer.self_describe().unwrap();
+    value.serialize(&mut serializer).unwrap();
+    base64::encode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether the labels of `path` lead to a node of the witness that is not pruned.
+    fn contains(tree: &HashTree, path: &[String]) -> bool {
+        match (tree, path.split_first()) {
+            (HashTree::Pruned(_), _) => false,
+            (_, None) => true,
+            (HashTree::Fork(fork), _) => contains(&fork.0, path) || contains(&fork.1, path),
+            (HashTree::Labeled(label, tree), Some((first, rest))) => {
+                *label == first.as_bytes() && contains(tree, rest)
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn expr_paths_start_with_http_expr() {
+        assert_eq!(expr_path(&segments("/"), EXACT), ["http_expr", "", "<$>"]);
+        assert_eq!(
+            expr_path(&segments("/1/metadata.json"), EXACT),
+            ["http_expr", "1", "metadata.json", "<$>"]
+        );
+        assert_eq!(fallback_expr_path(), ["http_expr", "<*>"]);
+    }
+
+    #[test]
+    fn witness_proves_response_at_expr_path() {
+        let mut tree = RbTree::new();
+        let served = expr_path(&segments("/1"), EXACT);
+        certify(&mut tree, &fallback_expr_path(), &Asset::not_found());
+        certify(&mut tree, &served, &Asset::text(200, "NFT"));
+
+        let witness = path_witness(&tree, &tree_path(&served));
+        assert_eq!(witness.reconstruct(), tree.root_hash());
+        assert!(contains(&witness, &served));
+        assert!(!contains(&witness, &fallback_expr_path()));
+    }
+}
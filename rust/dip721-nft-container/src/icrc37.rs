@@ -0,0 +1,620 @@
+// ICRC-37 approvals over the same NFTs as the DIP721 interface.
+// Approvals of both standards authorize transfers through both interfaces: a DIP721 approval (`approved`)
+// or operator counts as an ICRC-37 token or collection approval of the spender's default account without expiry.
+// The ICRC-37 queries only list approvals made through ICRC-37.
+
+use std::collections::HashMap;
+use std::mem;
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{api, export::candid};
+use serde::Deserialize;
+
+use crate::history::TransactionType;
+use crate::icrc7::{
+    check_created_at_time, dedup_key, is_default, memo_too_long, token_index, Account, Subaccount,
+    DEFAULT_TAKE_VALUE, MAX_QUERY_BATCH_SIZE, MAX_TAKE_VALUE, MAX_UPDATE_BATCH_SIZE,
+};
+use crate::{State, MGMT, STATE};
+
+pub const MAX_APPROVALS: usize = 100; // per token or per owner for collection approvals
+pub const MAX_REVOKE_APPROVALS: usize = MAX_UPDATE_BATCH_SIZE;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ApprovalInfo {
+    spender: Account,
+    from_subaccount: Option<Subaccount>,
+    expires_at: Option<u64>,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+}
+
+impl ApprovalInfo {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map(|t| t <= now).unwrap_or(false)
+    }
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct Approvals {
+    tokens: HashMap<u64, HashMap<Account, ApprovalInfo>>, // token to spender approvals
+    collections: HashMap<Principal, HashMap<Account, ApprovalInfo>>, // owner to spender approvals
+}
+
+impl State {
+    fn approvals_mut(&mut self) -> &mut Approvals {
+        self.approvals.get_or_insert_with(Approvals::default)
+    }
+
+    // Whether `spender` may transfer the NFT on behalf of its owner, by an approval of either standard.
+    pub fn is_approved(&self, token_id: u64, spender: &Account) -> bool {
+        let nft = match self.nfts.get(token_id as usize) {
+            Some(nft) => nft,
+            None => return false,
+        };
+        if let Some(spender) = spender.principal() {
+            if nft.approved == Some(spender)
+                || self
+                    .operators
+                    .get(&nft.owner)
+                    .map(|s| s.contains(&spender))
+                    .unwrap_or(false)
+            {
+                return true;
+            }
+        }
+        let now = api::time();
+        let approved = |approvals: Option<&HashMap<Account, ApprovalInfo>>| {
+            approvals
+                .and_then(|a| a.get(spender))
+                .map(|a| !a.is_expired(now))
+                .unwrap_or(false)
+        };
+        self.approvals.as_ref().map_or(false, |approvals| {
+            approved(approvals.tokens.get(&token_id))
+                || approved(approvals.collections.get(&nft.owner))
+        })
+    }
+
+    // Token approvals end when the NFT changes hands.
+    pub fn remove_token_approvals(&mut self, token_id: u64) {
+        if let Some(approvals) = self.approvals.as_mut() {
+            approvals.tokens.remove(&token_id);
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct ApproveTokenArg {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize)]
+enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct ApproveCollectionArg {
+    approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize)]
+enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct RevokeTokenApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Subaccount>,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct RevokeCollectionApprovalArg {
+    spender: Option<Account>,
+    from_subaccount: Option<Subaccount>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct IsApprovedArg {
+    spender: Account,
+    from_subaccount: Option<Subaccount>,
+    token_id: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct TokenApproval {
+    token_id: Nat,
+    approval_info: ApprovalInfo,
+}
+
+type CollectionApproval = ApprovalInfo;
+
+#[derive(CandidType, Deserialize)]
+struct TransferFromArg {
+    spender_subaccount: Option<Subaccount>,
+    from: Account,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+fn batch_too_large<T>(len: usize, error: impl Fn(Nat, String) -> T) -> Option<Vec<Option<T>>> {
+    if len > MAX_UPDATE_BATCH_SIZE {
+        Some(vec![Some(error(
+            0u64.into(),
+            "batch too large".to_string(),
+        ))])
+    } else {
+        None
+    }
+}
+
+fn too_many_approvals() -> (Nat, String) {
+    (1u64.into(), "too many approvals".to_string())
+}
+
+fn memo_too_long_error() -> (Nat, String) {
+    (3u64.into(), "memo too long".to_string())
+}
+
+// Expired approvals are only removed when more approvals are made, so that they don't count against the limit.
+fn remove_expired(approvals: &mut HashMap<Account, ApprovalInfo>) {
+    let now = api::time();
+    approvals.retain(|_, a| !a.is_expired(now));
+}
+
+// Returns up to `take` approvals ordered by spender, starting after `prev`.
+fn page(
+    approvals: Option<&HashMap<Account, ApprovalInfo>>,
+    prev: Option<&Account>,
+    take: Option<Nat>,
+) -> Vec<ApprovalInfo> {
+    let take = take
+        .and_then(|take| token_index(&take))
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE);
+    let now = api::time();
+    let mut approvals: Vec<_> = approvals
+        .into_iter()
+        .flat_map(|a| a.values())
+        .filter(|a| !a.is_expired(now) && prev.map(|prev| a.spender > *prev).unwrap_or(true))
+        .cloned()
+        .collect();
+    approvals.sort_by(|a, b| a.spender.cmp(&b.spender));
+    approvals.truncate(take);
+    approvals
+}
+
+// -----------------
+// ICRC-37 approvals
+// -----------------
+
+#[query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(MAX_APPROVALS.into())
+}
+
+#[query]
+fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(MAX_REVOKE_APPROVALS.into())
+}
+
+#[update]
+fn icrc37_approve_tokens(
+    args: Vec<ApproveTokenArg>,
+) -> Vec<Option<Result<Nat, ApproveTokenError>>> {
+    if let Some(err) = batch_too_large(args.len(), |error_code, message| {
+        Err(ApproveTokenError::GenericBatchError {
+            error_code,
+            message,
+        })
+    }) {
+        return err;
+    }
+    args.into_iter()
+        .map(|arg| Some(approve_token(arg)))
+        .collect()
+}
+
+fn approve_token(arg: ApproveTokenArg) -> Result<Nat, ApproveTokenError> {
+    let ApproveTokenArg {
+        token_id,
+        approval_info: mut info,
+    } = arg;
+    if memo_too_long(&info.memo) {
+        let (error_code, message) = memo_too_long_error();
+        return Err(ApproveTokenError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    match check_created_at_time(Some(info.created_at_time)) {
+        Err(None) => return Err(ApproveTokenError::TooOld),
+        Err(Some(ledger_time)) => return Err(ApproveTokenError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let caller = api::caller();
+    info.spender = info.spender.normalized();
+    if info.spender.principal() == Some(caller) {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let nft = state
+            .live_nft(&token_id)
+            .ok_or(ApproveTokenError::NonExistingTokenId)?;
+        if nft.owner != caller || !is_default(&info.from_subaccount) {
+            return Err(ApproveTokenError::Unauthorized);
+        }
+        let token_id = nft.id;
+        let approvals = state.approvals_mut().tokens.entry(token_id).or_default();
+        remove_expired(approvals);
+        if approvals.len() >= MAX_APPROVALS && !approvals.contains_key(&info.spender) {
+            let (error_code, message) = too_many_approvals();
+            return Err(ApproveTokenError::GenericError {
+                error_code,
+                message,
+            });
+        }
+        let to = info.spender.owner;
+        approvals.insert(info.spender.clone(), info);
+        Ok(state
+            .record(TransactionType::Approve {
+                token_id,
+                from: caller,
+                to,
+            })
+            .into())
+    })
+}
+
+#[update]
+fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<Result<Nat, ApproveCollectionError>>> {
+    if let Some(err) = batch_too_large(args.len(), |error_code, message| {
+        Err(ApproveCollectionError::GenericBatchError {
+            error_code,
+            message,
+        })
+    }) {
+        return err;
+    }
+    args.into_iter()
+        .map(|arg| Some(approve_collection(arg)))
+        .collect()
+}
+
+fn approve_collection(arg: ApproveCollectionArg) -> Result<Nat, ApproveCollectionError> {
+    let mut info = arg.approval_info;
+    if memo_too_long(&info.memo) {
+        let (error_code, message) = memo_too_long_error();
+        return Err(ApproveCollectionError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    match check_created_at_time(Some(info.created_at_time)) {
+        Err(None) => return Err(ApproveCollectionError::TooOld),
+        Err(Some(ledger_time)) => {
+            return Err(ApproveCollectionError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => {}
+    }
+    let caller = api::caller();
+    info.spender = info.spender.normalized();
+    if info.spender.principal() == Some(caller) || info.spender.owner == MGMT {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+    if !is_default(&info.from_subaccount) {
+        // subaccounts own no NFTs
+        return Err(ApproveCollectionError::GenericError {
+            error_code: 2u64.into(),
+            message: "subaccounts are not supported".to_string(),
+        });
+    }
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let approvals = state.approvals_mut().collections.entry(caller).or_default();
+        remove_expired(approvals);
+        if approvals.len() >= MAX_APPROVALS && !approvals.contains_key(&info.spender) {
+            let (error_code, message) = too_many_approvals();
+            return Err(ApproveCollectionError::GenericError {
+                error_code,
+                message,
+            });
+        }
+        let to = info.spender.owner;
+        approvals.insert(info.spender.clone(), info);
+        Ok(state
+            .record(TransactionType::SetApprovalForAll { from: caller, to })
+            .into())
+    })
+}
+
+#[update]
+fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<Result<Nat, RevokeTokenApprovalError>>> {
+    if let Some(err) = batch_too_large(args.len(), |error_code, message| {
+        Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code,
+            message,
+        })
+    }) {
+        return err;
+    }
+    args.into_iter()
+        .map(|arg| Some(revoke_token_approval(arg)))
+        .collect()
+}
+
+fn revoke_token_approval(arg: RevokeTokenApprovalArg) -> Result<Nat, RevokeTokenApprovalError> {
+    match check_created_at_time(arg.created_at_time) {
+        Err(None) => return Err(RevokeTokenApprovalError::TooOld),
+        Err(Some(ledger_time)) => {
+            return Err(RevokeTokenApprovalError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => {}
+    }
+    let caller = api::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let nft = state
+            .live_nft(&arg.token_id)
+            .ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+        if nft.owner != caller || !is_default(&arg.from_subaccount) {
+            return Err(RevokeTokenApprovalError::Unauthorized);
+        }
+        let token_id = nft.id;
+        let approvals = state.approvals_mut().tokens.entry(token_id).or_default();
+        let to = arg.spender.as_ref().map(|spender| spender.owner);
+        let revoked = match arg.spender {
+            Some(spender) => approvals.remove(&spender.normalized()).is_some(),
+            None => !mem::take(approvals).is_empty(),
+        };
+        if !revoked {
+            return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+        }
+        Ok(state
+            .record(TransactionType::Revoke {
+                token_id: Some(token_id),
+                from: caller,
+                to,
+            })
+            .into())
+    })
+}
+
+#[update]
+fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<Result<Nat, RevokeCollectionApprovalError>>> {
+    if let Some(err) = batch_too_large(args.len(), |error_code, message| {
+        Err(RevokeCollectionApprovalError::GenericBatchError {
+            error_code,
+            message,
+        })
+    }) {
+        return err;
+    }
+    args.into_iter()
+        .map(|arg| Some(revoke_collection_approval(arg)))
+        .collect()
+}
+
+fn revoke_collection_approval(
+    arg: RevokeCollectionApprovalArg,
+) -> Result<Nat, RevokeCollectionApprovalError> {
+    match check_created_at_time(arg.created_at_time) {
+        Err(None) => return Err(RevokeCollectionApprovalError::TooOld),
+        Err(Some(ledger_time)) => {
+            return Err(RevokeCollectionApprovalError::CreatedInFuture { ledger_time })
+        }
+        Ok(()) => {}
+    }
+    if !is_default(&arg.from_subaccount) {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    let caller = api::caller();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let approvals = state.approvals_mut().collections.entry(caller).or_default();
+        let to = arg.spender.as_ref().map(|spender| spender.owner);
+        let revoked = match arg.spender {
+            Some(spender) => approvals.remove(&spender.normalized()).is_some(),
+            None => !mem::take(approvals).is_empty(),
+        };
+        if !revoked {
+            return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+        }
+        Ok(state
+            .record(TransactionType::Revoke {
+                token_id: None,
+                from: caller,
+                to,
+            })
+            .into())
+    })
+}
+
+#[query]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    if args.len() > MAX_QUERY_BATCH_SIZE {
+        api::trap("batch too large");
+    }
+    STATE.with(|state| {
+        let state = state.borrow();
+        args.into_iter()
+            .map(|arg| {
+                is_default(&arg.from_subaccount)
+                    && state
+                        .live_nft(&arg.token_id)
+                        .map(|nft| state.is_approved(nft.id, &arg.spender.normalized()))
+                        .unwrap_or(false)
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc37_get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let id = match state.live_nft(&token_id) {
+            Some(nft) => nft.id,
+            None => return vec![],
+        };
+        let approvals = state.approvals.as_ref().and_then(|a| a.tokens.get(&id));
+        let prev = prev.map(|p| p.approval_info.spender);
+        page(approvals, prev.as_ref(), take)
+            .into_iter()
+            .map(|approval_info| TokenApproval {
+                token_id: token_id.clone(),
+                approval_info,
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc37_get_collection_approvals(
+    owner: Account,
+    prev: Option<CollectionApproval>,
+    take: Option<Nat>,
+) -> Vec<CollectionApproval> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let owner = match owner.principal() {
+            Some(owner) => owner,
+            None => return vec![],
+        };
+        let approvals = state
+            .approvals
+            .as_ref()
+            .and_then(|a| a.collections.get(&owner));
+        let prev = prev.map(|p| p.spender);
+        page(approvals, prev.as_ref(), take)
+    })
+}
+
+// -----------------------
+// ICRC-37 transfer_from
+// -----------------------
+
+#[update]
+fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<Result<Nat, TransferFromError>>> {
+    if let Some(err) = batch_too_large(args.len(), |error_code, message| {
+        Err(TransferFromError::GenericBatchError {
+            error_code,
+            message,
+        })
+    }) {
+        return err;
+    }
+    args.into_iter()
+        .map(|arg| Some(transfer_from(arg)))
+        .collect()
+}
+
+fn transfer_from(arg: TransferFromArg) -> Result<Nat, TransferFromError> {
+    if memo_too_long(&arg.memo) {
+        let (error_code, message) = memo_too_long_error();
+        return Err(TransferFromError::GenericError {
+            error_code,
+            message,
+        });
+    }
+    match check_created_at_time(arg.created_at_time) {
+        Err(None) => return Err(TransferFromError::TooOld),
+        Err(Some(ledger_time)) => return Err(TransferFromError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let spender = Account {
+        owner: api::caller(),
+        subaccount: arg.spender_subaccount.clone(),
+    }
+    .normalized();
+    let from = arg
+        .from
+        .principal()
+        .ok_or(TransferFromError::Unauthorized)?;
+    let to = match arg.to.principal() {
+        Some(to) if to != MGMT && to != from => to,
+        _ => return Err(TransferFromError::InvalidRecipient),
+    };
+    let dedup_key = dedup_key(&arg, arg.created_at_time);
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(duplicate_of) = state.duplicate_of(&dedup_key) {
+            return Err(TransferFromError::Duplicate {
+                duplicate_of: duplicate_of.into(),
+            });
+        }
+        let nft = state
+            .live_nft(&arg.token_id)
+            .ok_or(TransferFromError::NonExistingTokenId)?;
+        let token_id = nft.id;
+        if nft.owner != from || !state.is_approved(token_id, &spender) {
+            return Err(TransferFromError::Unauthorized);
+        }
+        let txid = state.transfer(token_id, to);
+        state.remember_transfer(dedup_key, txid);
+        Ok(txid.into())
+    })
+}
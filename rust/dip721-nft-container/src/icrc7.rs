@@ -0,0 +1,507 @@
+// ICRC-7 interface over the same NFTs as the DIP721 interface.
+// Token IDs are the DIP721 token IDs, and accounts with the default subaccount are the DIP721 owners.
+// NFTs can't be owned by other subaccounts, so such accounts own nothing and can't receive NFTs.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use candid::{CandidType, Encode, Nat, Principal};
+use ic_cdk::{api, export::candid};
+use serde::Deserialize;
+
+use crate::royalty::Royalty;
+use crate::{MetadataPart, MetadataPurpose, MetadataVal, Nft, State, DEFAULT_LOGO, MGMT, STATE};
+
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_TAKE_VALUE: usize = 100;
+pub const MAX_TAKE_VALUE: usize = 1000;
+pub const MAX_MEMO_SIZE: usize = 32;
+pub const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours
+pub const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000; // 2 minutes
+
+pub type Subaccount = Vec<u8>;
+
+// The caller and the encoded arguments of a transfer, which include its memo and `created_at_time`
+type DedupKey = (Principal, Vec<u8>);
+
+// Transfers with a `created_at_time` that were made within the transaction window, by dedup key.
+// The values are the `created_at_time` and the transaction ID of the transfer.
+pub type RecentTransfers = HashMap<DedupKey, (u64, u128)>;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn new(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+
+    // The all-zero subaccount is the same account as no subaccount.
+    pub fn normalized(mut self) -> Self {
+        if is_default(&self.subaccount) {
+            self.subaccount = None;
+        }
+        self
+    }
+
+    // The DIP721 owner this account stands for, if it is a default account.
+    pub fn principal(&self) -> Option<Principal> {
+        if is_default(&self.subaccount) {
+            Some(self.owner)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_default(subaccount: &Option<Subaccount>) -> bool {
+    subaccount
+        .as_ref()
+        .map(|s| s.iter().all(|b| *b == 0))
+        .unwrap_or(true)
+}
+
+// ICRC-3 value
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Value {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+type TransferResult = Result<Nat, TransferError>;
+
+#[derive(CandidType, Deserialize)]
+struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+// Checks the `created_at_time` of a transaction against the transaction window.
+// Err(None) means the transaction is too old, Err(Some(now)) that it was created in the future.
+pub fn check_created_at_time(created_at_time: Option<u64>) -> Result<(), Option<u64>> {
+    let now = api::time();
+    match created_at_time {
+        Some(t) if t.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now => Err(None),
+        Some(t) if t > now.saturating_add(PERMITTED_DRIFT) => Err(Some(now)),
+        _ => Ok(()),
+    }
+}
+
+pub fn memo_too_long(memo: &Option<Vec<u8>>) -> bool {
+    memo.as_ref()
+        .map(|m| m.len() > MAX_MEMO_SIZE)
+        .unwrap_or(false)
+}
+
+// Returns the key to deduplicate a transfer by, and its `created_at_time`.
+// Transfers without a `created_at_time` are not deduplicated.
+pub fn dedup_key(arg: &impl CandidType, created_at_time: Option<u64>) -> Option<(DedupKey, u64)> {
+    created_at_time.map(|t| {
+        let arg = Encode!(arg).expect("failed to encode transfer");
+        ((api::caller(), arg), t)
+    })
+}
+
+// Token IDs are indices into `State::nfts`.
+pub fn token_index(token_id: &Nat) -> Option<usize> {
+    u64::try_from(&token_id.0)
+        .ok()
+        .and_then(|id| usize::try_from(id).ok())
+}
+
+impl State {
+    // Returns the NFT with the given ID, unless it was burned.
+    pub fn live_nft(&self, token_id: &Nat) -> Option<&Nft> {
+        self.nfts
+            .get(token_index(token_id)?)
+            .filter(|nft| nft.owner != MGMT)
+    }
+
+    fn live_nfts(&self) -> impl Iterator<Item = &Nft> + '_ {
+        self.nfts.iter().filter(|nft| nft.owner != MGMT)
+    }
+
+    // The transaction ID of an earlier transfer with the same dedup key.
+    pub fn duplicate_of(&self, key: &Option<(DedupKey, u64)>) -> Option<u128> {
+        let (key, _) = key.as_ref()?;
+        self.recent_transfers
+            .as_ref()
+            .and_then(|transfers| transfers.get(key))
+            .map(|(_, txid)| *txid)
+    }
+
+    // Remembers a transfer for deduplication, and forgets the transfers that are now too old to be sent again.
+    pub fn remember_transfer(&mut self, key: Option<(DedupKey, u64)>, txid: u128) {
+        let (key, created_at_time) = match key {
+            Some(key) => key,
+            None => return,
+        };
+        let now = api::time();
+        let transfers = self.recent_transfers.get_or_insert_with(HashMap::new);
+        transfers.retain(|_, (t, _)| t.saturating_add(TX_WINDOW + PERMITTED_DRIFT) >= now);
+        transfers.insert(key, (created_at_time, txid));
+    }
+}
+
+// Returns up to `take` IDs of the given NFTs, starting after `prev`.
+fn page<'a>(nfts: impl Iterator<Item = &'a Nft>, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let take = take
+        .and_then(|take| token_index(&take))
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE);
+    let prev = prev.map(|prev| u64::try_from(&prev.0).unwrap_or(u64::MAX));
+    nfts.filter(|nft| prev.map_or(true, |prev| nft.id > prev))
+        .take(take)
+        .map(|nft| Nat::from(nft.id))
+        .collect()
+}
+
+fn check_query_batch<T>(args: &[T]) {
+    if args.len() > MAX_QUERY_BATCH_SIZE {
+        api::trap("batch too large");
+    }
+}
+
+fn text(key: &str, value: impl Into<String>) -> (String, Value) {
+    (key.to_string(), Value::Text(value.into()))
+}
+
+fn nat(key: &str, value: impl Into<Nat>) -> (String, Value) {
+    (key.to_string(), Value::Nat(value.into()))
+}
+
+impl From<&MetadataVal> for Value {
+    fn from(val: &MetadataVal) -> Self {
+        match val {
+            MetadataVal::TextContent(s) => Value::Text(s.clone()),
+            MetadataVal::BlobContent(b) => Value::Blob(b.clone()),
+            MetadataVal::NatContent(n) => Value::Nat((*n).into()),
+            MetadataVal::Nat8Content(n) => Value::Nat((*n).into()),
+            MetadataVal::Nat16Content(n) => Value::Nat((*n).into()),
+            MetadataVal::Nat32Content(n) => Value::Nat((*n).into()),
+            MetadataVal::Nat64Content(n) => Value::Nat((*n).into()),
+        }
+    }
+}
+
+// The content of a part is not included, since it can be large. It is served over HTTP at `location`.
+fn part_value(token_id: u64, index: usize, part: &MetadataPart) -> Value {
+    let purpose = match part.purpose {
+        MetadataPurpose::Preview => "Preview",
+        MetadataPurpose::Rendered => "Rendered",
+    };
+    let mut key_val_data: Vec<_> = part
+        .key_val_data
+        .iter()
+        .map(|(k, v)| (k.clone(), v.into()))
+        .collect();
+    key_val_data.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Value::Map(vec![
+        text("purpose", purpose),
+        text("location", format!("/{}/{}", token_id, index)),
+        ("key_val_data".to_string(), Value::Map(key_val_data)),
+    ])
+}
+
+fn token_metadata(state: &State, nft: &Nft) -> Vec<(String, Value)> {
+    let parts = nft
+        .metadata
+        .iter()
+        .enumerate()
+        .map(|(i, part)| part_value(nft.id, i, part))
+        .collect();
+    let mut metadata = vec![("dip721:metadata".to_string(), Value::Array(parts))];
+    if let Some(royalty) = state.royalty(nft.id) {
+        metadata.push(royalty_value(royalty));
+    }
+    metadata
+}
+
+fn royalty_value(royalty: &Royalty) -> (String, Value) {
+    (
+        "dip721:royalty".to_string(),
+        Value::Map(vec![
+            text("recipient", royalty.recipient.to_text()),
+            nat("bps", royalty.bps),
+        ]),
+    )
+}
+
+// ---------------
+// ICRC-7 metadata
+// ---------------
+
+#[query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let logo = state.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+        let mut metadata = vec![
+            text("icrc7:symbol", state.symbol.clone()),
+            text("icrc7:name", state.name.clone()),
+            text(
+                "icrc7:logo",
+                format!("data:{};base64,{}", logo.logo_type, logo.data),
+            ),
+            nat("icrc7:total_supply", state.live_nfts().count()),
+            nat("icrc7:max_query_batch_size", MAX_QUERY_BATCH_SIZE),
+            nat("icrc7:max_update_batch_size", MAX_UPDATE_BATCH_SIZE),
+            nat("icrc7:default_take_value", DEFAULT_TAKE_VALUE),
+            nat("icrc7:max_take_value", MAX_TAKE_VALUE),
+            nat("icrc7:max_memo_size", MAX_MEMO_SIZE),
+            nat("icrc7:tx_window", TX_WINDOW),
+            nat("icrc7:permitted_drift", PERMITTED_DRIFT),
+        ];
+        if let Some(description) = &state.description {
+            metadata.push(text("icrc7:description", description.clone()));
+        }
+        if let Some(max_supply) = state.max_supply {
+            metadata.push(nat("icrc7:supply_cap", max_supply));
+        }
+        if let Some(website) = &state.website {
+            metadata.push(text("dip721:website", website.clone()));
+        }
+        if let Some(royalty) = &state.royalty {
+            metadata.push(royalty_value(royalty));
+        }
+        metadata
+    })
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    STATE.with(|state| state.borrow().symbol.clone())
+}
+
+#[query]
+fn icrc7_name() -> String {
+    STATE.with(|state| state.borrow().name.clone())
+}
+
+#[query]
+fn icrc7_description() -> Option<String> {
+    STATE.with(|state| state.borrow().description.clone())
+}
+
+#[query]
+fn icrc7_logo() -> Option<String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let logo = state.logo.as_ref().unwrap_or(&DEFAULT_LOGO);
+        Some(format!("data:{};base64,{}", logo.logo_type, logo.data))
+    })
+}
+
+#[query]
+fn icrc7_total_supply() -> Nat {
+    STATE.with(|state| state.borrow().live_nfts().count().into())
+}
+
+#[query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    STATE.with(|state| state.borrow().max_supply.map(Nat::from))
+}
+
+#[query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(MAX_QUERY_BATCH_SIZE.into())
+}
+
+#[query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(MAX_UPDATE_BATCH_SIZE.into())
+}
+
+#[query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(DEFAULT_TAKE_VALUE.into())
+}
+
+#[query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(MAX_TAKE_VALUE.into())
+}
+
+#[query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(MAX_MEMO_SIZE.into())
+}
+
+#[query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[query]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(TX_WINDOW.into())
+}
+
+#[query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(PERMITTED_DRIFT.into())
+}
+
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    [
+        ("ICRC-7", "https://github.com/dfinity/ICRC/ICRCs/ICRC-7"),
+        ("ICRC-10", "https://github.com/dfinity/ICRC/ICRCs/ICRC-10"),
+        ("ICRC-37", "https://github.com/dfinity/ICRC/ICRCs/ICRC-37"),
+    ]
+    .iter()
+    .map(|(name, url)| SupportedStandard {
+        name: name.to_string(),
+        url: url.to_string(),
+    })
+    .collect()
+}
+
+// -------------
+// ICRC-7 tokens
+// -------------
+
+#[query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    check_query_batch(&token_ids);
+    STATE.with(|state| {
+        let state = state.borrow();
+        token_ids
+            .iter()
+            .map(|id| state.live_nft(id).map(|nft| token_metadata(&state, nft)))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    check_query_batch(&token_ids);
+    STATE.with(|state| {
+        let state = state.borrow();
+        token_ids
+            .iter()
+            .map(|id| state.live_nft(id).map(|nft| Account::new(nft.owner)))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    check_query_batch(&accounts);
+    STATE.with(|state| {
+        let state = state.borrow();
+        accounts
+            .iter()
+            .map(|account| match account.principal() {
+                Some(owner) => state
+                    .live_nfts()
+                    .filter(|n| n.owner == owner)
+                    .count()
+                    .into(),
+                None => Nat::from(0u64),
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    STATE.with(|state| page(state.borrow().live_nfts(), prev, take))
+}
+
+#[query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        match account.principal() {
+            Some(owner) => page(state.live_nfts().filter(|n| n.owner == owner), prev, take),
+            None => vec![],
+        }
+    })
+}
+
+// ---------------
+// ICRC-7 transfer
+// ---------------
+
+// Transfers are not atomic: every transfer of a batch succeeds or fails on its own.
+#[update]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: 0u64.into(),
+            message: "batch too large".to_string(),
+        }))];
+    }
+    args.into_iter().map(|arg| Some(transfer(arg))).collect()
+}
+
+fn transfer(arg: TransferArg) -> TransferResult {
+    if memo_too_long(&arg.memo) {
+        return Err(TransferError::GenericError {
+            error_code: 0u64.into(),
+            message: "memo too long".to_string(),
+        });
+    }
+    match check_created_at_time(arg.created_at_time) {
+        Err(None) => return Err(TransferError::TooOld),
+        Err(Some(ledger_time)) => return Err(TransferError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let caller = api::caller();
+    let to = match arg.to.principal() {
+        Some(to) if to != MGMT && to != caller => to,
+        _ => return Err(TransferError::InvalidRecipient),
+    };
+    let dedup_key = dedup_key(&arg, arg.created_at_time);
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(duplicate_of) = state.duplicate_of(&dedup_key) {
+            return Err(TransferError::Duplicate {
+                duplicate_of: duplicate_of.into(),
+            });
+        }
+        let nft = state
+            .live_nft(&arg.token_id)
+            .ok_or(TransferError::NonExistingTokenId)?;
+        if nft.owner != caller || !is_default(&arg.from_subaccount) {
+            return Err(TransferError::Unauthorized);
+        }
+        let token_id = nft.id;
+        let txid = state.transfer(token_id, to);
+        state.remember_transfer(dedup_key, txid);
+        Ok(txid.into())
+    })
+}
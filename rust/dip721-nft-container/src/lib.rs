@@ -14,16 +14,22 @@ use std::mem;
 use std::num::TryFromIntError;
 use std::result::Result as StdResult;
 
-use candid::{CandidType, Encode, Principal};
+use candid::{CandidType, Decode, Encode, Principal};
+use history::TransactionType;
 use ic_cdk::{
     api::{self, call},
     export::candid,
     storage,
 };
-use ic_certified_map::Hash;
+use icrc7::Account;
 use include_base64::include_base64;
 
+mod content;
+mod history;
 mod http;
+mod icrc37;
+mod icrc7;
+mod royalty;
 
 const MGMT: Principal = Principal::from_slice(&[]);
 
@@ -31,26 +37,26 @@ thread_local! {
     static STATE: RefCell<State> = RefCell::default();
 }
 
+// The certified response hashes are not saved, since they are computed from the state.
 #[derive(CandidType, Deserialize)]
 struct StableState {
     state: State,
-    hashes: Vec<(String, Hash)>,
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
     let state = STATE.with(|state| mem::take(&mut *state.borrow_mut()));
-    let hashes = http::HASHES.with(|hashes| mem::take(&mut *hashes.borrow_mut()));
-    let hashes = hashes.iter().map(|(k, v)| (k.clone(), *v)).collect();
-    let stable_state = StableState { state, hashes };
-    storage::stable_save((stable_state,)).unwrap();
+    let stable_state = StableState { state };
+    content::save_state(&Encode!(&stable_state).unwrap());
 }
 #[post_upgrade]
 fn post_upgrade() {
-    let (StableState { state, hashes },) = storage::stable_restore().unwrap();
+    let StableState { state } = match content::restore_state() {
+        Some(bytes) => Decode!(&bytes, StableState).unwrap(),
+        None => storage::stable_restore::<(StableState,)>().unwrap().0,
+    };
+    http::init_hashes(&state);
     STATE.with(|state0| *state0.borrow_mut() = state);
-    let hashes = hashes.into_iter().collect();
-    http::HASHES.with(|hashes0| *hashes0.borrow_mut() = hashes);
 }
 
 #[derive(CandidType, Deserialize)]
@@ -59,6 +65,10 @@ struct InitArgs {
     logo: Option<LogoResult>,
     name: String,
     symbol: String,
+    description: Option<String>,
+    website: Option<String>,
+    max_supply: Option<u64>,
+    royalty: Option<royalty::Royalty>,
 }
 
 #[init]
@@ -71,6 +81,11 @@ fn init(args: InitArgs) {
         state.name = args.name;
         state.symbol = args.symbol;
         state.logo = args.logo;
+        state.description = args.description;
+        state.website = args.website;
+        state.max_supply = args.max_supply;
+        state.royalty = args.royalty;
+        http::init_hashes(&state);
     });
 }
 
@@ -126,7 +141,7 @@ fn transfer_from(from: Principal, to: Principal, token_id: u64) -> Result {
         let state = &mut *state;
         let nft = state
             .nfts
-            .get_mut(usize::try_from(token_id)?)
+            .get(usize::try_from(token_id)?)
             .ok_or(Error::InvalidTokenId)?;
         let caller = api::caller();
         if nft.owner != caller
@@ -137,14 +152,13 @@ fn transfer_from(from: Principal, to: Principal, token_id: u64) -> Result {
                 .map(|s| s.contains(&caller))
                 .unwrap_or(false)
             && !state.custodians.contains(&caller)
+            && !state.is_approved(token_id, &Account::new(caller))
         {
             Err(Error::Unauthorized)
         } else if nft.owner != from {
             Err(Error::Other)
         } else {
-            nft.approved = None;
-            nft.owner = to;
-            Ok(state.next_txid())
+            Ok(state.transfer(token_id, to))
         }
     })
 }
@@ -165,6 +179,7 @@ fn supported_interfaces() -> &'static [InterfaceId] {
         // InterfaceId::Approval, // Psychedelic/DIP721#5
         InterfaceId::Burn,
         InterfaceId::Mint,
+        InterfaceId::TransactionHistory,
     ]
 }
 
@@ -307,7 +322,12 @@ fn approve(user: Principal, token_id: u64) -> Result {
             Err(Error::Unauthorized)
         } else {
             nft.approved = Some(user);
-            Ok(state.next_txid())
+            let from = nft.owner;
+            Ok(state.record(TransactionType::Approve {
+                token_id,
+                from,
+                to: user,
+            }))
         }
     })
 }
@@ -333,7 +353,19 @@ fn set_approval_for_all(operator: Principal, is_approved: bool) -> Result {
                 }
             }
         }
-        Ok(state.next_txid())
+        let transaction_type = if is_approved {
+            TransactionType::SetApprovalForAll {
+                from: caller,
+                to: operator,
+            }
+        } else {
+            TransactionType::Revoke {
+                token_id: None,
+                from: caller,
+                to: Some(operator).filter(|operator| *operator != MGMT),
+            }
+        };
+        Ok(state.record(transaction_type))
     })
 }
 
@@ -378,18 +410,36 @@ fn mint(
         if !state.custodians.contains(&api::caller()) {
             return Err(ConstrainedError::Unauthorized);
         }
+        let missing_content = metadata
+            .iter()
+            .filter_map(|part| part.content_id)
+            .any(|content_id| state.content(content_id).is_none());
+        if missing_content {
+            return Err(ConstrainedError::InvalidContentId);
+        }
         let new_id = state.nfts.len() as u64;
+        if state
+            .max_supply
+            .map_or(false, |max_supply| new_id >= max_supply)
+        {
+            return Err(ConstrainedError::MaxSupplyReached);
+        }
         let nft = Nft {
             owner: to,
             approved: None,
             id: new_id,
             metadata,
             content: blob_content,
+            royalty: None,
         };
         state.nfts.push(nft);
-        Ok((state.next_txid(), new_id))
+        let txid = state.record(TransactionType::Mint {
+            token_id: new_id,
+            to,
+        });
+        http::add_hash(&state, new_id);
+        Ok((txid, new_id))
     })?;
-    http::add_hash(tkid);
     Ok(MintResult {
         id: txid,
         token_id: tkid,
@@ -412,7 +462,9 @@ fn burn(token_id: u64) -> Result {
             Err(Error::Unauthorized)
         } else {
             nft.owner = MGMT;
-            Ok(state.next_txid())
+            state.remove_token_approvals(token_id);
+            http::update_metadata_hash(&state, token_id);
+            Ok(state.record(TransactionType::Burn { token_id }))
         }
     })
 }
@@ -426,6 +478,20 @@ struct State {
     name: String,
     symbol: String,
     txid: u128,
+    // None when upgrading from a version without the transaction log
+    transactions: Option<Vec<history::Transaction>>,
+    // ICRC-37 approvals, None when upgrading from a version without them
+    approvals: Option<icrc37::Approvals>,
+    // Content uploaded to stable memory, by content ID. None when upgrading from a version without it
+    contents: Option<Vec<content::Content>>,
+    description: Option<String>,
+    website: Option<String>,
+    // The number of NFTs that can be minted, including burned ones. None if unlimited
+    max_supply: Option<u64>,
+    // The royalty of NFTs without a royalty of their own
+    royalty: Option<royalty::Royalty>,
+    // ICRC-7 and ICRC-37 transfers within the transaction window, for deduplication
+    recent_transfers: Option<icrc7::RecentTransfers>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -435,6 +501,7 @@ struct Nft {
     id: u64,
     metadata: MetadataDesc,
     content: Vec<u8>,
+    royalty: Option<royalty::Royalty>,
 }
 
 type MetadataDesc = Vec<MetadataPart>;
@@ -445,6 +512,8 @@ struct MetadataPart {
     purpose: MetadataPurpose,
     key_val_data: HashMap<String, MetadataVal>,
     data: Vec<u8>,
+    // Content uploaded with `begin_upload`, which is served instead of `data`
+    content_id: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize, PartialEq)]
@@ -477,6 +546,21 @@ impl State {
         self.txid += 1;
         txid
     }
+
+    // Transfers an NFT, which ends all of its token approvals.
+    fn transfer(&mut self, token_id: u64, to: Principal) -> u128 {
+        let nft = &mut self.nfts[token_id as usize];
+        let from = mem::replace(&mut nft.owner, to);
+        nft.approved = None;
+        self.remove_token_approvals(token_id);
+        http::update_metadata_hash(self, token_id);
+        let transaction_type = if from == api::caller() {
+            TransactionType::Transfer { token_id, from, to }
+        } else {
+            TransactionType::TransferFrom { token_id, from, to }
+        };
+        self.record(transaction_type)
+    }
 }
 
 #[derive(CandidType, Deserialize)]
@@ -491,6 +575,8 @@ enum InterfaceId {
 #[derive(CandidType, Deserialize)]
 enum ConstrainedError {
     Unauthorized,
+    InvalidContentId,
+    MaxSupplyReached,
 }
 
 #[update]
@@ -532,6 +618,48 @@ fn set_logo(logo: Option<LogoResult>) -> Result<()> {
     })
 }
 
+#[update]
+fn set_description(description: Option<String>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.custodians.contains(&api::caller()) {
+            state.description = description;
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_website(website: Option<String>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.custodians.contains(&api::caller()) {
+            state.website = website;
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+// The max supply can't be lowered below the number of NFTs minted so far.
+#[update]
+fn set_max_supply(max_supply: Option<u64>) -> Result<()> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.custodians.contains(&api::caller()) {
+            Err(Error::Unauthorized)
+        } else if max_supply.map_or(false, |max_supply| max_supply < state.nfts.len() as u64) {
+            Err(Error::Other)
+        } else {
+            state.max_supply = max_supply;
+            Ok(())
+        }
+    })
+}
+
 #[update]
 fn set_custodian(user: Principal, custodian: bool) -> Result<()> {
     STATE.with(|state| {
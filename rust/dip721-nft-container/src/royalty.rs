@@ -0,0 +1,109 @@
+// Royalties owed to a recipient when an NFT is sold, set for the whole collection and overridden per NFT.
+// The canister does not handle sales; marketplaces query the split of a sale price and pay it out themselves.
+
+use std::convert::TryFrom;
+
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::{api, export::candid};
+use serde::Deserialize;
+
+use crate::{http, Error, Result, State, STATE};
+
+pub const BPS: u32 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Royalty {
+    pub recipient: Principal,
+    // Share of the sale price in basis points
+    pub bps: u32,
+}
+
+#[derive(CandidType)]
+struct RoyaltySplit {
+    // None if no royalty is set for the NFT
+    royalty: Option<RoyaltyPayment>,
+    owner: Principal,
+    owner_amount: Nat,
+}
+
+#[derive(CandidType)]
+struct RoyaltyPayment {
+    recipient: Principal,
+    amount: Nat,
+}
+
+impl State {
+    // The royalty of an NFT: its own, or else the one of the collection.
+    pub fn royalty(&self, token_id: u64) -> Option<&Royalty> {
+        let nft = self.nfts.get(usize::try_from(token_id).ok()?)?;
+        nft.royalty.as_ref().or_else(|| self.royalty.as_ref())
+    }
+}
+
+fn check_royalty(royalty: &Option<Royalty>) -> Result<()> {
+    match royalty {
+        Some(royalty) if royalty.bps > BPS => Err(Error::Other),
+        _ => Ok(()),
+    }
+}
+
+#[update]
+fn set_royalty(royalty: Option<Royalty>) -> Result<()> {
+    check_royalty(&royalty)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.custodians.contains(&api::caller()) {
+            state.royalty = royalty;
+            for tkid in 0..state.nfts.len() as u64 {
+                http::update_metadata_hash(&state, tkid);
+            }
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    })
+}
+
+#[update]
+fn set_token_royalty(token_id: u64, royalty: Option<Royalty>) -> Result<()> {
+    check_royalty(&royalty)?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if !state.custodians.contains(&api::caller()) {
+            return Err(Error::Unauthorized);
+        }
+        state
+            .nfts
+            .get_mut(usize::try_from(token_id)?)
+            .ok_or(Error::InvalidTokenId)?
+            .royalty = royalty;
+        http::update_metadata_hash(&state, token_id);
+        Ok(())
+    })
+}
+
+// Splits the price of a sale of an NFT between the royalty recipient and its owner. The royalty is rounded down.
+#[query]
+fn royalty_split(token_id: u64, sale_price: Nat) -> Result<RoyaltySplit> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let owner = state
+            .nfts
+            .get(usize::try_from(token_id)?)
+            .ok_or(Error::InvalidTokenId)?
+            .owner;
+        let royalty = state.royalty(token_id).map(|royalty| RoyaltyPayment {
+            recipient: royalty.recipient,
+            amount: sale_price.clone() * royalty.bps / BPS,
+        });
+        let owner_amount = match &royalty {
+            Some(royalty) => sale_price - royalty.amount.clone(),
+            None => sale_price,
+        };
+        Ok(RoyaltySplit {
+            royalty,
+            owner,
+            owner_amount,
+        })
+    })
+}